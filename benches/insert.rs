@@ -0,0 +1,111 @@
+/* Benchmark for inserting rows
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Compares loading `cdataset_raw` one row per statement, as the loaders used
+//! to, with batched inserts.  Run with `cargo bench`.
+
+use covid19db::dbschema::{cdataset_schema, execute_all, CDataSet};
+use covid19db::dbutil::{open_pool, BulkInsert};
+use covid19db::progress::Progress;
+use std::time::Instant;
+
+const ROWS: i32 = 100_000;
+
+fn row(n: i32) -> CDataSet {
+    CDataSet {
+        dataset: String::from("bench"),
+        locid: i64::from(n % 500),
+        location_lat: Some(38.0),
+        location_long: Some(-97.0),
+        date_julian: 2_458_850 + n / 500,
+        day_index_0: n / 500,
+        day_index_1: n / 500,
+        day_index_10: Some(n / 500),
+        day_index_100: None,
+        day_index_1k: None,
+        day_index_10k: None,
+        day_index_peak: None,
+        day_index_peak_confirmed: None,
+        day_index_peak_deaths: None,
+        absolute_confirmed: i64::from(n),
+        absolute_deaths: i64::from(n / 50),
+        absolute_recovered: 0,
+        absolute_infected: i64::from(n),
+        absolute_pop100k_confirmed: Some(f64::from(n) / 10.0),
+        absolute_pop100k_deaths: Some(f64::from(n) / 500.0),
+        absolute_pop100k_recovered: None,
+        absolute_pop100k_infected: Some(f64::from(n) / 10.0),
+        relative_deaths: Some(0.02),
+        relative_recovered: None,
+        relative_infected: Some(1.0),
+        delta_confirmed: 10,
+        delta_deaths: 1,
+        delta_recovered: 0,
+        delta_infected: 9,
+        delta_pct_confirmed: Some(0.01),
+        delta_pct_deaths: Some(0.01),
+        delta_pct_recovered: None,
+        delta_pct_infected: Some(0.01),
+        delta_pop100k_confirmed: Some(1.0),
+        delta_pop100k_deaths: Some(0.1),
+        delta_pop100k_recovered: None,
+        delta_pop100k_infected: Some(0.9),
+        peak_pct_confirmed: Some(0.5),
+        peak_pct_deaths: Some(0.5),
+        peak_pct_recovered: None,
+        peak_pct_infected: Some(0.5),
+        factbook_area: Some(2000.0),
+        factbook_population: Some(100_000),
+        factbook_death_rate: Some(8.5),
+        factbook_median_age: Some(38.1),
+    }
+}
+
+/// Loads `ROWS` rows with `batch_size` rows per statement, returning rows per second.
+async fn run(batch_size: usize) -> covid19db::Result<f64> {
+    let dir = tempfile::tempdir()?;
+    let pool = open_pool(&dir.path().join("bench.db"), 1).await?;
+    execute_all(
+        &mut pool.acquire().await?,
+        &cdataset_schema(),
+        &Progress::silent(),
+    )
+    .await?;
+    let start = Instant::now();
+    let mut transaction = pool.begin().await?;
+    let mut inserter =
+        BulkInsert::new(CDataSet::insert_str(), CDataSet::bind_query).batch_size(batch_size);
+    for n in 0..ROWS {
+        inserter.push(row(n), &mut transaction).await?;
+    }
+    inserter.finish(&mut transaction).await?;
+    transaction.commit().await?;
+    let elapsed = start.elapsed().as_secs_f64();
+    pool.close().await;
+    Ok(f64::from(ROWS) / elapsed)
+}
+
+#[tokio::main]
+async fn main() -> covid19db::Result<()> {
+    let before = run(1).await?;
+    let after = run(usize::MAX).await?;
+    println!("one row per statement: {:>10.0} rows/sec", before);
+    println!("batched:               {:>10.0} rows/sec", after);
+    println!("speedup:               {:>10.1}x", after / before);
+    Ok(())
+}
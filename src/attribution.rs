@@ -0,0 +1,190 @@
+/* Attribution and licenses
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Whom to credit for the data, and on what terms it may be used.
+
+The built-in credits are kept in `src/attribution.toml`, which also
+documents its syntax.  Each build writes those of the sources it loads, as
+given by [`Source::attribution`](crate::loader::Source::attribution), to the
+`covid19db_attribution` table, which [`Credits::read`] returns. */
+
+use serde::Deserialize;
+use sqlx::pool::PoolConnection;
+use sqlx::prelude::*;
+use sqlx::SqliteConnection;
+use std::fmt::Write;
+
+use crate::{Error, Result};
+
+const BUILTIN: &str = include_str!("attribution.toml");
+
+/// The credit due for the data of one source to one of its providers.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Credit {
+    /// The source whose data this covers.
+    pub source: String,
+    /// A short name for who is credited, the same for every source using their data.
+    pub provider: String,
+    /// Who is credited, in full.
+    pub name: String,
+    /// The tables and views holding their data.
+    pub tables: Vec<String>,
+    /// The credit the terms of use ask for.
+    pub attribution: String,
+    /// The name of the license, such as "CC BY 4.0".
+    pub license: Option<String>,
+    /// Where the terms of the license are.
+    pub license_url: Option<String>,
+    /// Where the data comes from.
+    pub url: Option<String>,
+    /// The publication to cite.
+    pub citation: Option<String>,
+    /// The publication to cite, as a BibTeX entry.
+    pub bibtex: Option<String>,
+}
+
+/** A list of credits, as read from an attribution file or a database.
+
+```
+use covid19db::attribution::Credits;
+
+let credits = Credits::builtin().covering(&["owid", "cdataset"]);
+let providers: Vec<&str> = credits.credits.iter().map(|x| x.provider.as_str()).collect();
+assert_eq!(providers, vec!["jhu", "owid", "covid19-datasets", "nytimes", "ecdc"]);
+assert!(credits.text().contains("License: CC BY 4.0"));
+assert!(credits.bibtex().contains("@article{owidcoronavirus,"));
+assert!(Credits::builtin().covering(&["covid19db_meta"]).credits.is_empty());
+```
+*/
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Credits {
+    #[serde(default, rename = "credit")]
+    pub credits: Vec<Credit>,
+}
+
+impl Credits {
+    /// The credits for the built-in sources.
+    pub fn builtin() -> Self {
+        Credits::from_toml(BUILTIN).expect("built-in attribution is invalid")
+    }
+
+    /// Parses an attribution file.
+    pub fn from_toml(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|e| Error::Config(format!("Invalid attribution: {}", e)))
+    }
+
+    /// The credits for the named source.
+    pub fn for_source(&self, source: &str) -> Vec<Credit> {
+        self.credits
+            .iter()
+            .filter(|x| x.source == source)
+            .cloned()
+            .collect()
+    }
+
+    /** The credits due for the data in any of `tables`, or in any table if
+    `tables` is empty, with those for the same provider combined. */
+    pub fn covering(&self, tables: &[&str]) -> Credits {
+        let mut covering: Vec<Credit> = Vec::new();
+        for credit in &self.credits {
+            if !tables.is_empty() && !credit.tables.iter().any(|x| tables.contains(&x.as_str())) {
+                continue;
+            }
+            match covering.iter_mut().find(|x| x.provider == credit.provider) {
+                Some(existing) => {
+                    for table in &credit.tables {
+                        if !existing.tables.contains(table) {
+                            existing.tables.push(table.clone());
+                        }
+                    }
+                }
+                None => covering.push(credit.clone()),
+            }
+        }
+        Credits { credits: covering }
+    }
+
+    /// The credits as plain text, a paragraph for each.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for credit in &self.credits {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            writeln!(text, "{}", credit.name).unwrap();
+            writeln!(text, "  Tables: {}", credit.tables.join(", ")).unwrap();
+            writeln!(text, "  Attribution: {}", credit.attribution).unwrap();
+            match (&credit.license, &credit.license_url) {
+                (Some(license), Some(url)) => {
+                    writeln!(text, "  License: {} <{}>", license, url).unwrap()
+                }
+                (Some(license), None) => writeln!(text, "  License: {}", license).unwrap(),
+                (None, Some(url)) => writeln!(text, "  Terms: <{}>", url).unwrap(),
+                (None, None) => (),
+            }
+            if let Some(url) = &credit.url {
+                writeln!(text, "  Source: <{}>", url).unwrap();
+            }
+            if let Some(citation) = &credit.citation {
+                writeln!(text, "  Cite: {}", citation).unwrap();
+            }
+        }
+        text
+    }
+
+    /// The BibTeX entries of the credits that have one.
+    pub fn bibtex(&self) -> String {
+        let entries: Vec<&str> = self
+            .credits
+            .iter()
+            .filter_map(|x| x.bibtex.as_deref())
+            .map(str::trim)
+            .collect();
+        let mut bibtex = entries.join("\n\n");
+        if !bibtex.is_empty() {
+            bibtex.push('\n');
+        }
+        bibtex
+    }
+
+    /// Reads the `covid19db_attribution` table of a database built by this crate.
+    pub async fn read(conn: &mut PoolConnection<SqliteConnection>) -> Result<Self> {
+        let mut credits = Vec::new();
+        let mut cursor =
+            sqlx::query("SELECT * FROM covid19db_attribution ORDER BY source, provider")
+                .fetch(conn);
+        while let Some(row) = cursor.next().await? {
+            let tables: String = row.try_get("tables")?;
+            credits.push(Credit {
+                source: row.try_get("source")?,
+                provider: row.try_get("provider")?,
+                name: row.try_get("name")?,
+                tables: tables.split(',').map(String::from).collect(),
+                attribution: row.try_get("attribution")?,
+                license: row.try_get("license")?,
+                license_url: row.try_get("license_url")?,
+                url: row.try_get("url")?,
+                citation: row.try_get("citation")?,
+                bibtex: row.try_get("bibtex")?,
+            });
+        }
+        Ok(Credits { credits })
+    }
+}
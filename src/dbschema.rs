@@ -16,6 +16,8 @@ Copyright (c) 2020 John Goerzen
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use crate::progress::{Event, Progress};
+use crate::Result;
 use sqlx::prelude::*;
 
 mod cdataset;
@@ -28,33 +30,147 @@ pub use crate::dbschema::{cdataset::*, covidtracking::*, owid::*, rtlive::*};
 /** Initialize a database.  This will drop all indices and tables related to
 this project, then re-create them, thus emptying them and readying them to
 receive data. */
-pub async fn initdb<E: Executor>(db: &mut E) -> () {
-    let statements = vec![
-        "drop index if exists cdataset_raw_uniq_idx",
-        "drop table if exists cdataset_raw",
-        "drop table if exists cdataset_loc",
-        "drop index if exists harveycodata_raw_uniq_idx",
-        "drop view if exists harveycodata",
-        "drop table if exists harveycodata_raw",
-        "drop table if exists nytcounties_raw",
-        "drop index if exists nytcounties_raw_uniq_idx",
-        "drop view if exists nytcounties",
-        "drop view if exists cdataset",
-        "drop index if exists loc_lookup_fips",
-        "drop table if exists loc_lookup",
+pub async fn initdb<E: Executor>(db: &mut E) -> Result<()> {
+    let mut queries = meta_schema();
+    queries.extend(loc_lookup_schema());
+    queries.extend(nytcounties_schema());
+    queries.extend(harveycodata_schema());
+    queries.extend(covidtracking_schema());
+    queries.extend(owid_schema());
+    queries.extend(rtlive_schema());
+    queries.extend(cdataset_schema());
+    execute_all(db, &queries, &Progress::default()).await
+}
+
+/// Executes each of the given statements in turn, reporting each to `progress`.
+pub async fn execute_all<E: Executor>(
+    db: &mut E,
+    queries: &[String],
+    progress: &Progress,
+) -> Result<()> {
+    for query in queries {
+        progress.event(Event::Statement { sql: query.clone() });
+        db.execute(query.as_str()).await?;
+    }
+    Ok(())
+}
+
+/// Statements to drop and re-create the `covid19db_meta`, `covid19db_sources`,
+/// `load_rejects`, `source_coverage` and `covid19db_attribution` tables.
+pub fn meta_schema() -> Vec<String> {
+    vec![
         "drop table if exists covid19db_meta",
-        "drop view if exists rtlive",
-        "drop index if exists rtlive_raw_uniq_idx",
-        "drop table if exists rtlive_raw",
-        "drop index if exists covidtracking_uniq_idx",
-        "drop view if exists covidtracking",
-        "drop view if exists covidtracking_us",
-        "drop table if exists covidtracking_raw",
-        "drop view if exists owid",
-        "drop index if exists owid_raw_uniq_idx",
-        "drop table if exists owid_raw",
         "create table covid19db_meta (field text not null, value text not null)",
         "insert into covid19db_meta values ('schemaver', '2')",
+        "drop table if exists covid19db_sources",
+        // Where each source file came from and what was loaded from it.  The
+        // HTTP validators are also used by incremental builds.
+        "create table covid19db_sources (
+         source text not null,
+         filename text not null,
+         url text not null,
+         revision text,
+         fetched text not null,
+         sha256 text not null,
+         bytes integer not null,
+         etag text,
+         last_modified text,
+         parsed integer,
+         inserted integer,
+         skipped integer,
+         rejected integer,
+         replacements integer,
+         min_date_julian integer,
+         max_date_julian integer)",
+        "drop table if exists load_rejects",
+        // Records that could not be parsed, and were left out.
+        "create table load_rejects (
+         source text not null,
+         filename text not null,
+         line integer not null,
+         record text not null,
+         error text not null)",
+        "drop table if exists source_coverage",
+        // The days each source has data for, as a whole (where location is
+        // NULL) and for each location, and how many days old its latest data
+        // was when its files were last fetched or found unchanged.
+        "create table source_coverage (
+         source text not null,
+         location text,
+         rows integer not null,
+         first_date text not null,
+         last_date text not null,
+         first_date_julian integer not null,
+         last_date_julian integer not null,
+         days_stale integer not null)",
+        "drop table if exists covid19db_attribution",
+        // Whom to credit for each source's data, and its license; `tables`
+        // lists the tables and views holding it, separated by commas.
+        "create table covid19db_attribution (
+         source text not null,
+         provider text not null,
+         name text not null,
+         tables text not null,
+         attribution text not null,
+         license text,
+         license_url text,
+         url text,
+         citation text,
+         bibtex text,
+         primary key (source, provider))",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/** Statements to create the `load_checkpoints` table, which records the
+sources a build has finished, if it doesn't exist, and to empty it.  A build
+resumed with `--resume` skips the sources listed there. */
+pub fn checkpoint_schema() -> Vec<String> {
+    vec![
+        "create table if not exists load_checkpoints (
+         source text primary key,
+         completed text not null)",
+        "delete from load_checkpoints",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/** Statements run once every source is loaded.  Sources are loaded in the
+order their files arrive, so the rows they add to the tables they share are
+put back in a fixed order; together with `VACUUM`, this makes builds from the
+same files byte-identical. */
+pub fn finish_schema() -> Vec<String> {
+    let mut statements = Vec::new();
+    for (table, order) in &[
+        ("covid19db_meta", "field, value"),
+        ("covid19db_sources", "source, filename"),
+        ("load_rejects", "source, filename, line"),
+        ("source_coverage", "source, location"),
+        ("covid19db_attribution", "source, provider"),
+        ("load_checkpoints", "source"),
+    ] {
+        statements.extend(vec![
+            format!(
+                "create temp table sorted as select * from {} order by {}",
+                table, order
+            ),
+            format!("delete from {}", table),
+            format!("insert into {} select * from sorted order by rowid", table),
+            String::from("drop table sorted"),
+        ]);
+    }
+    statements
+}
+
+/// Statements to drop and re-create the `loc_lookup` table.
+pub fn loc_lookup_schema() -> Vec<String> {
+    vec![
+        "drop index if exists loc_lookup_fips",
+        "drop table if exists loc_lookup",
         //
         // From Johns Hopkins UID_ISO_FIPS_LookUp_Table.csv
         // https://github.com/CSSEGISandData/COVID-19/blob/master/csse_covid_19_data/UID_ISO_FIPS_LookUp_Table.csv
@@ -73,6 +189,18 @@ pub async fn initdb<E: Executor>(db: &mut E) -> () {
          combined_key text not null,
          population integer)",
         "create index loc_lookup_fips on loc_lookup (fips)",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// Statements to drop and re-create the `rtlive` tables and view.
+pub fn rtlive_schema() -> Vec<String> {
+    let mut statements: Vec<String> = vec![
+        "drop view if exists rtlive",
+        "drop index if exists rtlive_raw_uniq_idx",
+        "drop table if exists rtlive_raw",
         //
         // rt.live data
         // https://d14wlfuexuxgcm.cloudfront.net/covid/rt.csv
@@ -94,6 +222,26 @@ pub async fn initdb<E: Executor>(db: &mut E) -> () {
          new_cases integer,
          new_deaths integer)",
         "create unique index rtlive_raw_uniq_idx on rtlive_raw (state, date_julian)",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    statements.push(format!("CREATE VIEW rtlive AS select {} as date, {} as date_year, {} as date_month, {} as date_day,
+             rtlive_raw.* FROM rtlive_raw",
+            querystr_jd_to_datestr("rtlive_raw.date_julian"),
+            querystr_jd_to_year("rtlive_raw.date_julian"),
+            querystr_jd_to_month("rtlive_raw.date_julian"),
+            querystr_jd_to_day("rtlive_raw.date_julian"),
+    ));
+    statements
+}
+
+/// Statements to drop and re-create the `harveycodata` tables and view.
+pub fn harveycodata_schema() -> Vec<String> {
+    let mut statements: Vec<String> = vec![
+        "drop index if exists harveycodata_raw_uniq_idx",
+        "drop view if exists harveycodata",
+        "drop table if exists harveycodata_raw",
         // Harvey County data
         "create table harveycodata_raw(
          date_julian integer not null primary key,
@@ -103,6 +251,28 @@ pub async fn initdb<E: Executor>(db: &mut E) -> () {
          harveyco_pos_results integer,
          harveyco_confirmed integer,
          harveyco_recovered integer)",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    statements.push(format!("CREATE VIEW harveycodata AS select {} as date, {} as date_year, {} as date_month, {} as date_day,
+             34429 as population, kdhe_neg_results + kdhe_pos_results AS kdhe_tot_results,
+             harveyco_tot_results - harveyco_pos_results AS harveyco_neg_results,
+             harveycodata_raw.* FROM harveycodata_raw",
+            querystr_jd_to_datestr("harveycodata_raw.date_julian"),
+            querystr_jd_to_year("harveycodata_raw.date_julian"),
+            querystr_jd_to_month("harveycodata_raw.date_julian"),
+            querystr_jd_to_day("harveycodata_raw.date_julian"),
+    ));
+    statements
+}
+
+/// Statements to drop and re-create the `nytcounties` tables and view.
+pub fn nytcounties_schema() -> Vec<String> {
+    let mut statements: Vec<String> = vec![
+        "drop table if exists nytcounties_raw",
+        "drop index if exists nytcounties_raw_uniq_idx",
+        "drop view if exists nytcounties",
         //
         // NY Times - Counties
         //
@@ -114,6 +284,35 @@ pub async fn initdb<E: Executor>(db: &mut E) -> () {
          cases integer not null,
          deaths integer)",
         "create unique index nytcounties_raw_uniq_idx on nytcounties_raw (state, county, date_julian)",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    statements.push(format!("CREATE VIEW nytcounties AS select {} as date, {} as date_year, {} as date_month, {} as date_day,
+             loc_lookup.population AS population,
+                  cases -
+                    (SELECT cases FROM nytcounties_raw AS ntc
+                            WHERE ntc.state = nytcounties_raw.state AND ntc.county = nytcounties_raw.county AND ntc.date_julian = nytcounties_raw.date_julian - 1) AS cases_new,
+                  deaths -
+                    (SELECT deaths FROM nytcounties_raw AS ntc
+                            WHERE ntc.state = nytcounties_raw.state AND ntc.county = nytcounties_raw.county AND ntc.date_julian = nytcounties_raw.date_julian - 1) AS deaths_new,
+             nytcounties_raw.* FROM nytcounties_raw LEFT JOIN loc_lookup ON nytcounties_raw.fips = loc_lookup.fips
+             WHERE nytcounties_raw.fips IS NOT NULL",
+            querystr_jd_to_datestr("nytcounties_raw.date_julian"),
+            querystr_jd_to_year("nytcounties_raw.date_julian"),
+            querystr_jd_to_month("nytcounties_raw.date_julian"),
+            querystr_jd_to_day("nytcounties_raw.date_julian"),
+    ));
+    statements
+}
+
+/// Statements to drop and re-create the `covidtracking` tables and views.
+pub fn covidtracking_schema() -> Vec<String> {
+    let mut statements: Vec<String> = vec![
+        "drop index if exists covidtracking_uniq_idx",
+        "drop view if exists covidtracking",
+        "drop view if exists covidtracking_us",
+        "drop table if exists covidtracking_raw",
         //
         // From https://covidtracking.com/api/v1/states/daily.csv
         //
@@ -175,11 +374,66 @@ pub async fn initdb<E: Executor>(db: &mut E) -> () {
          grade text
          )",
         "create unique index covidtracking_raw_uniq_idx on covidtracking_raw (date_julian, state)",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    statements.extend(vec![
+        format!("CREATE VIEW covidtracking AS select {} as date, {} as date_year, {} as date_month, {} as date_day,
+                 covidtracking_raw.* from covidtracking_raw",
+                querystr_jd_to_datestr("covidtracking_raw.date_julian"),
+                querystr_jd_to_year("covidtracking_raw.date_julian"),
+                querystr_jd_to_month("covidtracking_raw.date_julian"),
+                querystr_jd_to_day("covidtracking_raw.date_julian"),
+        ),
+        // In a build of some states only, their sum is not the US total, so
+        // the view is left empty.
+        String::from("create view covidtracking_us as select date, date_julian, date_year, date_month, date_day,
+        sum(positive) as positive, sum(negative) as negative, sum(pending) as pending,
+        sum(hospitalizedCurrently) as hospitalizedCurrently, sum(hospitalizedCumulative) as hospitalizedCumulative,
+        sum(incluCurrently) as inclueCurrently, sum(incluCumulative) as incluCumulative,
+        sum(onVentilatorCurrently) as onVentilatorCurrently, sum(onVentilatorCumulative) as onVentilatorCumulative,
+        sum(recovered) as recovered, sum(death) as death, sum(hospitalized) as hospitalized,
+        sum(totalTestsViral) as totalTestsViral, sum(positiveTestsViral) as positiveTestsViral,
+        sum(negativeTestsViral) as negativeTestsViral, sum(positiveCasesViral) as positiveCasesViral,
+        sum(deathConfirmed) as deathConfirmed, sum(deathProbable) as deathProbable,
+        sum(totalTestEncountersViral) as totalTestEncountersViral,
+        sum(totalTestsPeopleViral) as totalTestsPeopleViral,
+        sum(totalTestsAntibody) as totalTestsAntibody,
+        sum(positiveTestsAntibody) as positiveTestsAntibody,
+        sum(negativeTestsAntibody) as negativeTestsAntibody,
+        sum(totalTestsPeopleAntibody) as totalTestsPeopleAntibody,
+        sum(positiveTestsPeopleAntibody) as positiveTestsPeopleAntibody,
+        sum(negativeTestsPeopleAntibody) as negativeTestsPeopleAntibody,
+        sum(totalTestsPeopleAntigen) as totalTestsPeopleAntigen,
+        sum(positiveTestsPeopleAntigen) as positiveTestsPeopleAntigen,
+        sum(totalTestsAntigen) as totalTestsAntigen,
+        sum(positiveTestsAntigen) as positiveTestsAntigen,
+        sum(positiveIncrease) as positiveIncrease, sum(negativeIncrease) as negativeIncrease,
+        sum(total) as total, sum(totalTestResults) as totalTestResults,
+        sum(totalTestResultsIncrease) as totalTestResultsIncrease, sum(posNeg) as posNeg,
+        sum(deathIncrease) as deathIncrease, sum(hospitalizedIncrease) as hospitalizedIncrease from covidtracking
+        where not exists (select 1 from covid19db_meta where field in ('subset:states', 'subset:fips'))
+        group by(date)"),
+    ]);
+    statements
+}
+
+/// Statements to drop and re-create the `cdataset` tables and view.
+pub fn cdataset_schema() -> Vec<String> {
+    let mut statements: Vec<String> = vec![
+        "drop index if exists cdataset_raw_uniq_idx",
+        "drop view if exists cdataset",
+        "drop table if exists cdataset_raw",
+        "drop table if exists cdataset_loc",
         //
         // From covid19-datasets
         //
+        // locid is the Johns Hopkins UID of the location, if it has one, or
+        // else is derived from location_key; see LocIds.
         "create table cdataset_loc (
          locid integer not null primary key,
+         location_key text not null unique,
          xtype text not null,
          label text not null,
          country_code text not null,
@@ -243,6 +497,36 @@ pub async fn initdb<E: Executor>(db: &mut E) -> () {
         factbook_median_age real
         )",
         "CREATE UNIQUE INDEX cdataset_raw_uniq_idx ON cdataset_raw (dataset, locid, date_julian)",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    statements.push(format!("CREATE VIEW cdataset AS select {} AS date, {} as date_year, {} as date_month, {} as date_day,
+             cdataset_loc.location_key AS location_key,
+             cdataset_loc.xtype AS location_type,
+             cdataset_loc.label AS location_label,
+             cdataset_loc.country_code AS country_code,
+             cdataset_loc.country_normalized AS country,
+             cdataset_loc.province_normalized AS province,
+             cdataset_loc.administrative_normalized AS administrative,
+             cdataset_loc.region AS region,
+             cdataset_loc.subregion AS subregion,
+             cdataset_loc.us_county_fips AS us_county_fips,
+             cdataset_raw.* FROM cdataset_raw, cdataset_loc WHERE cdataset_raw.locid = cdataset_loc.locid",
+            querystr_jd_to_datestr("cdataset_raw.date_julian"),
+            querystr_jd_to_year("cdataset_raw.date_julian"),
+            querystr_jd_to_month("cdataset_raw.date_julian"),
+            querystr_jd_to_day("cdataset_raw.date_julian"),
+    ));
+    statements
+}
+
+/// Statements to drop and re-create the `owid` tables and view.
+pub fn owid_schema() -> Vec<String> {
+    let mut statements: Vec<String> = vec![
+        "drop view if exists owid",
+        "drop index if exists owid_raw_uniq_idx",
+        "drop table if exists owid_raw",
         //
         // Our World In Data set
         //
@@ -309,112 +593,24 @@ pub async fn initdb<E: Executor>(db: &mut E) -> () {
         excess_mortality
 )",
         "CREATE UNIQUE INDEX owid_raw_uniq_idx ON owid_raw (date_julian, iso_code)",
-    ];
-
-    let views = vec![
-        format!("CREATE VIEW cdataset AS select {} AS date, {} as date_year, {} as date_month, {} as date_day,
-                 cdataset_loc.xtype AS location_type,
-                 cdataset_loc.label AS location_label,
-                 cdataset_loc.country_code AS country_code,
-                 cdataset_loc.country_normalized AS country,
-                 cdataset_loc.province_normalized AS province,
-                 cdataset_loc.administrative_normalized AS administrative,
-                 cdataset_loc.region AS region,
-                 cdataset_loc.subregion AS subregion,
-                 cdataset_loc.us_county_fips AS us_county_fips,
-                 cdataset_raw.* FROM cdataset_raw, cdataset_loc WHERE cdataset_raw.locid = cdataset_loc.locid",
-                querystr_jd_to_datestr("cdataset_raw.date_julian"),
-                querystr_jd_to_year("cdataset_raw.date_julian"),
-                querystr_jd_to_month("cdataset_raw.date_julian"),
-                querystr_jd_to_day("cdataset_raw.date_julian"),
-        ),
-        format!("CREATE VIEW harveycodata AS select {} as date, {} as date_year, {} as date_month, {} as date_day,
-                 34429 as population, kdhe_neg_results + kdhe_pos_results AS kdhe_tot_results,
-                 harveyco_tot_results - harveyco_pos_results AS harveyco_neg_results,
-                 harveycodata_raw.* FROM harveycodata_raw",
-                querystr_jd_to_datestr("harveycodata_raw.date_julian"),
-                querystr_jd_to_year("harveycodata_raw.date_julian"),
-                querystr_jd_to_month("harveycodata_raw.date_julian"),
-                querystr_jd_to_day("harveycodata_raw.date_julian"),
-        ),
-        format!("CREATE VIEW nytcounties AS select {} as date, {} as date_year, {} as date_month, {} as date_day,
-                 loc_lookup.population AS population,
-                      cases -
-                        (SELECT cases FROM nytcounties_raw AS ntc
-                                WHERE ntc.state = nytcounties_raw.state AND ntc.county = nytcounties_raw.county AND ntc.date_julian = nytcounties_raw.date_julian - 1) AS cases_new,
-                      deaths -
-                        (SELECT deaths FROM nytcounties_raw AS ntc
-                                WHERE ntc.state = nytcounties_raw.state AND ntc.county = nytcounties_raw.county AND ntc.date_julian = nytcounties_raw.date_julian - 1) AS deaths_new,
-                 nytcounties_raw.* FROM nytcounties_raw LEFT JOIN loc_lookup ON nytcounties_raw.fips = loc_lookup.fips
-                 WHERE nytcounties_raw.fips IS NOT NULL",
-                querystr_jd_to_datestr("nytcounties_raw.date_julian"),
-                querystr_jd_to_year("nytcounties_raw.date_julian"),
-                querystr_jd_to_month("nytcounties_raw.date_julian"),
-                querystr_jd_to_day("nytcounties_raw.date_julian"),
-        ),
-        format!("CREATE VIEW rtlive AS select {} as date, {} as date_year, {} as date_month, {} as date_day,
-                 rtlive_raw.* FROM rtlive_raw",
-                querystr_jd_to_datestr("rtlive_raw.date_julian"),
-                querystr_jd_to_year("rtlive_raw.date_julian"),
-                querystr_jd_to_month("rtlive_raw.date_julian"),
-                querystr_jd_to_day("rtlive_raw.date_julian"),
-        ),
-        format!("CREATE VIEW covidtracking AS select {} as date, {} as date_year, {} as date_month, {} as date_day,
-                 covidtracking_raw.* from covidtracking_raw",
-                querystr_jd_to_datestr("covidtracking_raw.date_julian"),
-                querystr_jd_to_year("covidtracking_raw.date_julian"),
-                querystr_jd_to_month("covidtracking_raw.date_julian"),
-                querystr_jd_to_day("covidtracking_raw.date_julian"),
-        ),
-        String::from("create view covidtracking_us as select date, date_julian, date_year, date_month, date_day,
-        sum(positive) as positive, sum(negative) as negative, sum(pending) as pending,
-        sum(hospitalizedCurrently) as hospitalizedCurrently, sum(hospitalizedCumulative) as hospitalizedCumulative,
-        sum(incluCurrently) as inclueCurrently, sum(incluCumulative) as incluCumulative,
-        sum(onVentilatorCurrently) as onVentilatorCurrently, sum(onVentilatorCumulative) as onVentilatorCumulative,
-        sum(recovered) as recovered, sum(death) as death, sum(hospitalized) as hospitalized,
-        sum(totalTestsViral) as totalTestsViral, sum(positiveTestsViral) as positiveTestsViral,
-        sum(negativeTestsViral) as negativeTestsViral, sum(positiveCasesViral) as positiveCasesViral,
-        sum(deathConfirmed) as deathConfirmed, sum(deathProbable) as deathProbable,
-        sum(totalTestEncountersViral) as totalTestEncountersViral,
-        sum(totalTestsPeopleViral) as totalTestsPeopleViral,
-        sum(totalTestsAntibody) as totalTestsAntibody,
-        sum(positiveTestsAntibody) as positiveTestsAntibody,
-        sum(negativeTestsAntibody) as negativeTestsAntibody,
-        sum(totalTestsPeopleAntibody) as totalTestsPeopleAntibody,
-        sum(positiveTestsPeopleAntibody) as positiveTestsPeopleAntibody,
-        sum(negativeTestsPeopleAntibody) as negativeTestsPeopleAntibody,
-        sum(totalTestsPeopleAntigen) as totalTestsPeopleAntigen,
-        sum(positiveTestsPeopleAntigen) as positiveTestsPeopleAntigen,
-        sum(totalTestsAntigen) as totalTestsAntigen,
-        sum(positiveTestsAntigen) as positiveTestsAntigen,
-        sum(positiveIncrease) as positiveIncrease, sum(negativeIncrease) as negativeIncrease,
-        sum(total) as total, sum(totalTestResults) as totalTestResults,
-        sum(totalTestResultsIncrease) as totalTestResultsIncrease, sum(posNeg) as posNeg,
-        sum(deathIncrease) as deathIncrease, sum(hospitalizedIncrease) as hospitalizedIncrease from covidtracking group by(date)"),
-        format!("CREATE VIEW owid AS select {} as date, {} as date_year, {} as date_month, {} as date_day,
-                 total_cases_per_million / 10.0 AS total_cases_per_100k,
-                 new_cases_per_million / 10.0 AS new_cases_per_100k,
-                 total_deaths_per_million / 10.0 AS total_deaths_per_100k,
-                 new_deaths_per_million / 10.0 AS new_deaths_per_100k,
-                 total_tests_per_thousand * 100.0 AS total_tests_per_100k,
-                 new_tests_per_thousand * 100.0 AS new_tests_per_100k,
-                 new_tests_smoothed_per_thousand * 100.0 AS new_tests_smoothed_per_100k, owid_raw.* FROM owid_raw",
-                querystr_jd_to_datestr("owid_raw.date_julian"),
-                querystr_jd_to_year("owid_raw.date_julian"),
-                querystr_jd_to_month("owid_raw.date_julian"),
-                querystr_jd_to_day("owid_raw.date_julian"),
-        ),
-    ];
-
-    let mut queries: Vec<String> = statements.into_iter().map(String::from).collect();
-    queries.extend(views);
-
-    for query in queries {
-        println!("PREP: executing {}", query);
-        db.execute(query.as_str())
-            .await
-            .expect("Error executing statement");
-    }
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    statements.push(format!("CREATE VIEW owid AS select {} as date, {} as date_year, {} as date_month, {} as date_day,
+             total_cases_per_million / 10.0 AS total_cases_per_100k,
+             new_cases_per_million / 10.0 AS new_cases_per_100k,
+             total_deaths_per_million / 10.0 AS total_deaths_per_100k,
+             new_deaths_per_million / 10.0 AS new_deaths_per_100k,
+             total_tests_per_thousand * 100.0 AS total_tests_per_100k,
+             new_tests_per_thousand * 100.0 AS new_tests_per_100k,
+             new_tests_smoothed_per_thousand * 100.0 AS new_tests_smoothed_per_100k, owid_raw.* FROM owid_raw",
+            querystr_jd_to_datestr("owid_raw.date_julian"),
+            querystr_jd_to_year("owid_raw.date_julian"),
+            querystr_jd_to_month("owid_raw.date_julian"),
+            querystr_jd_to_day("owid_raw.date_julian"),
+    ));
+    statements
 }
 
 /// Returns a SQLite query string converting the Julian date to a date string for the given column
@@ -16,7 +16,19 @@ Copyright (c) 2019-2020 John Goerzen
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use crate::Error;
 use sqlx::prelude::*;
+use sqlx::sqlite::SqlitePool;
+use sqlx::{Query, Sqlite};
+use std::path::Path;
+
+/// Opens (creating if necessary) the SQLite database at `path`.
+pub async fn open_pool(path: &Path, max_size: u32) -> Result<SqlitePool, sqlx::Error> {
+    SqlitePool::builder()
+        .max_size(max_size)
+        .build(format!("sqlite::{}", path.display()).as_str())
+        .await
+}
 
 pub async fn assert_one_i64(
     expected: i64,
@@ -35,3 +47,298 @@ pub async fn assert_one_opti64(
     let val: (Option<i64>,) = sqlx::query_as(query).fetch_one(conn).await.unwrap();
     assert_eq!(expected, val.0);
 }
+
+/// Checks that `query` returns `expected` as its single value.
+pub async fn check_one_opti64(
+    expected: Option<i64>,
+    query: &str,
+    conn: &mut sqlx::pool::PoolConnection<sqlx::SqliteConnection>,
+) -> crate::Result<()> {
+    let val: (Option<i64>,) = sqlx::query_as(query).fetch_one(conn).await?;
+    if val.0 == expected {
+        Ok(())
+    } else {
+        Err(Error::Check(format!(
+            "{}: expected {:?}, got {:?}",
+            query, expected, val.0
+        )))
+    }
+}
+
+/// Checks that `table` has at least `minrows` rows.
+pub async fn check_min_rows(
+    minrows: i64,
+    table: &str,
+    conn: &mut sqlx::pool::PoolConnection<sqlx::SqliteConnection>,
+) -> crate::Result<()> {
+    let rows = count_rows(conn, table).await?;
+    if rows >= minrows {
+        Ok(())
+    } else {
+        Err(Error::Check(format!(
+            "{} has {} rows; expected at least {}",
+            table, rows, minrows
+        )))
+    }
+}
+
+/// Returns the names and types (`table` or `view`) of all tables and views in the database,
+/// sorted by name.  SQLite's internal tables are omitted.
+pub async fn list_relations(
+    conn: &mut sqlx::pool::PoolConnection<sqlx::SqliteConnection>,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT name, type FROM sqlite_master WHERE type IN ('table', 'view')
+         AND name NOT LIKE 'sqlite_%' ORDER BY name",
+    )
+    .fetch_all(conn)
+    .await
+}
+
+/// Returns the column names of the given table or view, in order.
+pub async fn column_names(
+    conn: &mut sqlx::pool::PoolConnection<sqlx::SqliteConnection>,
+    table: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    let query = format!("PRAGMA table_info({})", quote_ident(table));
+    let mut cursor = sqlx::query(query.as_str()).fetch(conn);
+    let mut names = Vec::new();
+    while let Some(row) = cursor.next().await? {
+        names.push(row.get::<String, &str>("name"));
+    }
+    Ok(names)
+}
+
+/// Returns the number of rows in the given table or view.
+pub async fn count_rows(
+    conn: &mut sqlx::pool::PoolConnection<sqlx::SqliteConnection>,
+    table: &str,
+) -> Result<i64, sqlx::Error> {
+    let rows: (i64,) =
+        sqlx::query_as(format!("SELECT COUNT(*) FROM {}", quote_ident(table)).as_str())
+            .fetch_one(conn)
+            .await?;
+    Ok(rows.0)
+}
+
+/** Quote an identifier for use in SQLite SQL.
+
+```
+use covid19db::dbutil::quote_ident;
+
+assert_eq!(quote_ident("owid"), "\"owid\"");
+assert_eq!(quote_ident("a\"b"), "\"a\"\"b\"");
+```
+*/
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Writes every row of the given table or view as CSV, with a header row.  All values are
+/// written in their SQLite text representation; NULL becomes an empty field.
+pub async fn export_csv<W: std::io::Write>(
+    conn: &mut sqlx::pool::PoolConnection<sqlx::SqliteConnection>,
+    table: &str,
+    wtr: &mut csv::Writer<W>,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    if !list_relations(conn)
+        .await?
+        .iter()
+        .any(|(name, _)| name == table)
+    {
+        return Err(format!("No table or view named {:?}", table).into());
+    }
+    let columns = column_names(conn, table).await?;
+    wtr.write_record(&columns)?;
+    let query = format!(
+        "SELECT {} FROM {}",
+        columns
+            .iter()
+            .map(|c| format!("CAST({} AS TEXT)", quote_ident(c)))
+            .collect::<Vec<String>>()
+            .join(", "),
+        quote_ident(table)
+    );
+    let mut cursor = sqlx::query(query.as_str()).fetch(conn);
+    let mut written: u64 = 0;
+    while let Some(row) = cursor.next().await? {
+        let fields: Vec<String> = (0..columns.len())
+            .map(|i| row.get::<Option<String>, usize>(i).unwrap_or_default())
+            .collect();
+        wtr.write_record(&fields)?;
+        written += 1;
+    }
+    wtr.flush()?;
+    Ok(written)
+}
+
+/// Binds the values of one row to a query; see [`BulkInsert`].
+pub type Binder<T> = for<'q> fn(T, Query<'q, Sqlite>) -> Query<'q, Sqlite>;
+
+/// The most parameters SQLite accepts in one statement by default.
+const MAX_PARAMS: usize = 999;
+
+/** Inserts rows with multi-row `INSERT ... VALUES (...), (...)` statements,
+which SQLite processes far faster than one statement per row.
+
+`insert` is a single-row statement such as the `insert_str` functions in
+[`dbschema`](crate::dbschema) return, and `bind` binds one row to a query, as
+their `bind_query` methods do.  Rows queued with [`push`](BulkInsert::push)
+are written as soon as a batch is full; [`finish`](BulkInsert::finish) writes
+the rest.  Every full batch uses the same SQL, so the connection prepares the
+statement once and reuses it from then on.
+
+```
+use covid19db::dbutil::{count_rows, open_pool, BulkInsert};
+use sqlx::prelude::*;
+use sqlx::{Query, Sqlite};
+
+fn bind(row: (i64, String), query: Query<Sqlite>) -> Query<Sqlite> {
+    query.bind(row.0).bind(row.1)
+}
+
+# #[tokio::main]
+# async fn main() -> covid19db::Result<()> {
+# let dir = tempfile::tempdir()?;
+let pool = open_pool(&dir.path().join("test.db"), 1).await?;
+let mut conn = pool.acquire().await?;
+conn.execute("CREATE TABLE t (n integer, s text)").await?;
+let mut inserter = BulkInsert::new("INSERT INTO t VALUES (?, ?)", bind).batch_size(2);
+for n in 0..5 {
+    inserter.push((n, n.to_string()), &mut conn).await?;
+}
+assert_eq!(inserter.finish(&mut conn).await?, 5);
+assert_eq!(count_rows(&mut conn, "t").await?, 5);
+# Ok(())
+# }
+```
+*/
+pub struct BulkInsert<T> {
+    prefix: String,
+    row: String,
+    batch_size: usize,
+    sql: String,
+    bind: Binder<T>,
+    pending: Vec<T>,
+    inserted: u64,
+    discard: bool,
+}
+
+impl<T: Send> BulkInsert<T> {
+    /// Creates an inserter with the largest batches SQLite allows for `insert`.
+    pub fn new(insert: &str, bind: Binder<T>) -> Self {
+        let idx = insert
+            .find(" VALUES ")
+            .expect("INSERT statement without VALUES");
+        let prefix = String::from(&insert[..idx]);
+        let row = String::from(insert[idx + 8..].trim());
+        let batch_size = MAX_PARAMS / row.matches('?').count().max(1);
+        BulkInsert {
+            sql: batch_sql(&prefix, &row, batch_size),
+            prefix,
+            row,
+            batch_size,
+            bind,
+            pending: Vec::new(),
+            inserted: 0,
+            discard: false,
+        }
+    }
+
+    /** Sets the number of rows per statement.  It is limited to what fits
+    SQLite's limit on parameters; 1 gives the plain one-row-at-a-time
+    behavior. */
+    pub fn batch_size(mut self, rows: usize) -> Self {
+        self.batch_size = rows.max(1).min(self.batch_size);
+        self.sql = batch_sql(&self.prefix, &self.row, self.batch_size);
+        self
+    }
+
+    /** If `discard` is set, rows are counted as if they were inserted, but
+    nothing is written; loaders use this for a [dry
+    run](crate::loader::LoadContext::dry_run).
+
+    ```
+    use covid19db::dbutil::{count_rows, open_pool, BulkInsert};
+    use sqlx::prelude::*;
+    use sqlx::{Query, Sqlite};
+
+    fn bind(n: i64, query: Query<Sqlite>) -> Query<Sqlite> {
+        query.bind(n)
+    }
+
+    # #[tokio::main]
+    # async fn main() -> covid19db::Result<()> {
+    # let dir = tempfile::tempdir()?;
+    let pool = open_pool(&dir.path().join("test.db"), 1).await?;
+    let mut conn = pool.acquire().await?;
+    conn.execute("CREATE TABLE t (n integer)").await?;
+    let mut inserter = BulkInsert::new("INSERT INTO t VALUES (?)", bind).discard(true);
+    for n in 0..5 {
+        inserter.push(n, &mut conn).await?;
+    }
+    assert_eq!(inserter.finish(&mut conn).await?, 5);
+    assert_eq!(count_rows(&mut conn, "t").await?, 0);
+    # Ok(())
+    # }
+    ```
+    */
+    pub fn discard(mut self, discard: bool) -> Self {
+        self.discard = discard;
+        self
+    }
+
+    /// Queues a row, writing a batch if one is full.
+    pub async fn push<E: Executor<Database = Sqlite>>(
+        &mut self,
+        row: T,
+        db: &mut E,
+    ) -> crate::Result<()> {
+        self.pending.push(row);
+        if self.pending.len() >= self.batch_size {
+            self.flush(db).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes any queued rows, and returns the number of rows inserted in all.
+    pub async fn finish<E: Executor<Database = Sqlite>>(
+        mut self,
+        db: &mut E,
+    ) -> crate::Result<u64> {
+        self.flush(db).await?;
+        Ok(self.inserted)
+    }
+
+    async fn flush<E: Executor<Database = Sqlite>>(&mut self, db: &mut E) -> crate::Result<()> {
+        let rows = self.pending.len();
+        if rows == 0 {
+            return Ok(());
+        }
+        if self.discard {
+            self.pending.clear();
+            self.inserted += rows as u64;
+            return Ok(());
+        }
+        // Only the last batch is short, so its SQL isn't worth keeping.
+        let short;
+        let sql = if rows == self.batch_size {
+            &self.sql
+        } else {
+            short = batch_sql(&self.prefix, &self.row, rows);
+            &short
+        };
+        let mut query = sqlx::query(sql);
+        for row in self.pending.drain(..) {
+            query = (self.bind)(row, query);
+        }
+        query.execute(&mut *db).await?;
+        self.inserted += rows as u64;
+        Ok(())
+    }
+}
+
+/// Returns an INSERT statement for `rows` rows.
+fn batch_sql(prefix: &str, row: &str, rows: usize) -> String {
+    format!("{} VALUES {}", prefix, vec![row; rows].join(", "))
+}
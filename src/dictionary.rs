@@ -0,0 +1,273 @@
+/* The data dictionary
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! What each column of the database holds.
+
+The descriptions are kept in `src/columns.toml`, which also documents its
+syntax.  Every build writes them, for each column of each table and view
+actually in the database, to the `covid19db_columns` table, which
+[`read`] returns. */
+
+use serde::Deserialize;
+use sqlx::pool::PoolConnection;
+use sqlx::prelude::*;
+use sqlx::SqliteConnection;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::dbutil::{list_relations, quote_ident};
+use crate::{Error, Result};
+
+const BUILTIN: &str = include_str!("columns.toml");
+
+/// How a figure relates to the days around it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// A running total to date.
+    Cumulative,
+    /// A figure for that day alone.
+    Daily,
+    /// Computed from other figures, such as a rate per 100,000 people.
+    Derived,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Cumulative => "cumulative",
+            Kind::Daily => "daily",
+            Kind::Derived => "derived",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Kind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cumulative" => Ok(Kind::Cumulative),
+            "daily" => Ok(Kind::Daily),
+            "derived" => Ok(Kind::Derived),
+            _ => Err(Error::Config(format!("Unknown column kind {:?}", s))),
+        }
+    }
+}
+
+/// What one column holds.  Any part may be unknown.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnDoc {
+    pub description: Option<String>,
+    /// What the column is counted or measured in, such as "cases per 100,000 people".
+    pub unit: Option<String>,
+    pub kind: Option<Kind>,
+    /// The column of the source file the value comes from.
+    pub source_column: Option<String>,
+    /// What is done to the value on its way into the database.
+    pub transformation: Option<String>,
+}
+
+/// The descriptions of the columns of one table or view.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TableDoc {
+    pub name: String,
+    /// Tables whose columns of the same name mean the same in this one.
+    #[serde(default)]
+    pub like: Vec<String>,
+    /// What is done to the columns taken from `like`.
+    pub transformation: Option<String>,
+    /// True if each column comes from the source file's column of the same
+    /// name, unless its `source_column` says otherwise.
+    #[serde(default)]
+    pub upstream: bool,
+    #[serde(default)]
+    pub columns: BTreeMap<String, ColumnDoc>,
+}
+
+/** The descriptions of the database's columns, as read from a dictionary
+file such as `src/columns.toml`.
+
+```
+use covid19db::dictionary::{Dictionary, Kind};
+
+let dict = Dictionary::from_toml(
+    r#"
+    [common]
+    date_julian = { description = "The date, as a Julian day number", unit = "days" }
+
+    [[table]]
+    name = "owid_raw"
+    upstream = true
+    [table.columns]
+    total_cases = { description = "Confirmed cases to date", kind = "cumulative" }
+
+    [[table]]
+    name = "owid"
+    like = ["owid_raw"]
+    "#,
+)
+.unwrap();
+let cases = dict.describe("owid", "total_cases").unwrap();
+assert_eq!(cases.kind, Some(Kind::Cumulative));
+assert_eq!(cases.source_column.as_deref(), Some("total_cases"));
+assert_eq!(dict.describe("owid", "date_julian").unwrap().unit.as_deref(), Some("days"));
+assert_eq!(dict.describe("owid", "new_cases"), None);
+```
+*/
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Dictionary {
+    /// Columns that mean the same in every table that has them.
+    #[serde(default)]
+    pub common: BTreeMap<String, ColumnDoc>,
+    #[serde(default, rename = "table")]
+    pub tables: Vec<TableDoc>,
+}
+
+/// A column of a table or view in a database, as listed in `covid19db_columns`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Column {
+    pub table: String,
+    pub name: String,
+    /// The type the column was declared with; empty for computed view columns.
+    pub sql_type: String,
+    pub doc: ColumnDoc,
+}
+
+impl Dictionary {
+    /// The descriptions of the columns of the tables and views this crate creates.
+    pub fn builtin() -> Self {
+        Dictionary::from_toml(BUILTIN).expect("built-in dictionary is invalid")
+    }
+
+    /// Parses a dictionary file.
+    pub fn from_toml(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|e| Error::Config(format!("Invalid dictionary: {}", e)))
+    }
+
+    /** Describes `column` of `table`: from the table's own entry for it, or
+    else that of the first table in its `like` list that has one, or else
+    the common entry. */
+    pub fn describe(&self, table: &str, column: &str) -> Option<ColumnDoc> {
+        self.lookup(table, column)
+            .or_else(|| self.common.get(column).cloned())
+    }
+
+    fn lookup(&self, table: &str, column: &str) -> Option<ColumnDoc> {
+        let table = self.tables.iter().find(|x| x.name == table)?;
+        if let Some(doc) = table.columns.get(column) {
+            let mut doc = doc.clone();
+            if table.upstream && doc.source_column.is_none() {
+                doc.source_column = Some(String::from(column));
+            }
+            return Some(doc);
+        }
+        let mut doc = table.like.iter().find_map(|x| self.lookup(x, column))?;
+        if let Some(transformation) = &table.transformation {
+            doc.transformation = Some(match doc.transformation {
+                Some(x) => format!("{}; then {}", x, transformation),
+                None => transformation.clone(),
+            });
+        }
+        Some(doc)
+    }
+
+    /** Re-creates the `covid19db_columns` table, listing every column of
+    every table and view in the database, with its description where this
+    dictionary has one. */
+    pub async fn write(&self, conn: &mut PoolConnection<SqliteConnection>) -> Result<()> {
+        conn.execute("drop table if exists covid19db_columns")
+            .await?;
+        conn.execute(
+            "create table covid19db_columns (
+             table_name text not null,
+             column_name text not null,
+             type text not null,
+             description text,
+             unit text,
+             kind text,
+             source_column text,
+             transformation text)",
+        )
+        .await?;
+        for (table, _) in list_relations(conn).await? {
+            for (name, sql_type) in column_types(conn, &table).await? {
+                let doc = self.describe(&table, &name).unwrap_or_default();
+                sqlx::query("insert into covid19db_columns values (?, ?, ?, ?, ?, ?, ?, ?)")
+                    .bind(&table)
+                    .bind(&name)
+                    .bind(sql_type)
+                    .bind(doc.description)
+                    .bind(doc.unit)
+                    .bind(doc.kind.map(|x| x.as_str()))
+                    .bind(doc.source_column)
+                    .bind(doc.transformation)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the names and declared types of the columns of `table`, in order.
+async fn column_types(
+    conn: &mut PoolConnection<SqliteConnection>,
+    table: &str,
+) -> Result<Vec<(String, String)>> {
+    let query = format!("PRAGMA table_info({})", quote_ident(table));
+    let mut cursor = sqlx::query(query.as_str()).fetch(conn);
+    let mut columns = Vec::new();
+    while let Some(row) = cursor.next().await? {
+        columns.push((row.try_get("name")?, row.try_get("type")?));
+    }
+    Ok(columns)
+}
+
+/** Reads the `covid19db_columns` table of a database built by this crate,
+listing its columns by table and in the order they were declared. */
+pub async fn read(conn: &mut PoolConnection<SqliteConnection>) -> Result<Vec<Column>> {
+    let mut columns = Vec::new();
+    let mut cursor = sqlx::query("SELECT * FROM covid19db_columns ORDER BY rowid").fetch(conn);
+    while let Some(row) = cursor.next().await? {
+        let kind: Option<String> = row.try_get("kind")?;
+        columns.push(Column {
+            table: row.try_get("table_name")?,
+            name: row.try_get("column_name")?,
+            sql_type: row.try_get("type")?,
+            doc: ColumnDoc {
+                description: row.try_get("description")?,
+                unit: row.try_get("unit")?,
+                kind: kind.map(|x| x.parse()).transpose()?,
+                source_column: row.try_get("source_column")?,
+                transformation: row.try_get("transformation")?,
+            },
+        });
+    }
+    Ok(columns)
+}
@@ -0,0 +1,235 @@
+/* Error type
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+
+/** Errors that can occur while building or checking the database.  Nothing
+in the loader panics on bad input; it returns one of these instead, so callers
+can decide what to do. */
+#[derive(Debug)]
+pub enum Error {
+    /// A file could not be downloaded from `url`.
+    Download { url: String, error: reqwest::Error },
+    /// The server at `url` stopped responding.
+    Timeout { url: String },
+    /// The server at `url` sent a different amount of data than it said it would.
+    ContentLength {
+        url: String,
+        expected: u64,
+        received: u64,
+    },
+    /// A download from `url` could not be completed for some other reason.
+    Transfer { url: String, message: String },
+    /// The file from `url` doesn't have the SHA-256 it was pinned to.
+    Checksum {
+        url: String,
+        expected: String,
+        found: String,
+    },
+    /// The header row of a source file lacks columns the loader requires.
+    HeaderMismatch {
+        source: String,
+        missing: Vec<String>,
+        found: Vec<String>,
+    },
+    /// A row of a source file could not be parsed.  `line` is the 1-based line
+    /// number in the file, or the row number for sources that aren't text.
+    /// `record` is the text of the row, if known, or empty.
+    RowParse {
+        source: String,
+        line: u64,
+        message: String,
+        record: String,
+    },
+    /// More records of `source` could not be parsed than its budget allows.
+    /// `first` describes the first of them.
+    TooManyRejects {
+        source: String,
+        rejected: u64,
+        allowed: u64,
+        first: String,
+    },
+    /// A database operation failed.
+    Sql(sqlx::Error),
+    /// Reading or writing a local file failed.
+    Io(std::io::Error),
+    /// The options given to the loader were invalid, such as an unknown source name.
+    Config(String),
+    /// A check on the loaded data failed.
+    Check(String),
+}
+
+/// A `Result` whose error type is [`Error`].
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /** Builds an [`Error::RowParse`] from a CSV error, taking the line number from
+    the error if it has one.
+
+    ```
+    use covid19db::Error;
+
+    let mut rdr = csv::Reader::from_reader("a,b\n1,2\n3,x\n".as_bytes());
+    let err = rdr
+        .deserialize::<(i64, i64)>()
+        .find_map(|x| x.err())
+        .unwrap();
+    match Error::row_parse("test", &err) {
+        Error::RowParse { source, line, .. } => {
+            assert_eq!(source, "test");
+            assert_eq!(line, 3);
+        }
+        e => panic!("unexpected {:?}", e),
+    }
+    ```
+    */
+    pub fn row_parse(source: &str, err: &csv::Error) -> Self {
+        Error::RowParse {
+            source: String::from(source),
+            line: err.position().map(|p| p.line()).unwrap_or(0),
+            message: err.to_string(),
+            record: String::new(),
+        }
+    }
+
+    /** Sets the text of the row in an [`Error::RowParse`] to `record`, written as
+    CSV.  Other errors are returned unchanged.
+
+    ```
+    use covid19db::Error;
+
+    let mut rdr = csv::Reader::from_reader("a,b\n1,x\n".as_bytes());
+    let rec = rdr.records().next().unwrap().unwrap();
+    let err = rec.deserialize::<(i64, i64)>(None).unwrap_err();
+    match Error::row_parse("test", &err).with_record(&rec) {
+        Error::RowParse { record, .. } => assert_eq!(record, "1,x"),
+        e => panic!("unexpected {:?}", e),
+    }
+    ```
+    */
+    pub fn with_record(self, rec: &csv::StringRecord) -> Self {
+        match self {
+            Error::RowParse {
+                source,
+                line,
+                message,
+                ..
+            } => {
+                let mut buf = Vec::new();
+                let written = {
+                    let mut wtr = csv::Writer::from_writer(&mut buf);
+                    wtr.write_record(rec).is_ok() && wtr.flush().is_ok()
+                };
+                let record = if written {
+                    String::from_utf8_lossy(&buf).trim_end().to_string()
+                } else {
+                    String::new()
+                };
+                Error::RowParse {
+                    source,
+                    line,
+                    message,
+                    record,
+                }
+            }
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Download { url, error } => write!(f, "Error downloading {}: {}", url, error),
+            Error::Timeout { url } => write!(f, "Timed out downloading {}", url),
+            Error::ContentLength {
+                url,
+                expected,
+                received,
+            } => write!(
+                f,
+                "Error downloading {}: expected {} bytes, received {}",
+                url, expected, received
+            ),
+            Error::Transfer { url, message } => {
+                write!(f, "Error downloading {}: {}", url, message)
+            }
+            Error::Checksum {
+                url,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} has SHA-256 {}, but {} was expected",
+                url, found, expected
+            ),
+            Error::HeaderMismatch {
+                source,
+                missing,
+                found,
+            } => write!(
+                f,
+                "{}: required columns {:?} are missing; found {:?}",
+                source, missing, found
+            ),
+            Error::RowParse {
+                source,
+                line,
+                message,
+                ..
+            } => write!(f, "{}: line {}: {}", source, line, message),
+            Error::TooManyRejects {
+                source,
+                rejected,
+                allowed,
+                first,
+            } => write!(
+                f,
+                "{}: {} records could not be parsed, more than the {} allowed; the first was {}",
+                source, rejected, allowed, first
+            ),
+            Error::Sql(e) => write!(f, "Database error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Config(msg) => write!(f, "{}", msg),
+            Error::Check(msg) => write!(f, "Check failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Download { error, .. } => Some(error),
+            Error::Sql(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        Error::Sql(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
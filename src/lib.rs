@@ -19,7 +19,14 @@ Copyright (c) 2019-2020 John Goerzen
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+pub mod attribution;
 pub mod dateutil;
 pub mod dbschema;
 pub mod dbutil;
+pub mod dictionary;
+mod error;
 pub mod loader;
+pub mod progress;
+pub mod validate;
+
+pub use error::{Error, Result};
@@ -16,287 +16,1076 @@ Copyright (c) 2019-2021 John Goerzen
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use zstd::stream::write::Decoder;
-use reqwest;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, FuturesUnordered, StreamExt, TryStreamExt};
 use sqlx::prelude::*;
 use sqlx::sqlite::SqlitePool;
-use std::fs::OpenOptions;
-use std::io::{Seek, SeekFrom, Write};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
 use std::mem::drop;
+use std::path::{Path, PathBuf};
 use tempfile::tempdir;
 
-use crate::dbschema;
+use crate::dateutil::nd_to_day;
+use crate::dbschema::{self, querystr_jd_to_datestr};
 use crate::dbutil::*;
+use crate::dictionary::Dictionary;
+use crate::progress::{Event, Progress};
+use crate::validate::{Check, CheckResult, Checks, Kind, Outcome, Report, Severity};
+use crate::{Error, Result};
 mod combinedloader;
 mod combinedlocloader;
+mod compression;
+mod config;
 mod covidtrackingloader;
+mod download;
+mod dryrun;
 mod harveycodataloader;
 mod loclookuploader;
+mod manifest;
+mod nytcountiesloader;
 mod owidloader;
 mod parseutil;
 mod rtliveloader;
-mod nytcountiesloader;
+mod source;
+mod subset;
+
+pub use combinedloader::CDataSetSource;
+pub use combinedlocloader::{LocIds, Location, HASHED_LOCIDS};
+pub use compression::Compression;
+pub use config::{FileConfig, SourceConfig, SourcesConfig};
+pub use covidtrackingloader::CovidTrackingSource;
+pub use download::{downloadto, downloadto_if_changed, DownloadOptions, Validators};
+pub use dryrun::{DryRunReport, SourceReport};
+pub use harveycodataloader::HarveyCoDataSource;
+pub use loclookuploader::{JhuUids, LocLookupSource};
+pub use manifest::{Manifest, ManifestInput};
+pub use nytcountiesloader::NYTCountiesSource;
+pub use owidloader::OWIDSource;
+pub use parseutil::{Encoding, HeaderDrift};
+pub use rtliveloader::RTLiveSource;
+pub use source::{
+    Coverage, Fetcher, FileInfo, LoadContext, LoadStats, Registry, Reject, Source, SourceFile,
+};
+pub use subset::{Place, Subset};
+
+/** Options controlling a build of the database. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadOptions {
+    /// Path of the database to write.
+    pub output: PathBuf,
+    /// If non-empty, only these sources, plus those they depend on, are processed.
+    pub only: Vec<String>,
+    /// Sources not to process.
+    pub skip: Vec<String>,
+    /// If set, source files are read from this directory, as populated by [`fetch`],
+    /// instead of being downloaded.
+    pub input_dir: Option<PathBuf>,
+    /// Update an existing database, reloading only the sources that have changed.
+    /// See [`Registry::load`].
+    pub incremental: bool,
+    /// Timeouts and retries for downloads.
+    pub download: DownloadOptions,
+    /// When replacing an existing database, keep it as `<output>.prev`.
+    pub keep_previous: bool,
+    /// A check file to run in addition to [`Checks::builtin`].
+    pub checks: Option<PathBuf>,
+    /// If set, the checks fail if the latest data of a selected source was
+    /// more than this many days old when its files were last fetched or found
+    /// unchanged, as recorded in `source_coverage`.  Otherwise, the checks
+    /// only warn, after a week.
+    pub max_staleness: Option<u32>,
+    /// Keep the `cdataset` location ids given by the database at this path,
+    /// typically the one being replaced; see [`LocIds`].  Its SHA-256 is
+    /// recorded in `covid19db_meta`, and so in the [`Manifest`].
+    pub locids_from: Option<PathBuf>,
+    /// How many records of each source may fail to parse before the build
+    /// fails.  Rejected records are left out and listed in `load_rejects`.
+    pub max_rejects: u64,
+    /// Overrides `max_rejects` for the named sources.
+    pub max_rejects_for: HashMap<String, u64>,
+    /// Where to report progress, including that of downloads; this takes the
+    /// place of `download.progress`.
+    pub progress: Progress,
+    /// Continue the build left unfinished in `<output>.partial`, if there is
+    /// one, skipping the sources it completed.  See [`Registry::load`].
+    pub resume: bool,
+    /// A source configuration file to apply on top of [`SourcesConfig::builtin`].
+    /// This is used by [`load_with`], [`fetch`] and [`verify`]; see [`registry`].
+    pub sources: Option<PathBuf>,
+    /// The part of the data to load; see [`Subset`].  It is recorded in
+    /// `covid19db_meta`, and an incremental or resumed build must ask for
+    /// the same subset as the database holds.
+    pub subset: Subset,
+    /// If set, recorded as the time each file was fetched, in place of the
+    /// actual time, so that builds from the same files are byte-identical.
+    /// The command line takes it from `SOURCE_DATE_EPOCH`.
+    pub timestamp: Option<DateTime<Utc>>,
+}
 
-pub async fn downloadto<W: Write>(url: &str, file: &mut W) {
-    let mut result = reqwest::get(url).await.unwrap();
-    // let mut counter: usize = 0;
-    while let Some(chunk) = result.chunk().await.unwrap() {
-        // counter += chunk.len();
-        file.write_all(chunk.as_ref()).unwrap();
-        // println!("{}", counter);
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            output: PathBuf::from("covid19.db"),
+            only: Vec::new(),
+            skip: Vec::new(),
+            input_dir: None,
+            incremental: false,
+            download: DownloadOptions::default(),
+            keep_previous: false,
+            checks: None,
+            max_staleness: None,
+            locids_from: None,
+            max_rejects: 10,
+            max_rejects_for: HashMap::new(),
+            progress: Progress::default(),
+            resume: false,
+            sources: None,
+            subset: Subset::default(),
+            timestamp: None,
+        }
     }
 }
 
-/** Downloads the data and puts it in `covid19.db` in the current working directory. */
-pub async fn load() {
-    let tmp_dir = tempdir().unwrap();
-    let tmp_path = tmp_dir.path().to_owned();
-    let mut stdoptions = &mut OpenOptions::new();
-    stdoptions = stdoptions
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true);
-
-    // OUTPUT DB INIT
-
-    println!("Initializing output database");
-    let mut outputpool = SqlitePool::builder()
-        .max_size(1)
-        .build("sqlite::covid19.db")
+impl LoadOptions {
+    /// Returns the download options, reporting to `progress`.
+    pub fn download(&self) -> DownloadOptions {
+        DownloadOptions {
+            progress: self.progress.clone(),
+            ..self.download.clone()
+        }
+    }
+
+    /// Returns how many records of `source` may fail to parse.
+    pub fn max_rejects(&self, source: &str) -> u64 {
+        self.max_rejects_for
+            .get(source)
+            .copied()
+            .unwrap_or(self.max_rejects)
+    }
+}
+
+/// The names of the built-in sources, in the order they are registered.
+const PARSERS: &[&str] = &[
+    "loc_lookup",
+    "nytcounties",
+    "harveycodata",
+    "covidtracking",
+    "owid",
+    "rtlive",
+    "cdataset",
+];
+
+/// Returns the built-in source with the given name.
+fn builtin_source(name: &str) -> Option<Box<dyn Source>> {
+    let source: Box<dyn Source> = match name {
+        "loc_lookup" => Box::new(LocLookupSource),
+        "nytcounties" => Box::new(NYTCountiesSource),
+        "harveycodata" => Box::new(HarveyCoDataSource),
+        "covidtracking" => Box::new(CovidTrackingSource),
+        "owid" => Box::new(OWIDSource),
+        "rtlive" => Box::new(RTLiveSource),
+        "cdataset" => Box::new(CDataSetSource),
+        _ => return None,
+    };
+    Some(source)
+}
+
+impl Registry {
+    /// Returns a registry holding all the sources built in to this crate,
+    /// with the files given in [`SourcesConfig::builtin`].
+    pub fn builtin() -> Self {
+        let mut registry = Registry::new();
+        for name in PARSERS {
+            registry.register(builtin_source(name).unwrap());
+        }
+        registry
+    }
+
+    /** Returns a registry holding the sources in `config`, each loaded by the
+    built-in source named as its parser but fetching the configured files.
+    Disabled sources are registered but [`disable`](Registry::disable)d.  It
+    is an error to name an unknown parser, or to enable two sources with the
+    same parser, since they would load the same tables.
+
+    ```
+    use covid19db::loader::{LoadOptions, Registry, SourcesConfig};
+
+    let mut config = SourcesConfig::builtin();
+    let disable = r#"
+        [[source]]
+        name = "rtlive"
+        enabled = false
+    "#;
+    config.extend(SourcesConfig::from_toml(disable).unwrap());
+    let registry = Registry::from_config(&config).unwrap();
+    let names: Vec<&str> = registry
+        .select(&LoadOptions::default())
+        .unwrap()
+        .iter()
+        .map(|x| x.name())
+        .collect();
+    assert!(!names.contains(&"rtlive"));
+    assert!(registry.get("rtlive").is_some());
+    ```
+    */
+    pub fn from_config(config: &SourcesConfig) -> Result<Self> {
+        config::registry(config, builtin_source, PARSERS)
+    }
+
+    /** Returns the sources selected by `opts.only` and `opts.skip`, in
+    registry order.  Sources that a selected source depends on are pulled in
+    automatically; it is an error to skip one explicitly, or to name a source
+    that isn't registered.  Disabled sources are never selected, and it is an
+    error to ask for one with `only`, or to select a source that depends on one.
+
+    ```
+    use covid19db::loader::{LoadOptions, Registry};
+
+    let registry = Registry::builtin();
+    let opts = LoadOptions {
+        only: vec![String::from("cdataset")],
+        ..LoadOptions::default()
+    };
+    let names: Vec<&str> = registry.select(&opts).unwrap().iter().map(|x| x.name()).collect();
+    assert_eq!(names, vec!["loc_lookup", "cdataset"]);
+
+    let opts = LoadOptions {
+        skip: vec![String::from("bogus")],
+        ..LoadOptions::default()
+    };
+    assert!(registry.select(&opts).is_err());
+    ```
+    */
+    pub fn select(&self, opts: &LoadOptions) -> Result<Vec<&dyn Source>> {
+        opts.subset.check()?;
+        let datasets: Vec<String> = self.iter().flat_map(|x| x.datasets(&x.files())).collect();
+        opts.subset.check_datasets(&datasets)?;
+        for name in opts
+            .only
+            .iter()
+            .chain(opts.skip.iter())
+            .chain(opts.max_rejects_for.keys())
+        {
+            if self.get(name).is_none() {
+                return Err(Error::Config(format!(
+                    "Unknown source {:?}; valid sources are: {}",
+                    name,
+                    self.names().join(", ")
+                )));
+            }
+        }
+        if let Some(name) = opts.only.iter().find(|x| self.is_disabled(x)) {
+            return Err(Error::Config(format!(
+                "{} is disabled in the source configuration",
+                name
+            )));
+        }
+        let mut wanted: Vec<&str> = self
+            .iter()
+            .map(|x| x.name())
+            .filter(|name| !self.is_disabled(name))
+            .filter(|name| opts.only.is_empty() || opts.only.iter().any(|x| x == name))
+            .filter(|name| !opts.skip.iter().any(|x| x == name))
+            .collect();
+        // Walk backwards so that dependencies of dependencies are found too.
+        for source in self.iter().collect::<Vec<_>>().into_iter().rev() {
+            if !wanted.contains(&source.name()) {
+                continue;
+            }
+            for dep in source.dependencies() {
+                if opts.skip.iter().any(|x| x == dep) {
+                    return Err(Error::Config(format!(
+                        "{} requires {}, which was skipped",
+                        source.name(),
+                        dep
+                    )));
+                }
+                if self.get(dep).is_none() {
+                    return Err(Error::Config(format!(
+                        "{} requires {}, which is not registered",
+                        source.name(),
+                        dep
+                    )));
+                }
+                if self.is_disabled(dep) {
+                    return Err(Error::Config(format!(
+                        "{} requires {}, which is disabled",
+                        source.name(),
+                        dep
+                    )));
+                }
+                if !wanted.contains(&dep) {
+                    wanted.push(dep);
+                }
+            }
+        }
+        Ok(self.iter().filter(|x| wanted.contains(&x.name())).collect())
+    }
+
+    /** Downloads the files for the sources selected in `opts` into `dir`, creating it
+    if necessary.  Files are stored exactly as downloaded, under the names given by
+    [`Source::files`], so that `dir` can later be given as [`LoadOptions::input_dir`].
+    Up to [`DownloadOptions::concurrency`] files are downloaded at once. */
+    pub async fn fetch(&self, opts: &LoadOptions, dir: &Path) -> Result<()> {
+        let mut files = Vec::new();
+        for source in self.select(opts)? {
+            files.extend(source.files());
+        }
+        let download = &opts.download();
+        stream::iter(files.into_iter().map(|sf| async move {
+            let path = dir.join(&sf.filename);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            download.progress.event(Event::DownloadStarted {
+                url: sf.url.clone(),
+                path: path.clone(),
+            });
+            let mut file = File::create(&path)?;
+            downloadto_if_changed(&sf.url, &Validators::default(), download, &mut file).await?;
+            file.flush()?;
+            Ok(())
+        }))
+        .buffer_unordered(opts.download.concurrency.max(1))
+        .try_collect()
         .await
-        .expect("Error building output sqlite");
-    dbschema::initdb(&mut outputpool.acquire().await.unwrap()).await;
-
-    // CSSE FIPS
-
-    let csse_fips_path = tmp_path.join("UID_ISO_FIPS_LookUp_Table.csv");
-    let mut csse_fips_file = stdoptions.open(&csse_fips_path).unwrap();
-    println!("Downloading {:#?}", csse_fips_path);
-    downloadto("https://github.com/CSSEGISandData/COVID-19/raw/master/csse_covid_19_data/UID_ISO_FIPS_LookUp_Table.csv",
-               &mut csse_fips_file).await;
-    csse_fips_file.seek(SeekFrom::Start(0)).unwrap();
-    println!("Processing {:#?}", csse_fips_path);
-    let mut rdr = parseutil::parse_init_file(csse_fips_file).expect("Couldn't init parser");
-    let fipshm = loclookuploader::load(&mut rdr, outputpool.begin().await.unwrap()).await;
-
-    // NY Times Counties
-
-    let path = tmp_path.join("nytcounties.csv");
-    let mut file = stdoptions.open(&path).unwrap();
-    println!("Downloading {:#?}", path);
-    downloadto(
-        "https://raw.githubusercontent.com/nytimes/covid-19-data/master/us-counties.csv",
-        &mut file,
-    )
-    .await;
-    file.seek(SeekFrom::Start(0)).unwrap();
-    println!("Processing {:#?}", path);
-    let mut rdr = parseutil::parse_init_file(file).expect("Couldn't init parser");
-    nytcountiesloader::load(&mut rdr, outputpool.begin().await.unwrap()).await;
-    //let mut conn = outputpool.acquire().await.unwrap();
-    // tests here
-    // drop(conn)
-
-    // Harvey County
-    //
-    // https://github.com/jgoerzen/covid19-data/raw/master/harveycodata.csv
-    let path = tmp_path.join("harveycodata.csv");
-    let mut file = stdoptions.open(&path).unwrap();
-    println!("Downloading {:#?}", path);
-    downloadto(
-        "https://github.com/jgoerzen/covid19-data/raw/master/harveycodata.csv",
-        &mut file,
-    )
-    .await;
-    file.seek(SeekFrom::Start(0)).unwrap();
-    println!("Processing {:#?}", path);
-    let mut rdr = parseutil::parse_init_file(file).expect("Couldn't init parser");
-    harveycodataloader::load(&mut rdr, outputpool.begin().await.unwrap()).await;
-    let mut conn = outputpool.acquire().await.unwrap();
-    assert_one_opti64(
-        Some(52),
-        "SELECT kdhe_neg_results FROM harveycodata WHERE date = '2020-07-19'",
-        &mut conn,
-    )
-    .await;
-    assert_one_opti64(
-        Some(1),
-        "SELECT kdhe_pos_results FROM harveycodata WHERE date = '2020-07-19'",
-        &mut conn,
-    )
-    .await;
-    assert_one_opti64(
-        None,
-        "SELECT harveyco_neg_results FROM harveycodata WHERE date = '2020-07-19'",
-        &mut conn,
-    )
-    .await;
-    assert_one_opti64(
-        None,
-        "SELECT harveyco_pos_results FROM harveycodata WHERE date = '2020-07-19'",
-        &mut conn,
-    )
-    .await;
-    assert_one_i64(
-        49,
-        "SELECT kdhe_neg_results FROM harveycodata WHERE date = '2020-08-15'",
-        &mut conn,
-    )
-    .await;
-    assert_one_i64(
-        21,
-        "SELECT kdhe_pos_results FROM harveycodata WHERE date = '2020-08-15'",
-        &mut conn,
-    )
-    .await;
-    assert_one_i64(
-        28,
-        "SELECT harveyco_neg_results FROM harveycodata WHERE date = '2020-08-15'",
-        &mut conn,
-    )
-    .await;
-    assert_one_i64(
-        4,
-        "SELECT harveyco_pos_results FROM harveycodata WHERE date = '2020-08-15'",
-        &mut conn,
-    )
-    .await;
-    assert_one_i64(
-        20,
-        "SELECT harveyco_recovered FROM harveycodata WHERE date = '2020-06-30'",
-        &mut conn,
-    )
-    .await;
-    assert_one_i64(
-        41,
-        "SELECT harveyco_confirmed FROM harveycodata WHERE date = '2020-06-30'",
-        &mut conn,
-    )
-    .await;
-    drop(conn);
+    }
 
-    // covidtracking
+    /** Obtains the data for the sources selected in `opts` and puts it in the
+    database at `opts.output`.  The schema for every registered source is
+    created, so that views across sources remain valid, but only the selected
+    ones are loaded.
 
-    let path = tmp_path.join("covidtracking.csv");
-    let mut file = stdoptions.open(&path).unwrap();
-    println!("Downloading {:#?}", path);
-    downloadto(
-        "https://covidtracking.com/api/v1/states/daily.csv",
-        &mut file,
-    )
-    .await;
-    file.seek(SeekFrom::Start(0)).unwrap();
-    println!("Processing {:#?}", path);
-    let mut rdr = parseutil::parse_init_file(file).expect("Couldn't init parser");
-    covidtrackingloader::load(&mut rdr, outputpool.begin().await.unwrap()).await;
-
-    // Our World in Data
-
-    let path = tmp_path.join("owid.csv");
-    let mut file = stdoptions.open(&path).unwrap();
-    println!("Downloading {:#?}", path);
-    downloadto(
-        "https://covid.ourworldindata.org/data/owid-covid-data.csv",
-        &mut file,
-    )
-    .await;
-    file.seek(SeekFrom::Start(0)).unwrap();
-    println!("Processing {:#?}", path);
-    let mut rdr = parseutil::parse_init_file(file).expect("Couldn't init parser");
-    owidloader::load(&mut rdr, outputpool.begin().await.unwrap()).await;
-
-    // rt.live
-
-    let path = tmp_path.join("rt.csv");
-    let mut file = stdoptions.open(&path).unwrap();
-    println!("Downloading {:#?}", path);
-    downloadto(
-        "https://d14wlfuexuxgcm.cloudfront.net/covid/rt.csv",
-        &mut file,
-    )
-    .await;
-    file.seek(SeekFrom::Start(0)).unwrap();
-    println!("Processing {:#?}", path);
-    let mut rdr = parseutil::parse_init_file(file).expect("Couldn't init parser");
-    rtliveloader::load(&mut rdr, outputpool.begin().await.unwrap()).await;
-
-    // Location map
-
-    let loc_path = tmp_path.join("locations-diff.tsv");
-    let mut loc_file = stdoptions.open(&loc_path).unwrap();
-    println!("Downloading {:#?}", loc_path);
-    downloadto("https://github.com/cipriancraciun/covid19-datasets/raw/5444d3e19eb2556a93e4d9ac4974762d9489fc1b/exports/combined/v1/locations-diff.tsv",
-               &mut loc_file).await;
-    loc_file.seek(SeekFrom::Start(0)).unwrap();
-    println!("Processing {:#?}", loc_path);
-    let mut rdr = combinedlocloader::parse_init_file(loc_file).expect("Couldn't init parser");
-    let mut lochm =
-        combinedlocloader::load(outputpool.begin().await.unwrap(), &fipshm, &mut rdr).await;
-
-    // Sqlite Combined
-    let sources = vec!["https://github.com/cipriancraciun/covid19-datasets/raw/master/exports/ecdc/v1/worldwide/values-sqlite.db.zst",
-                       "https://github.com/cipriancraciun/covid19-datasets/raw/master/exports/jhu/v1/daily/values-sqlite.db.zst",
-                       "https://github.com/cipriancraciun/covid19-datasets/raw/master/exports/jhu/v1/series/values-sqlite.db.zst",
-                       "https://github.com/cipriancraciun/covid19-datasets/raw/master/exports/nytimes/v1/us-counties/values-sqlite.db.zst",
-                       "https://github.com/cipriancraciun/covid19-datasets/raw/master/exports/nytimes/v1/us-states/values-sqlite.db.zst"];
-    let combined_path = tmp_path.join("values-sqlite.db");
-    for source in sources {
-        let mut combined_file = stdoptions.open(&combined_path).unwrap();
-        if source.ends_with(".zst") {
-            println!(
-                "Downloading and decompressing {:#?} to {:#?}",
-                source, combined_path
+    The files of all selected sources are fetched concurrently, subject to
+    [`DownloadOptions::concurrency`], and each source is loaded as soon as its
+    files and the sources it depends on are in.
+
+    A source's records that fail to parse are left out and listed in the
+    `load_rejects` table, with a warning; if there are more of them than
+    [`LoadOptions::max_rejects`] allows, the build fails.
+
+    If `opts.incremental` is set and `opts.output` is a database built earlier,
+    it is updated in place instead: each selected source is reloaded only if
+    the server reports that one of its files has changed since the last build,
+    or if a source it depends on was reloaded.
+
+    The database is built in `<output>.partial`, next to `opts.output`, and
+    renamed over it only once no check fails (see [`verify`](Registry::verify)),
+    so a failed build leaves any existing database as it was.  The partial
+    database is kept after a failure, with each source that was completed
+    listed in its `load_checkpoints` table.  If `opts.resume` is set, the
+    build continues there: completed sources are neither fetched nor loaded
+    again, and the rest are reloaded from scratch.
+
+    Once in place, the database's [`Manifest`] is written to
+    `<output>.manifest.json`.  Rows are stored in an order that doesn't
+    depend on the order in which the files arrived, so if
+    [`LoadOptions::timestamp`] is set, building from the same files again
+    gives the same bytes. */
+    pub async fn load(&self, opts: &LoadOptions) -> Result<()> {
+        let mut partial = opts.output.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        let resuming = opts.resume && partial.exists();
+        if resuming {
+            opts.progress
+                .step(format!("Resuming the build in {:#?}", partial));
+        } else {
+            if opts.resume {
+                opts.progress
+                    .step(format!("{:#?} does not exist; starting afresh", partial));
+            }
+            remove_database(&partial)?;
+            if opts.incremental && opts.output.exists() {
+                opts.progress
+                    .step(format!("Copying {:#?} to {:#?}", opts.output, partial));
+                std::fs::copy(&opts.output, &partial)?;
+            } else {
+                File::create(&partial)?;
+                if opts.output.exists() {
+                    std::fs::set_permissions(
+                        &partial,
+                        std::fs::metadata(&opts.output)?.permissions(),
+                    )?;
+                }
+            }
+        }
+        let build_opts = LoadOptions {
+            output: partial.clone(),
+            ..opts.clone()
+        };
+        if let Err(e) = self.build(&build_opts, resuming).await {
+            opts.progress.warning(
+                None,
+                format!(
+                    "the unfinished build is in {:#?}; use --resume to continue it",
+                    partial
+                ),
             );
-            let mut decoder = Decoder::new(combined_file).unwrap();
-            downloadto(source, &mut decoder).await;
-            decoder.flush().unwrap();
-            drop(decoder);
+            return Err(e);
+        }
+        if opts.keep_previous && opts.output.exists() {
+            let mut prev = opts.output.clone().into_os_string();
+            prev.push(".prev");
+            let prev = PathBuf::from(prev);
+            opts.progress
+                .step(format!("Keeping the previous database as {:#?}", prev));
+            if prev.exists() {
+                std::fs::remove_file(&prev)?;
+            }
+            // A hard link keeps the database in place until the rename below.
+            if std::fs::hard_link(&opts.output, &prev).is_err() {
+                std::fs::copy(&opts.output, &prev)?;
+            }
+        }
+        opts.progress
+            .step(format!("Moving {:#?} to {:#?}", partial, opts.output));
+        std::fs::rename(&partial, &opts.output)?;
+        let manifest = Manifest::path(&opts.output);
+        opts.progress
+            .step(format!("Writing the manifest to {:#?}", manifest));
+        Manifest::of(&opts.output).await?.write(&manifest)?;
+        opts.progress.event(Event::Finished {
+            output: opts.output.clone(),
+        });
+        Ok(())
+    }
+
+    /// Does the work of [`load`](Registry::load), writing directly to
+    /// `opts.output`, and continuing the build there if `resuming`.
+    async fn build(&self, opts: &LoadOptions, resuming: bool) -> Result<()> {
+        let sources = self.select(opts)?;
+
+        // OUTPUT DB INIT
+
+        opts.progress.step("Initializing output database");
+        let mut outputpool = open_pool(&opts.output, 1).await?;
+        let result = self
+            .load_sources(&sources, opts, resuming, &mut outputpool)
+            .await;
+        // Close the database even on failure, so that its write-ahead log is
+        // folded in before a resumed or fresh build opens the file again.
+        outputpool.close().await;
+        result?;
+
+        // Started getting errors at VACUUM about statements in progress.  Re-connect.
+        let outputpool = open_pool(&opts.output, 5).await?;
+        let mut conn = outputpool.acquire().await?;
+        opts.progress.step("Writing the data dictionary");
+        Dictionary::builtin().write(&mut conn).await?;
+        dbschema::execute_all(&mut conn, &dbschema::finish_schema(), &opts.progress).await?;
+        opts.progress.step("Vacuuming");
+        conn.execute("VACUUM").await?;
+        opts.progress.step("Optimizing");
+        conn.execute("PRAGMA OPTIMIZE").await?;
+        drop(conn);
+        outputpool.close().await;
+        opts.progress.step(" *** Checking data");
+        let report = self.verify(opts).await?;
+        for result in &report.results {
+            opts.progress.event(Event::Check(result.clone()));
+        }
+        opts.progress.event(Event::Checked {
+            passed: report.count(Outcome::Pass),
+            warnings: report.count(Outcome::Warn),
+            failed: report.count(Outcome::Fail),
+        });
+        if report.passed() {
+            Ok(())
         } else {
-            println!("Downloading {:#?} to {:#?}", source, combined_path);
-            downloadto(source, &mut combined_file).await;
-            drop(combined_file);
+            Err(Error::Check(format!(
+                "{} of {} checks failed",
+                report.count(Outcome::Fail),
+                report.results.len()
+            )))
         }
-        println!("Processing {:#}...", source);
-
-        let mut inputpool = SqlitePool::builder()
-            .max_size(5)
-            .build(format!("sqlite::{}", combined_path.to_str().unwrap()).as_ref())
-            .await
-            .expect("Error building");
-        combinedloader::load(&mut inputpool, &mut outputpool, &mut lochm, &fipshm).await;
-        std::fs::remove_file(&combined_path).unwrap();
-        inputpool.close().await;
     }
 
-    // Started getting errors at VACUUM about statements in progress.  Drop and re-connect.
-    outputpool.close().await;
-    let outputpool = SqlitePool::builder()
-        .max_size(5)
-        .build("sqlite::covid19.db")
-        .await
-        .expect("Error building output sqlite");
-    let mut conn = outputpool.acquire().await.unwrap();
-    println!("Vacuuming");
-    conn.execute("VACUUM").await.unwrap();
-    println!("Optimizing");
-    conn.execute("PRAGMA OPTIMIZE").await.unwrap();
-    println!(" *** All data loaded; row counts follow:");
-    for (tablename, minrows) in vec![
-        ("cdataset", 1250000),
-        ("covidtracking", 9000),
-        ("loc_lookup", 4000),
-        ("rtlive", 8000),
-        ("harveycodata", 80),
+    /** Creates the schema, unless `resuming`, and loads the `sources` into
+    `outputpool`, as part of [`build`](Registry::build). */
+    async fn load_sources(
+        &self,
+        sources: &[&dyn Source],
+        opts: &LoadOptions,
+        resuming: bool,
+        outputpool: &mut SqlitePool,
+    ) -> Result<()> {
+        let tmp_dir = tempdir()?;
+        let fetcher = Fetcher::new(opts.input_dir.clone(), opts.download(), tmp_dir.path());
+        let ctx = LoadContext {
+            progress: opts.progress.clone(),
+            subset: opts.subset.clone(),
+            dry_run: false,
+            encoding: Encoding::default(),
+            source: None,
+            locids_from: opts.locids_from.clone(),
+        };
+        let incremental = opts.incremental
+            && list_relations(&mut outputpool.acquire().await?)
+                .await?
+                .iter()
+                .any(|(name, _)| name == "covid19db_sources");
+        let completed: Vec<String> = if resuming {
+            sqlx::query_as::<_, (String,)>("SELECT source FROM load_checkpoints")
+                .fetch_all(&mut outputpool.acquire().await?)
+                .await?
+                .into_iter()
+                .map(|(x,)| x)
+                .collect()
+        } else {
+            let mut queries = Vec::new();
+            if !incremental {
+                queries.extend(dbschema::meta_schema());
+                for source in self.iter() {
+                    queries.extend(source.schema());
+                }
+            }
+            queries.extend(dbschema::checkpoint_schema());
+            dbschema::execute_all(&mut outputpool.acquire().await?, &queries, &opts.progress)
+                .await?;
+            Vec::new()
+        };
+        record_subset(opts, !(incremental || resuming), outputpool).await?;
+        if let Some(path) = &opts.locids_from {
+            record_locids_from(path, outputpool).await?;
+        }
+        let completed = |source: &dyn Source| completed.iter().any(|x| x == source.name());
+
+        // Fetch all the sources at once, and load each as soon as it and the
+        // sources it depends on are in.
+        let stored = if incremental {
+            stored_validators(outputpool).await?
+        } else {
+            HashMap::new()
+        };
+        let mut arrivals: FuturesUnordered<_> = sources
+            .iter()
+            .map(|&source| {
+                let fetcher = &fetcher;
+                let prev = stored.get(source.name());
+                let completed = completed(source);
+                async move {
+                    let files = match prev {
+                        _ if completed => None,
+                        Some(prev) if !resuming && !changed(source, fetcher, prev).await? => None,
+                        _ => Some(source.fetch(fetcher).await?),
+                    };
+                    Ok::<_, Error>((source.name(), files))
+                }
+            })
+            .collect();
+        let mut arrived: HashMap<&str, Option<Vec<PathBuf>>> = HashMap::new();
+        let mut done: Vec<&str> = Vec::new();
+        let mut reloaded: Vec<&str> = Vec::new();
+        while let Some(arrival) = arrivals.next().await {
+            let (name, files) = arrival?;
+            arrived.insert(name, files);
+            while let Some(source) = sources.iter().find(|x| {
+                arrived.contains_key(x.name())
+                    && x.dependencies().iter().all(|dep| done.contains(dep))
+            }) {
+                let files = arrived.remove(source.name()).unwrap();
+                done.push(source.name());
+                if completed(*source) {
+                    opts.progress.event(Event::SourceResumed {
+                        source: String::from(source.name()),
+                    });
+                    continue;
+                }
+                if incremental || resuming {
+                    let forced = source.dependencies().iter().any(|x| reloaded.contains(x));
+                    if files.is_none() && !forced {
+                        let mut conn = outputpool.acquire().await?;
+                        refresh_staleness(*source, opts.timestamp, &mut conn).await?;
+                        checkpoint(*source, opts.timestamp, &mut conn).await?;
+                        opts.progress.event(Event::SourceSkipped {
+                            source: String::from(source.name()),
+                        });
+                        continue;
+                    }
+                    let mut conn = outputpool.acquire().await?;
+                    dbschema::execute_all(&mut conn, &source.schema(), &opts.progress).await?;
+                    sqlx::query("DELETE FROM covid19db_meta WHERE field = ?")
+                        .bind(format!("headers:{}", source.name()))
+                        .execute(&mut conn)
+                        .await?;
+                }
+                let files = match files {
+                    Some(files) => files,
+                    None => source.fetch(&fetcher).await?,
+                };
+                opts.progress.event(Event::SourceStarted {
+                    source: String::from(source.name()),
+                });
+                let ctx = LoadContext {
+                    encoding: source.encoding(),
+                    ..ctx.clone()
+                };
+                let stats = source.load(&files, outputpool, &ctx).await?;
+                check_rejects(*source, &stats, opts)?;
+                warn_replacements(*source, &stats, &opts.progress);
+                save_provenance(*source, &fetcher, &stats, opts.timestamp, outputpool).await?;
+                let sum = |count: fn(&LoadStats) -> u64| stats.iter().map(count).sum();
+                opts.progress.event(Event::Committed {
+                    source: String::from(source.name()),
+                    parsed: sum(|x| x.parsed),
+                    inserted: sum(|x| x.inserted),
+                    skipped: sum(|x| x.skipped),
+                    rejected: sum(LoadStats::rejected),
+                });
+                reloaded.push(source.name());
+            }
+        }
+        Ok(())
+    }
+
+    /** Runs the checks for the sources selected in `opts` against the
+    database at `opts.output`: those in [`Checks::builtin`], warnings about
+    sources more than a week stale, those in `opts.checks` and for
+    [`LoadOptions::max_staleness`], and then each source's own
+    [`Source::check`].  Every check
+    is run, and the outcome of each is returned in the [`Report`].  If the
+    database holds a [`Subset`] of the data, only the built-in checks that
+    [hold for subsets](crate::validate::Kind::holds_for_subsets) are run. */
+    pub async fn verify(&self, opts: &LoadOptions) -> Result<Report> {
+        let sources = self.select(opts)?;
+        let names: Vec<&str> = sources.iter().map(|x| x.name()).collect();
+        let mut outputpool = open_pool(&opts.output, 1).await?;
+        let mut conn = outputpool.acquire().await?;
+        let mut checks = Checks::builtin();
+        checks.extend(staleness_checks(&sources, STALE_DAYS, Severity::Warn));
+        let has_meta = list_relations(&mut conn)
+            .await?
+            .iter()
+            .any(|(name, _)| name == "covid19db_meta");
+        if has_meta && !stored_subset(&mut conn).await?.is_empty() {
+            checks.checks.retain(|x| x.kind.holds_for_subsets());
+        }
+        if let Some(path) = &opts.checks {
+            checks.extend(Checks::from_file(path)?);
+        }
+        if let Some(days) = opts.max_staleness {
+            checks.extend(staleness_checks(&sources, days, Severity::Fail));
+        }
+        let mut report = checks.run(&mut conn, &names).await?;
+        drop(conn);
+        for source in sources {
+            match source.check(&mut outputpool).await {
+                Ok(()) => (),
+                Err(Error::Check(detail)) => report.results.push(CheckResult {
+                    check: format!("{} checks", source.name()),
+                    outcome: Outcome::Fail,
+                    detail,
+                }),
+                Err(e) => return Err(e),
+            }
+        }
+        outputpool.close().await;
+        Ok(report)
+    }
+}
+
+/// How many days stale a source may be before [`Registry::verify`] warns.
+const STALE_DAYS: u32 = 7;
+
+/** Checks, of the given `severity`, on whether the latest data of each of
+`sources` that have dates was more than `days` days old when its files were
+last fetched or found unchanged. */
+fn staleness_checks(sources: &[&dyn Source], days: u32, severity: Severity) -> Checks {
+    let checks = sources
+        .iter()
+        .filter(|x| x.coverage().is_some())
+        .map(|x| Check {
+            source: Some(String::from(x.name())),
+            table: String::from("source_coverage"),
+            filter: Some(format!(
+                "source = '{}' AND location IS NULL",
+                x.name().replace('\'', "''")
+            )),
+            severity,
+            kind: Kind::AtMost {
+                column: String::from("days_stale"),
+                max: f64::from(days),
+            },
+        })
+        .collect();
+    Checks { checks }
+}
+
+/** Removes the database at `path`, along with any journal SQLite left
+beside it, which would otherwise be applied to a new database there. */
+fn remove_database(path: &Path) -> Result<()> {
+    for suffix in &["", "-wal", "-shm", "-journal"] {
+        let mut file = path.as_os_str().to_owned();
+        file.push(suffix);
+        match std::fs::remove_file(&file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/** Records `opts.subset` in `covid19db_meta` of a `fresh` database, or
+checks that the database being updated or resumed holds the same subset. */
+async fn record_subset(opts: &LoadOptions, fresh: bool, pool: &mut SqlitePool) -> Result<()> {
+    let mut conn = pool.acquire().await?;
+    let mut wanted: Vec<(String, String)> = opts
+        .subset
+        .meta()
+        .into_iter()
+        .map(|(field, value)| (String::from(field), value))
+        .collect();
+    if fresh {
+        for (field, value) in wanted {
+            sqlx::query("INSERT INTO covid19db_meta VALUES (?, ?)")
+                .bind(field)
+                .bind(value)
+                .execute(&mut conn)
+                .await?;
+        }
+        return Ok(());
+    }
+    wanted.sort();
+    let stored = stored_subset(&mut conn).await?;
+    if stored != wanted {
+        let describe = |fields: &[(String, String)]| {
+            if fields.is_empty() {
+                String::from("all of it")
+            } else {
+                let fields: Vec<String> =
+                    fields.iter().map(|(f, v)| format!("{}={}", f, v)).collect();
+                fields.join(" ")
+            }
+        };
+        return Err(Error::Config(format!(
+            "The database holds a different subset of the data ({}) than was asked for ({}); \
+             build it without --incremental or --resume",
+            describe(&stored),
+            describe(&wanted)
+        )));
+    }
+    Ok(())
+}
+
+/** Records the SHA-256 of the database at `path`, whose location ids are
+kept, in `covid19db_meta` as `locids_from`. */
+async fn record_locids_from(path: &Path, pool: &mut SqlitePool) -> Result<()> {
+    let (sha256, _) = manifest::hash_file(path)?;
+    let mut transaction = pool.begin().await?;
+    sqlx::query("DELETE FROM covid19db_meta WHERE field = 'locids_from'")
+        .execute(&mut transaction)
+        .await?;
+    sqlx::query("INSERT INTO covid19db_meta VALUES ('locids_from', ?)")
+        .bind(sha256)
+        .execute(&mut transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Reads the `subset:` fields of `covid19db_meta`, in order.
+async fn stored_subset(
+    conn: &mut sqlx::pool::PoolConnection<sqlx::SqliteConnection>,
+) -> Result<Vec<(String, String)>> {
+    Ok(sqlx::query_as(
+        "SELECT field, value FROM covid19db_meta WHERE field LIKE 'subset:%' ORDER BY field",
+    )
+    .fetch_all(conn)
+    .await?)
+}
+
+/** Reads the validators recorded in `covid19db_sources`, keyed by source
+name and then by URL. */
+async fn stored_validators(
+    pool: &mut SqlitePool,
+) -> Result<HashMap<String, HashMap<String, Validators>>> {
+    let stored: Vec<(String, String, Option<String>, Option<String>)> =
+        sqlx::query_as("SELECT source, url, etag, last_modified FROM covid19db_sources")
+            .fetch_all(&mut pool.acquire().await?)
+            .await?;
+    let mut result: HashMap<String, HashMap<String, Validators>> = HashMap::new();
+    for (source, url, etag, last_modified) in stored {
+        result.entry(source).or_default().insert(
+            url,
+            Validators {
+                etag,
+                last_modified,
+            },
+        );
+    }
+    Ok(result)
+}
+
+/** Asks the server whether any of `source`'s files have changed since the
+`stored` validators were recorded. */
+async fn changed(
+    source: &dyn Source,
+    fetcher: &Fetcher,
+    stored: &HashMap<String, Validators>,
+) -> Result<bool> {
+    for sf in source.files() {
+        let prev = stored.get(&sf.url).cloned().unwrap_or_default();
+        if fetcher.obtain_if_changed(&sf, &prev).await?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/** Warns about the records of `source` that were rejected while loading,
+failing if there are more than `opts` allows. */
+fn check_rejects(source: &dyn Source, stats: &[LoadStats], opts: &LoadOptions) -> Result<()> {
+    let rejected: u64 = stats.iter().map(|x| x.rejected()).sum();
+    let allowed = opts.max_rejects(source.name());
+    if rejected == 0 {
+        return Ok(());
+    }
+    if rejected > allowed {
+        let files = source.files();
+        let (idx, first) = stats
+            .iter()
+            .enumerate()
+            .find_map(|(idx, x)| Some((idx, x.rejects.first()?)))
+            .expect("rejected records missing");
+        let filename = files.get(idx).map_or("", |x| x.filename.as_str());
+        return Err(Error::TooManyRejects {
+            source: String::from(source.name()),
+            rejected,
+            allowed,
+            first: format!(
+                "on line {} of {}: {} ({:?})",
+                first.line, filename, first.error, first.record
+            ),
+        });
+    }
+    opts.progress.warning(
+        Some(source.name()),
+        format!(
+            "{} records could not be parsed and were left out; see load_rejects",
+            rejected
+        ),
+    );
+    Ok(())
+}
+
+/** Warns if text loaded for `source` holds replacement characters, which
+usually mean that its [`Encoding`] is wrong or the file is damaged. */
+fn warn_replacements(source: &dyn Source, stats: &[LoadStats], progress: &Progress) {
+    let replacements: u64 = stats.iter().map(|x| x.replacements).sum();
+    if replacements > 0 {
+        progress.warning(
+            Some(source.name()),
+            format!(
+                "{} characters are not valid {} and were replaced with U+FFFD",
+                replacements,
+                source.encoding()
+            ),
+        );
+    }
+}
+
+/** Records where `source`'s files came from, and what `stats` says was
+loaded from them, in `covid19db_sources`, the records rejected in
+`load_rejects`, the days it has data for in `source_coverage`, and whom to
+credit for it in `covid19db_attribution`, and marks `source` as completed in
+`load_checkpoints`.  If `timestamp` is given, it is recorded as the time the
+files were fetched. */
+async fn save_provenance(
+    source: &dyn Source,
+    fetcher: &Fetcher,
+    stats: &[LoadStats],
+    timestamp: Option<DateTime<Utc>>,
+    pool: &mut SqlitePool,
+) -> Result<()> {
+    let mut transaction = pool.begin().await?;
+    for table in &[
+        "covid19db_sources",
+        "load_rejects",
+        "source_coverage",
+        "covid19db_attribution",
     ] {
-        let rows: (i64,) = sqlx::query_as(format!("SELECT COUNT(*) FROM {}", tablename).as_str())
-            .fetch_one(&mut conn)
-            .await
-            .unwrap();
-        println!("{}: {}", tablename, rows.0);
-        assert!(rows.0 >= minrows);
+        sqlx::query(&format!("DELETE FROM {} WHERE source = ?", table))
+            .bind(source.name())
+            .execute(&mut transaction)
+            .await?;
+    }
+    let mut fetched = timestamp;
+    for (idx, sf) in source.files().into_iter().enumerate() {
+        let info = fetcher
+            .info(&sf.url)
+            .expect("loaded file was never obtained");
+        if timestamp.is_none() {
+            fetched = fetched.max(Some(info.fetched));
+        }
+        let stats = stats.get(idx).cloned().unwrap_or_default();
+        sqlx::query(
+            "INSERT INTO covid19db_sources VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(source.name())
+        .bind(&sf.filename)
+        .bind(&sf.url)
+        .bind(sf.revision())
+        .bind(
+            timestamp
+                .unwrap_or(info.fetched)
+                .format("%Y-%m-%dT%H:%M:%SZ")
+                .to_string(),
+        )
+        .bind(info.sha256)
+        .bind(info.bytes as i64)
+        .bind(info.validators.etag)
+        .bind(info.validators.last_modified)
+        .bind(stats.parsed as i64)
+        .bind(stats.inserted as i64)
+        .bind(stats.skipped as i64)
+        .bind(stats.rejected() as i64)
+        .bind(stats.replacements as i64)
+        .bind(stats.min_date_julian)
+        .bind(stats.max_date_julian)
+        .execute(&mut transaction)
+        .await?;
+        let mut inserter = BulkInsert::new(
+            "INSERT INTO load_rejects VALUES (?, ?, ?, ?, ?)",
+            bind_reject,
+        );
+        for reject in stats.rejects {
+            inserter
+                .push(
+                    (source.name().to_string(), sf.filename.clone(), reject),
+                    &mut transaction,
+                )
+                .await?;
+        }
+        inserter.finish(&mut transaction).await?;
     }
-    drop(conn);
-    outputpool.close().await;
-    println!("Finished successfully!");
+    if let (Some(coverage), Some(fetched)) = (source.coverage(), fetched) {
+        let as_of = nd_to_day(&fetched.naive_utc().date());
+        for location in &["NULL", coverage.location.as_str()] {
+            let query = format!(
+                "INSERT INTO source_coverage
+                 SELECT ?, {location}, COUNT(*), {first}, {last}, MIN({date}), MAX({date}),
+                        ? - MAX({date})
+                 FROM {table} GROUP BY {location} HAVING COUNT(*) > 0 ORDER BY {location}",
+                location = location,
+                first = querystr_jd_to_datestr(&format!("MIN({})", coverage.date_column)),
+                last = querystr_jd_to_datestr(&format!("MAX({})", coverage.date_column)),
+                date = coverage.date_column,
+                table = coverage.table,
+            );
+            sqlx::query(&query)
+                .bind(source.name())
+                .bind(as_of)
+                .execute(&mut transaction)
+                .await?;
+        }
+    }
+    for credit in source.attribution() {
+        sqlx::query("INSERT INTO covid19db_attribution VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(source.name())
+            .bind(credit.provider)
+            .bind(credit.name)
+            .bind(credit.tables.join(","))
+            .bind(credit.attribution)
+            .bind(credit.license)
+            .bind(credit.license_url)
+            .bind(credit.url)
+            .bind(credit.citation)
+            .bind(credit.bibtex)
+            .execute(&mut transaction)
+            .await?;
+    }
+    checkpoint(source, timestamp, &mut transaction).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/** Brings `days_stale` in `source_coverage` up to date for `source`, whose
+files were found unchanged, measuring it at `timestamp` if given, or else
+now. */
+async fn refresh_staleness<E: Executor<Database = sqlx::Sqlite>>(
+    source: &dyn Source,
+    timestamp: Option<DateTime<Utc>>,
+    db: &mut E,
+) -> Result<()> {
+    let as_of = nd_to_day(&timestamp.unwrap_or_else(Utc::now).naive_utc().date());
+    sqlx::query("UPDATE source_coverage SET days_stale = ? - last_date_julian WHERE source = ?")
+        .bind(as_of)
+        .bind(source.name())
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Records in `load_checkpoints` that the build has finished with `source`,
+/// at `timestamp` if given, or else now.
+async fn checkpoint<E: Executor<Database = sqlx::Sqlite>>(
+    source: &dyn Source,
+    timestamp: Option<DateTime<Utc>>,
+    db: &mut E,
+) -> Result<()> {
+    sqlx::query("INSERT OR REPLACE INTO load_checkpoints VALUES (?, ?)")
+        .bind(source.name())
+        .bind(
+            timestamp
+                .unwrap_or_else(Utc::now)
+                .format("%Y-%m-%dT%H:%M:%SZ")
+                .to_string(),
+        )
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+fn bind_reject<'q>(
+    (source, filename, reject): (String, String, Reject),
+    query: sqlx::Query<'q, sqlx::Sqlite>,
+) -> sqlx::Query<'q, sqlx::Sqlite> {
+    query
+        .bind(source)
+        .bind(filename)
+        .bind(reject.line as i64)
+        .bind(reject.record)
+        .bind(reject.error)
+}
+
+/** Downloads the data and puts it in `covid19.db` in the current working directory. */
+pub async fn load() -> Result<()> {
+    load_with(&LoadOptions::default()).await
+}
+
+/** Returns the registry of built-in sources configured by
+[`SourcesConfig::builtin`] and `opts.sources`.  See [`Registry::from_config`]. */
+pub fn registry(opts: &LoadOptions) -> Result<Registry> {
+    let mut config = SourcesConfig::builtin();
+    if let Some(path) = &opts.sources {
+        config.extend(SourcesConfig::from_file(path)?);
+    }
+    Registry::from_config(&config)
+}
+
+/** Obtains the data for the built-in sources selected in `opts` and puts it in the
+database at `opts.output`.  See [`Registry::load`]. */
+pub async fn load_with(opts: &LoadOptions) -> Result<()> {
+    registry(opts)?.load(opts).await
+}
+
+/** Runs the checks for the built-in sources selected in `opts`.  See
+[`Registry::verify`]. */
+pub async fn verify(opts: &LoadOptions) -> Result<Report> {
+    registry(opts)?.verify(opts).await
+}
+
+/** Parses the data for the built-in sources selected in `opts` without writing
+the database.  See [`Registry::dry_run`]. */
+pub async fn dry_run(opts: &LoadOptions) -> Result<DryRunReport> {
+    registry(opts)?.dry_run(opts).await
+}
+
+/** Downloads the files for the built-in sources selected in `opts` into `dir`.
+See [`Registry::fetch`]. */
+pub async fn fetch(opts: &LoadOptions, dir: &Path) -> Result<()> {
+    registry(opts)?.fetch(opts, dir).await
 }
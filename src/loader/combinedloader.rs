@@ -18,16 +18,24 @@ Copyright (c) 2020 John Goerzen
 
 use crate::dateutil::*;
 use crate::dbschema::*;
-use crate::loader::combinedlocloader::LocRec;
-pub use crate::loader::parseutil::*;
+use crate::dbutil::*;
+use crate::loader::combinedlocloader::{self, LocIds, LocRec, Location};
+use crate::loader::config::builtin_files;
+use crate::loader::loclookuploader::{fips_populations, JhuUids};
+use crate::loader::source::{Coverage, LoadContext, LoadStats, Source, SourceFile};
+use crate::loader::subset::{Place, Subset};
+use crate::progress::Event;
+use crate::{Error, Result};
+use async_trait::async_trait;
 use chrono::NaiveDate;
 use sqlx::prelude::*;
+use sqlx::sqlite::SqlitePool;
 use sqlx::Transaction;
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::io;
-use std::io::Write;
+use std::fs::File;
 use std::mem::drop;
+use std::path::PathBuf;
 
 /// Calculates the absolute rate per 100k population in case it's not there
 fn set_per_pop(
@@ -38,26 +46,25 @@ fn set_per_pop(
 ) -> Option<f64> {
     match row.try_get::<Option<f64>, &str>(format!("{}_pop100k_{}", colprefix, colname).as_str()) {
         Ok(Some(x)) => Some(x),
-        _ => match population {
-            None => None,
-            Some(pop) => Some(
-                (row.try_get::<i64, &str>(format!("{}_{}", colprefix, colname).as_str())
-                    .unwrap_or(0) as f64)
-                    * 100000.0
-                    / (pop as f64),
-            ),
-        },
+        _ => population.map(|pop| {
+            (row.try_get::<i64, &str>(format!("{}_{}", colprefix, colname).as_str())
+                .unwrap_or(0) as f64)
+                * 100000.0
+                / (pop as f64)
+        }),
     }
 }
 
-/// Fill up rows in which no changes occurred.
+/// Fill up rows in which no changes occurred, within the dates of `subset`.
 async fn fillup(
-    mut transaction: &mut Transaction<sqlx::pool::PoolConnection<sqlx::SqliteConnection>>,
+    transaction: &mut Transaction<sqlx::pool::PoolConnection<sqlx::SqliteConnection>>,
+    inserter: &mut BulkInsert<CDataSet>,
     lastrow: &Option<CDataSet>,
     nextrow: Option<&CDataSet>,
     prevdate: &NaiveDate,
     maxdate: &NaiveDate,
-) {
+    subset: &Subset,
+) -> Result<()> {
     // If there was no last row, nothing to do.
     if let Some(lastrow) = lastrow {
         let targetmaxdate = if let Some(nextrow) = nextrow {
@@ -79,7 +86,6 @@ async fn fillup(
         let maxjulian = nd_to_day(targetmaxdate);
         let mut add_days: i32 = 1;
         while thisjulian <= maxjulian {
-            let query = sqlx::query(CDataSet::insert_str());
             let mut cds = lastrow.clone().dup_day();
             cds.set_date(thisjulian);
             cds.day_index_0 += add_days;
@@ -99,149 +105,224 @@ async fn fillup(
                      maxjulian, cds.date_julian);
             println!("{:?}", cds);
             */
-            cds.bind_query(query)
-                .execute(&mut transaction)
-                .await
-                .unwrap();
+            if subset.keep_julian(cds.date_julian) {
+                inserter.push(cds, transaction).await?;
+            }
             thisjulian += 1;
             add_days += 1;
         }
     }
+    Ok(())
+}
+
+/// Builds an [`Error::RowParse`] for row `rownum` of an input database of `source`.
+fn bad_row<E: std::fmt::Display>(source: &str, rownum: i64, err: E) -> Error {
+    Error::RowParse {
+        source: String::from(source),
+        line: rownum as u64,
+        message: err.to_string(),
+        record: String::new(),
+    }
+}
+
+/// The location of a row of one of the combined Sqlite databases.
+fn dataset_location(row: &sqlx::sqlite::SqliteRow) -> Result<Location> {
+    let text = |column: &str| -> String {
+        row.try_get::<Option<String>, &str>(column)
+            .unwrap_or(None)
+            .unwrap_or_default()
+    };
+    Ok(Location {
+        key: row.try_get("location_key")?,
+        fips: None,
+        country_code: text("country_code"),
+        province: text("province"),
+        administrative: text("administrative"),
+    })
+}
+
+/// The locations in one of the combined Sqlite databases.
+async fn dataset_locations(inputpool: &mut sqlx::SqlitePool) -> Result<Vec<Location>> {
+    let mut iconn = inputpool.acquire().await?;
+    let mut cursor = sqlx::query("SELECT * FROM dataset GROUP BY location_key").fetch(&mut iconn);
+    let mut locations = Vec::new();
+    while let Some(row) = cursor.next().await? {
+        locations.push(dataset_location(&row)?);
+    }
+    Ok(locations)
 }
 
-/** Parse the CSV, loading it into the database, and returning a hashmap of fips to population.
- * Will panic on parse error.  */
-pub async fn load(
+/** Load one of the combined Sqlite databases into `cdataset_raw`, adding any
+locations not already in `lochm` to `cdataset_loc`, with identifiers from
+`locids`, and return what was loaded.  Rows outside `ctx.subset` are skipped.  */
+async fn load_dataset(
     inputpool: &mut sqlx::SqlitePool,
     outputpool: &mut sqlx::SqlitePool,
     lochm: &mut HashMap<String, LocRec>,
+    locids: &mut LocIds,
     fipshm: &HashMap<u32, u64>,
-) {
+    ctx: &LoadContext,
+) -> Result<LoadStats> {
+    let progress = &ctx.progress;
+    let name = ctx.source_name("cdataset");
     // Speed things up a bit.
-    let mut conn = outputpool.acquire().await.unwrap();
-    conn.execute("PRAGMA auto_vacuum = 0").await.unwrap();
-    conn.execute("PRAGMA synchronous = 0").await.unwrap();
+    let mut conn = outputpool.acquire().await?;
+    conn.execute("PRAGMA auto_vacuum = 0").await?;
+    conn.execute("PRAGMA synchronous = 0").await?;
     drop(conn);
 
-    let mut transaction = outputpool.begin().await.unwrap();
+    let mut transaction = outputpool.begin().await?;
 
-    let mut iconn = inputpool.acquire().await.unwrap();
+    let mut iconn = inputpool.acquire().await?;
     let totalrecs: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM dataset")
         .fetch_one(&mut iconn)
-        .await
-        .unwrap();
+        .await?;
     let mut processedrecs: i64 = 0;
 
     let maxdate_str: (String,) = sqlx::query_as("SELECT MAX(date) FROM dataset")
         .fetch_one(&mut iconn)
-        .await
-        .unwrap();
-    let maxdate = NaiveDate::parse_from_str(maxdate_str.0.as_str(), "%Y-%m-%d").unwrap();
+        .await?;
+    let maxdate = NaiveDate::parse_from_str(maxdate_str.0.as_str(), "%Y-%m-%d")
+        .map_err(|e| bad_row(name, 0, e))?;
 
     let mut cursor =
         sqlx::query("SELECT * from dataset ORDER BY dataset, location_key, date").fetch(&mut iconn);
 
     let mut lastrow = None;
     let mut locrecsadded: u64 = 0;
+    let mut inserter =
+        BulkInsert::new(CDataSet::insert_str(), CDataSet::bind_query).discard(ctx.dry_run);
+    let mut stats = LoadStats::default();
 
-    while let Some(row) = cursor.next().await.unwrap() {
-        let locrec = match lochm.get(&row.get::<String, &str>("location_key")) {
+    while let Some(row) = cursor.next().await? {
+        let dataset: String = row.try_get("dataset")?;
+        let country: Option<String> = row.try_get("country_code")?;
+        let province: Option<String> = row.try_get("province").unwrap_or(None);
+        let location_key: String = row.try_get("location_key")?;
+        let place = Place {
+            country: country.as_deref(),
+            state: province.as_deref(),
+            fips: lochm.get(&location_key).and_then(|x| x.fips).map(i64::from),
+        };
+        if !ctx.subset.keep_dataset(&dataset) || !ctx.subset.keep_place(&place) {
+            processedrecs += 1;
+            stats.skipped += 1;
+            continue;
+        }
+        let locrec = match lochm.get(&location_key) {
             Some(x) => x.clone(),
             None => {
                 locrecsadded += 1;
-                let maxid: (i64,) = sqlx::query_as("SELECT MAX(locid) FROM cdataset_loc")
-                    .fetch_one(&mut transaction)
-                    .await
-                    .unwrap();
-                let query = sqlx::query(
-                    "INSERT INTO cdataset_loc VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-                );
-                let locid = maxid.0 + 1;
+                let locid = locids.assign(dataset_location(&row)?);
+                let text = |column: &str| -> String {
+                    row.try_get::<Option<String>, &str>(column)
+                        .unwrap_or(None)
+                        .unwrap_or_default()
+                };
                 let emptystr = String::from("");
-                query
-                    .bind(locid)
-                    .bind(
-                        row.get::<Option<String>, &str>("location_type")
-                            .unwrap_or(emptystr.clone()),
-                    )
-                    .bind(
-                        row.get::<Option<String>, &str>("location_label")
-                            .unwrap_or(emptystr.clone()),
-                    )
-                    .bind(
-                        row.get::<Option<String>, &str>("country_code")
-                            .unwrap_or(emptystr.clone()),
-                    )
-                    .bind(
-                        row.get::<Option<String>, &str>("country")
-                            .unwrap_or(emptystr.clone()),
-                    )
-                    .bind(
-                        row.try_get::<Option<String>, &str>("province")
-                            .unwrap_or(None)
-                            .unwrap_or(emptystr.clone()),
-                    )
-                    .bind(
-                        row.try_get::<Option<String>, &str>("administrative")
-                            .unwrap_or(None)
-                            .unwrap_or(emptystr.clone()),
-                    )
-                    .bind(
-                        row.get::<Option<String>, &str>("region")
-                            .unwrap_or(emptystr.clone()),
-                    )
-                    .bind("")
-                    .bind("")
-                    .bind(None::<Option<i64>>)
-                    .execute(&mut transaction)
-                    .await
-                    .unwrap();
+                sqlx::query(
+                    "INSERT INTO cdataset_loc VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                )
+                .bind(locid)
+                .bind(&location_key)
+                .bind(
+                    row.try_get::<Option<String>, &str>("location_type")?
+                        .unwrap_or(emptystr.clone()),
+                )
+                .bind(
+                    row.try_get::<Option<String>, &str>("location_label")?
+                        .unwrap_or(emptystr.clone()),
+                )
+                .bind(
+                    row.try_get::<Option<String>, &str>("country_code")?
+                        .unwrap_or(emptystr.clone()),
+                )
+                .bind(
+                    row.try_get::<Option<String>, &str>("country")?
+                        .unwrap_or(emptystr.clone()),
+                )
+                .bind(text("province"))
+                .bind(text("administrative"))
+                .bind(
+                    row.try_get::<Option<String>, &str>("region")?
+                        .unwrap_or(emptystr.clone()),
+                )
+                .bind("")
+                .bind("")
+                .bind(None::<Option<i64>>)
+                .execute(&mut transaction)
+                .await?;
                 let locrec = LocRec {
                     fips: None,
                     population: None,
-                    locid: u32::try_from(locid).unwrap(),
+                    locid,
                 };
-                lochm.insert(row.get::<String, &str>("location_key"), locrec.clone());
+                lochm.insert(location_key, locrec.clone());
                 locrec
             }
         };
 
-        let nd = NaiveDate::from_ymd(
-            row.get("date_year"),
-            u32::try_from(row.get::<i32, &str>("date_month")).unwrap(),
-            u32::try_from(row.get::<i32, &str>("date_day")).unwrap(),
+        let (year, month, day): (i32, i32, i32) = (
+            row.try_get("date_year")?,
+            row.try_get("date_month")?,
+            row.try_get("date_day")?,
         );
+        let nd = match NaiveDate::from_ymd_opt(year, month as u32, day as u32) {
+            Some(nd) => nd,
+            None => {
+                stats.reject(Error::RowParse {
+                    source: String::from(name),
+                    line: processedrecs as u64 + stats.rejected() + 1,
+                    message: format!("invalid date {}-{}-{}", year, month, day),
+                    record: format!(
+                        "{},{},{}-{}-{}",
+                        row.try_get::<String, &str>("dataset")?,
+                        row.try_get::<String, &str>("location_key")?,
+                        year,
+                        month,
+                        day
+                    ),
+                })?;
+                continue;
+            }
+        };
         let julian = nd_to_day(&nd);
+        let kept = ctx.subset.keep_julian(julian);
+        if kept {
+            // Rows filled in later all fall within the dates of the input rows.
+            stats.date(julian);
+        } else {
+            stats.skipped += 1;
+        }
 
-        let population: Option<i64> = match row.get("factbook_population") {
+        let population: Option<i64> = match row.try_get("factbook_population")? {
             Some(pop) => Some(pop),
-            None => locrec.fips.and_then(|x| {
-                fipshm
-                    .get(&x)
-                    .and_then(|y| Some(i64::try_from(*y).unwrap()))
-            }),
+            None => locrec
+                .fips
+                .and_then(|x| fipshm.get(&x).and_then(|y| i64::try_from(*y).ok())),
         };
 
-        let query = sqlx::query(CDataSet::insert_str());
         let cds = CDataSet {
-            dataset: row.get("dataset"),
-            locid: i64::from(locrec.locid),
-            location_lat: row.get("location_lat"),
-            location_long: row.get("location_long"),
+            dataset: row.try_get("dataset")?,
+            locid: locrec.locid,
+            location_lat: row.try_get("location_lat")?,
+            location_long: row.try_get("location_long")?,
             date_julian: julian,
-            day_index_0: row.get("day_index_0"),
-            day_index_1: row.get("day_index_1"),
-            day_index_10: row.get("day_index_10"),
-            day_index_100: row.get("day_index_100"),
-            day_index_1k: row.get("day_index_1k"),
-            day_index_10k: row.get("day_index_10k"),
-            day_index_peak: row.get("day_index_peak"),
-            day_index_peak_confirmed: row.get("day_index_peak_confirmed"),
-            day_index_peak_deaths: row.get("day_index_peak_deaths"),
+            day_index_0: row.try_get("day_index_0")?,
+            day_index_1: row.try_get("day_index_1")?,
+            day_index_10: row.try_get("day_index_10")?,
+            day_index_100: row.try_get("day_index_100")?,
+            day_index_1k: row.try_get("day_index_1k")?,
+            day_index_10k: row.try_get("day_index_10k")?,
+            day_index_peak: row.try_get("day_index_peak")?,
+            day_index_peak_confirmed: row.try_get("day_index_peak_confirmed")?,
+            day_index_peak_deaths: row.try_get("day_index_peak_deaths")?,
             absolute_confirmed: row
                 .get::<Option<i64>, &str>("absolute_confirmed")
                 .unwrap_or(0),
-            absolute_deaths: row.get::<Option<i64>, &str>("absolute_deaths").unwrap_or(0),
+            absolute_deaths: row
+                .try_get::<Option<i64>, &str>("absolute_deaths")?
+                .unwrap_or(0),
             absolute_recovered: row
                 .try_get::<Option<i64>, &str>("absolute_recovered")
                 .unwrap_or(None)
@@ -253,58 +334,173 @@ pub async fn load(
             absolute_pop100k_deaths: set_per_pop(&row, "absolute", "deaths", population),
             absolute_pop100k_recovered: set_per_pop(&row, "absolute", "recovered", population),
             absolute_pop100k_infected: set_per_pop(&row, "absolute", "infected", population),
-            relative_deaths: row.get("relative_deaths"),
+            relative_deaths: row.try_get("relative_deaths")?,
             relative_recovered: row.try_get("relative_recovered").unwrap_or(None),
-            relative_infected: row.get("relative_infected"),
-            delta_confirmed: row.get::<Option<i64>, &str>("delta_confirmed").unwrap_or(0),
-            delta_deaths: row.get::<Option<i64>, &str>("delta_deaths").unwrap_or(0),
+            relative_infected: row.try_get("relative_infected")?,
+            delta_confirmed: row
+                .try_get::<Option<i64>, &str>("delta_confirmed")?
+                .unwrap_or(0),
+            delta_deaths: row
+                .try_get::<Option<i64>, &str>("delta_deaths")?
+                .unwrap_or(0),
             delta_recovered: row
                 .try_get::<Option<i64>, &str>("delta_recovered")
                 .unwrap_or(None)
                 .unwrap_or(0),
-            delta_infected: row.get::<Option<i64>, &str>("delta_infected").unwrap_or(0),
-            delta_pct_confirmed: row.get("delta_pct_confirmed"),
-            delta_pct_deaths: row.get("delta_pct_deaths"),
+            delta_infected: row
+                .try_get::<Option<i64>, &str>("delta_infected")?
+                .unwrap_or(0),
+            delta_pct_confirmed: row.try_get("delta_pct_confirmed")?,
+            delta_pct_deaths: row.try_get("delta_pct_deaths")?,
             delta_pct_recovered: row.try_get("delta_pct_recovered").unwrap_or(None),
-            delta_pct_infected: row.get("delta_pct_infected"),
+            delta_pct_infected: row.try_get("delta_pct_infected")?,
             delta_pop100k_confirmed: set_per_pop(&row, "delta", "confirmed", population),
             delta_pop100k_deaths: set_per_pop(&row, "delta", "deaths", population),
             delta_pop100k_recovered: set_per_pop(&row, "delta", "recovered", population),
             delta_pop100k_infected: set_per_pop(&row, "delta", "infected", population),
-            peak_pct_confirmed: row.get("peak_pct_confirmed"),
-            peak_pct_deaths: row.get("peak_pct_deaths"),
+            peak_pct_confirmed: row.try_get("peak_pct_confirmed")?,
+            peak_pct_deaths: row.try_get("peak_pct_deaths")?,
             peak_pct_recovered: row.try_get("peak_pct_recovered").unwrap_or(None),
-            peak_pct_infected: row.get("peak_pct_infected"),
-            factbook_area: row.get("factbook_area"),
+            peak_pct_infected: row.try_get("peak_pct_infected")?,
+            factbook_area: row.try_get("factbook_area")?,
             factbook_population: population,
-            factbook_death_rate: row.get("factbook_death_rate"),
-            factbook_median_age: row.get("factbook_median_age"),
+            factbook_death_rate: row.try_get("factbook_death_rate")?,
+            factbook_median_age: row.try_get("factbook_median_age")?,
         };
 
-        fillup(&mut transaction, &lastrow, Some(&cds), &nd.pred(), &maxdate).await;
+        fillup(
+            &mut transaction,
+            &mut inserter,
+            &lastrow,
+            Some(&cds),
+            &nd.pred(),
+            &maxdate,
+            &ctx.subset,
+        )
+        .await?;
         // println!("Adding {}", cds.date_julian);
         // println!("{:?}", cds);
-        cds.clone()
-            .bind_query(query)
-            .execute(&mut transaction)
-            .await
-            .unwrap();
+        if kept {
+            inserter.push(cds.clone(), &mut transaction).await?;
+        }
         lastrow = Some(cds);
         processedrecs += 1;
         if processedrecs % 10000 == 0 {
-            print!(
-                "Processed {} of {} input records\r",
-                processedrecs, totalrecs.0
-            );
-            io::stdout().flush().unwrap();
+            progress.event(Event::Rows {
+                source: String::from(name),
+                processed: processedrecs as u64,
+                total: Some(totalrecs.0 as u64),
+            });
+        }
+    }
+    fillup(
+        &mut transaction,
+        &mut inserter,
+        &lastrow,
+        None,
+        &maxdate,
+        &maxdate,
+        &ctx.subset,
+    )
+    .await?;
+    stats.parsed = processedrecs as u64 - stats.skipped;
+    stats.inserted = inserter.finish(&mut transaction).await?;
+    progress.event(Event::Rows {
+        source: String::from(name),
+        processed: processedrecs as u64,
+        total: Some(totalrecs.0 as u64),
+    });
+    if locrecsadded > 0 {
+        progress.step(format!(
+            "{}: {} locations not in the location map were added",
+            name, locrecsadded
+        ));
+    }
+    transaction.commit().await?;
+    Ok(stats)
+}
+
+/** The COVID-19 derived datasets, in `cdataset`.  The first file is the
+location map; the rest are the Sqlite databases for each dataset. */
+pub struct CDataSetSource;
+
+#[async_trait]
+impl Source for CDataSetSource {
+    fn name(&self) -> &str {
+        "cdataset"
+    }
+
+    fn dependencies(&self) -> Vec<&str> {
+        vec!["loc_lookup"]
+    }
+
+    fn files(&self) -> Vec<SourceFile> {
+        builtin_files(self.name())
+    }
+
+    /// Named as in the `dataset` column: `jhu/v1/daily/values-sqlite.db.zst`
+    /// holds `jhu/daily`.
+    fn datasets(&self, files: &[SourceFile]) -> Vec<String> {
+        files
+            .iter()
+            .skip(1)
+            .filter_map(|x| match x.filename.split('/').collect::<Vec<_>>()[..] {
+                [provider, _version, name, _] => Some(format!("{}/{}", provider, name)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn schema(&self) -> Vec<String> {
+        cdataset_schema()
+    }
+
+    fn coverage(&self) -> Option<Coverage> {
+        Some(Coverage::new(
+            "cdataset_raw JOIN cdataset_loc USING (locid)",
+            "dataset || ': ' || location_key",
+        ))
+    }
+
+    async fn load(
+        &self,
+        files: &[PathBuf],
+        pool: &mut SqlitePool,
+        ctx: &LoadContext,
+    ) -> Result<Vec<LoadStats>> {
+        let fipshm = fips_populations(pool).await?;
+        let mut locids = LocIds::new(JhuUids::load(pool).await?);
+        if let Some(path) = &ctx.locids_from {
+            locids.keep_from(path).await?;
+        }
+        // Every location gets its id before any is loaded; see LocIds.
+        let mut others = Vec::new();
+        for path in &files[1..] {
+            let mut inputpool = open_pool(path, 1).await?;
+            let result = dataset_locations(&mut inputpool).await;
+            inputpool.close().await;
+            others.extend(result?);
+        }
+        let mut rdr = combinedlocloader::parse_init_file(File::open(&files[0])?)?;
+        let (mut lochm, stats) = combinedlocloader::load_locations(
+            pool.begin().await?,
+            &fipshm,
+            &mut locids,
+            others,
+            &mut rdr,
+            ctx,
+        )
+        .await?;
+        let mut allstats = vec![stats];
+
+        for path in &files[1..] {
+            ctx.progress.step(format!("Processing {:#?}...", path));
+            let mut inputpool = open_pool(path, 5).await?;
+            let result =
+                load_dataset(&mut inputpool, pool, &mut lochm, &mut locids, &fipshm, ctx).await;
+            inputpool.close().await;
+            allstats.push(result?);
         }
+        Ok(allstats)
     }
-    fillup(&mut transaction, &lastrow, None, &maxdate, &maxdate).await;
-    println!(
-        "Processed {} of {} input records ({} location records also added)",
-        processedrecs, totalrecs.0, locrecsadded
-    );
-    io::stdout().flush().unwrap();
-    println!("Committing...");
-    transaction.commit().await.unwrap();
 }
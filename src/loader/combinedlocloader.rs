@@ -16,29 +16,82 @@ Copyright (c) 2019-2020 John Goerzen
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use crate::dbutil::{column_names, open_pool, BulkInsert};
+use crate::loader::loclookuploader::JhuUids;
 pub use crate::loader::parseutil::*;
+use crate::loader::source::{LoadContext, LoadStats};
+use crate::loader::subset::Place;
+use crate::{Error, Result};
 use csv;
 use serde::Deserialize;
-use sqlx::Transaction;
-use std::collections::HashMap;
-use std::error::Error;
+use sha2::{Digest, Sha256};
+use sqlx::prelude::*;
+use sqlx::{Query, Sqlite, Transaction};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::path::Path;
+
+const COLUMNS: &[&str] = &[
+    "key",
+    "key_original",
+    "type",
+    "label",
+    "country_code",
+    "country_different",
+    "country_normalized",
+    "country_original",
+    "province_different",
+    "province_normalized",
+    "province_original",
+    "administrative_different",
+    "administrative_normalized",
+    "administrative_original",
+    "region",
+    "subregion",
+    "us_state_code",
+    "us_state_name",
+    "us_county_fips",
+    "us_county_name",
+    "dataset",
+];
+
+const REQUIRED_COLUMNS: &[&str] = &[
+    "key",
+    "type",
+    "label",
+    "country_code",
+    "country_normalized",
+    "province_normalized",
+    "administrative_normalized",
+    "region",
+    "subregion",
+    "us_state_code",
+    "us_state_name",
+];
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct LocRecord {
     pub key: String,
+    #[serde(default)]
     pub key_original: String,
+    #[serde(rename = "type")]
     pub xtype: String,
     pub label: String,
     pub country_code: String,
+    #[serde(default)]
     pub country_different: String,
     pub country_normalized: String,
+    #[serde(default)]
     pub country_original: String,
+    #[serde(default)]
     pub province_different: String,
     pub province_normalized: String,
+    #[serde(default)]
     pub province_original: String,
+    #[serde(default)]
     pub administrative_different: String,
     pub administrative_normalized: String,
+    #[serde(default)]
     pub administrative_original: String,
     pub region: String,
     pub subregion: String,
@@ -51,16 +104,196 @@ pub struct LocRecord {
 pub struct LocRec {
     pub fips: Option<u32>,
     pub population: Option<u64>,
-    pub locid: u32,
+    pub locid: i64,
+}
+
+/// The first `locid` that is derived from a location key rather than a JHU UID.
+pub const HASHED_LOCIDS: i64 = 1 << 32;
+
+/** A location to be given a `locid`: the county with FIPS code `fips`, if
+given, or else the place named by `country_code`, `province` and
+`administrative`, which are empty for a whole country or province. */
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    /// The upstream `location_key`.
+    pub key: String,
+    pub fips: Option<u32>,
+    pub country_code: String,
+    pub province: String,
+    pub administrative: String,
+}
+
+/** Gives each location a `locid` that stays the same from build to build, and
+is recorded with its key in `cdataset_loc`.
+
+A key that an earlier build gave an id keeps it; see
+[`keep_from`](LocIds::keep_from).  Otherwise, a location that is in
+`loc_lookup` gets its Johns Hopkins UID, which is below [`HASHED_LOCIDS`];
+any other gets [`HASHED_LOCIDS`] plus the first 48 bits of the SHA-256 of its
+key.  Where several keys name the same place, the UID goes to one whose FIPS
+code matches it, if any, and otherwise, or among those, to the shortest key,
+or of keys of the same length to the first in alphabetical order; the others
+are hashed.  Should a hash already be taken, the key is hashed
+again with a counter appended.  So that neither depends on the order in
+which keys are seen, all the keys of a build are given to
+[`assign_all`](LocIds::assign_all) at once. */
+#[derive(Clone, Debug, Default)]
+pub struct LocIds {
+    uids: JhuUids,
+    ids: HashMap<String, i64>,
+    used: HashSet<i64>,
+}
+
+impl LocIds {
+    pub fn new(uids: JhuUids) -> Self {
+        LocIds {
+            uids,
+            ids: HashMap::new(),
+            used: HashSet::new(),
+        }
+    }
+
+    /// Gives `key` the id `locid`, as an earlier build did.
+    pub fn keep(&mut self, key: &str, locid: i64) {
+        if !self.ids.contains_key(key) && self.used.insert(locid) {
+            self.ids.insert(String::from(key), locid);
+        }
+    }
+
+    /** Keeps the ids given by the build of the database at `path`, as
+    recorded in its `cdataset_loc` table.  A database without one, or from
+    before location keys were recorded there, is ignored, but one that
+    doesn't exist is an error. */
+    pub async fn keep_from(&mut self, path: &Path) -> Result<()> {
+        if !path.exists() {
+            return Err(Error::Config(format!(
+                "{}: no such database to keep location ids from",
+                path.display()
+            )));
+        }
+        let pool = open_pool(path, 1).await?;
+        let mut conn = pool.acquire().await?;
+        if column_names(&mut conn, "cdataset_loc")
+            .await?
+            .iter()
+            .any(|x| x == "location_key")
+        {
+            let ids: Vec<(String, i64)> =
+                sqlx::query_as("SELECT location_key, locid FROM cdataset_loc ORDER BY locid")
+                    .fetch_all(&mut conn)
+                    .await?;
+            for (key, locid) in ids {
+                self.keep(&key, locid);
+            }
+        }
+        drop(conn);
+        pool.close().await;
+        Ok(())
+    }
+
+    /// Gives an id to each of `locations` that hasn't one.
+    pub fn assign_all(&mut self, locations: impl IntoIterator<Item = Location>) {
+        let mut pending: Vec<Location> = locations
+            .into_iter()
+            .filter(|x| !self.ids.contains_key(&x.key))
+            .collect();
+        pending.sort();
+        pending.dedup_by(|a, b| a.key == b.key);
+        let mut pending: Vec<(Option<i64>, Location)> = pending
+            .into_iter()
+            .map(|x| {
+                let uid = self
+                    .uids
+                    .find(x.fips, &x.country_code, &x.province, &x.administrative);
+                (uid, x)
+            })
+            .collect();
+        // A key matching its UID by FIPS code beats one matching it by name.
+        let rank = |(uid, loc): &(Option<i64>, Location)| {
+            (uid.is_none() || loc.fips.is_none(), loc.key.len())
+        };
+        pending.sort_by(|a, b| (rank(a), &a.1).cmp(&(rank(b), &b.1)));
+        for (uid, loc) in pending {
+            let uid = uid.filter(|x| !self.used.contains(x));
+            let locid = match uid {
+                Some(uid) => uid,
+                None => (0..)
+                    .map(|n| hashed_locid(&loc.key, n))
+                    .find(|x| !self.used.contains(x))
+                    .unwrap(),
+            };
+            self.used.insert(locid);
+            self.ids.insert(loc.key, locid);
+        }
+    }
+
+    /// Returns the `locid` of `location`, giving it one if it has none.
+    pub fn assign(&mut self, location: Location) -> i64 {
+        let key = location.key.clone();
+        self.assign_all(Some(location));
+        self.ids[&key]
+    }
+
+    /// Returns the `locid` given to `key`, if any.
+    pub fn get(&self, key: &str) -> Option<i64> {
+        self.ids.get(key).copied()
+    }
+}
+
+/** [`HASHED_LOCIDS`] plus the first 48 bits of the SHA-256 of `key`, followed,
+for the `n`th retry after a collision, by a newline and `n`. */
+fn hashed_locid(key: &str, n: u32) -> i64 {
+    let digest = match n {
+        0 => Sha256::digest(key.as_bytes()),
+        n => Sha256::digest(format!("{}\n{}", key, n).as_bytes()),
+    };
+    let mut bytes = [0u8; 8];
+    bytes[2..].copy_from_slice(&digest[..6]);
+    HASHED_LOCIDS + i64::from_be_bytes(bytes)
 }
 
-pub fn parse_to_final<A: Iterator<Item = csv::StringRecord>>(
+impl From<&LocRecord> for Location {
+    fn from(rec: &LocRecord) -> Self {
+        Location {
+            key: rec.key.clone(),
+            fips: rec.us_county_fips,
+            country_code: rec.country_code.clone(),
+            province: rec.province_normalized.clone(),
+            administrative: rec.administrative_normalized.clone(),
+        }
+    }
+}
+
+/// Binds a location and its `locid` to an insert into `cdataset_loc`.
+fn bind_location((locid, rec): (i64, LocRecord), query: Query<Sqlite>) -> Query<Sqlite> {
+    query
+        .bind(locid)
+        .bind(rec.key)
+        .bind(rec.xtype)
+        .bind(rec.label)
+        .bind(rec.country_code)
+        .bind(rec.country_normalized)
+        .bind(rec.province_normalized)
+        .bind(rec.administrative_normalized)
+        .bind(rec.region)
+        .bind(rec.subregion)
+        .bind(rec.us_state_code)
+        .bind(rec.us_state_name)
+        .bind(rec.us_county_fips.map(i64::from))
+}
+
+pub fn parse_to_final<'a, A: Iterator<Item = Result<csv::StringRecord>> + 'a>(
+    source: &'a str,
+    headers: &'a csv::StringRecord,
     striter: A,
-) -> impl Iterator<Item = LocRecord> {
-    striter.filter_map(|x| rec_to_struct(&x).expect("rec_to_struct"))
+) -> impl Iterator<Item = Result<LocRecord>> + 'a {
+    striter.filter_map(move |x| {
+        x.and_then(|rec| rec_to_struct(source, headers, &rec))
+            .transpose()
+    })
 }
 
-pub fn parse_init_file(file: File) -> Result<csv::Reader<File>, Box<dyn Error>> {
+pub fn parse_init_file(file: File) -> Result<csv::Reader<File>> {
     let rdr = csv::ReaderBuilder::new()
         .delimiter(b'\t')
         .double_quote(false)
@@ -69,73 +302,77 @@ pub fn parse_init_file(file: File) -> Result<csv::Reader<File>, Box<dyn Error>>
     Ok(rdr)
 }
 
-/* Will panic on parse error.  */
-pub async fn load<'a, A: std::io::Read>(
+/** Parse the location TSV, loading the locations in `ctx.subset` into
+`cdataset_loc` with identifiers from `locids`, and returning a hashmap of
+location key to location information, along with what was loaded.  Every
+location in the file, along with those in `others` that it lacks, is given its
+identifier before the subset is applied, so that a subset gets the same
+identifiers as a full build.  */
+pub async fn load_locations<A: std::io::Read>(
     mut transaction: Transaction<sqlx::pool::PoolConnection<sqlx::SqliteConnection>>,
     fipshm: &HashMap<u32, u64>,
-    rdr: &'a mut csv::Reader<A>,
-) -> HashMap<String, LocRec> {
-    assert_eq!(
-        vec![
-            "key",
-            "key_original",
-            "type",
-            "label",
-            "country_code",
-            "country_different",
-            "country_normalized",
-            "country_original",
-            "province_different",
-            "province_normalized",
-            "province_original",
-            "administrative_different",
-            "administrative_normalized",
-            "administrative_original",
-            "region",
-            "subregion",
-            "us_state_code",
-            "us_state_name",
-            "us_county_fips",
-            "us_county_name",
-            "dataset"
-        ],
-        rdr.headers().unwrap().iter().collect::<Vec<&str>>()
-    );
-    let recs = parse_records(rdr.byte_records());
-    let finaliter = parse_to_final(recs);
+    locids: &mut LocIds,
+    others: Vec<Location>,
+    rdr: &mut csv::Reader<A>,
+    ctx: &LoadContext,
+) -> Result<(HashMap<String, LocRec>, LoadStats)> {
+    let name = ctx.source_name("cdataset");
+    let transcoder = Transcoder::new(ctx.encoding);
+    let headers = check_headers(
+        name,
+        COLUMNS,
+        REQUIRED_COLUMNS,
+        rdr,
+        &transcoder,
+        &ctx.progress,
+    )?;
+    record_headers(&mut transaction, name, &headers).await?;
+    let recs = parse_records(name, &transcoder, rdr.byte_records());
+    let finaliter = parse_to_final(name, &headers, recs);
     let mut hm = HashMap::new();
-    let mut counter = 1;
+    let mut stats = LoadStats::default();
+    let mut inserter = BulkInsert::new(
+        "INSERT INTO cdataset_loc VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        bind_location,
+    );
+    let mut recs = Vec::new();
     for rec in finaliter {
+        match rec {
+            Ok(rec) => recs.push(rec),
+            Err(e) => stats.reject(e)?,
+        }
+    }
+    // The file describes its locations better than the datasets do.
+    let keys: HashSet<&str> = recs.iter().map(|x| x.key.as_str()).collect();
+    let others = others
+        .into_iter()
+        .filter(|x| !keys.contains(x.key.as_str()));
+    locids.assign_all(recs.iter().map(Location::from).chain(others));
+    for rec in recs {
+        let place = Place {
+            country: Some(&rec.country_code),
+            state: Some(&rec.us_state_name),
+            fips: rec.us_county_fips.map(i64::from),
+        };
+        if !ctx.subset.keep_place(&place) {
+            continue;
+        }
+        stats.parsed += 1;
         let fips = rec.us_county_fips;
+        let locid = locids.assign(Location::from(&rec));
         hm.insert(
-            rec.key,
+            rec.key.clone(),
             LocRec {
-                locid: counter,
+                locid,
                 fips,
-                population: fips.and_then(|f| fipshm.get(&f).map(|x| *x)),
+                population: fips.and_then(|f| fipshm.get(&f).copied()),
             },
         );
-        let query =
-            sqlx::query("INSERT INTO cdataset_loc VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)");
-        query
-            .bind(i64::from(counter))
-            .bind(rec.xtype)
-            .bind(rec.label)
-            .bind(rec.country_code)
-            .bind(rec.country_normalized)
-            .bind(rec.province_normalized)
-            .bind(rec.administrative_normalized)
-            .bind(rec.region)
-            .bind(rec.subregion)
-            .bind(rec.us_state_code)
-            .bind(rec.us_state_name)
-            .bind(rec.us_county_fips.map(i64::from))
-            .execute(&mut transaction)
-            .await
-            .unwrap();
-
-        counter += 1;
+        inserter.push((locid, rec), &mut transaction).await?;
     }
-    transaction.commit().await.unwrap();
-    hm
+    stats.inserted = inserter.finish(&mut transaction).await?;
+    stats.replacements = transcoder.replacements();
+    stats.skipped = records_read(rdr) - stats.parsed - stats.rejected();
+    transaction.commit().await?;
+    Ok((hm, stats))
 }
@@ -0,0 +1,166 @@
+/* Decompressing source files
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use crate::Result;
+
+/** How a source file is compressed or packed.  Files are decompressed
+after they are obtained, so a source can come in any of these. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Plain,
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+    Zip,
+}
+
+const KINDS: &[(Compression, &str, &[u8])] = &[
+    (Compression::Gzip, ".gz", b"\x1f\x8b"),
+    (Compression::Xz, ".xz", b"\xfd7zXZ\x00"),
+    (Compression::Bzip2, ".bz2", b"BZh"),
+    (Compression::Zstd, ".zst", b"\x28\xb5\x2f\xfd"),
+    (Compression::Zip, ".zip", b"PK\x03\x04"),
+];
+
+impl Compression {
+    /** Determines how a file is compressed from the extension of its name,
+    or, if that says nothing, from `head`, the first bytes of the file.
+
+    ```
+    use covid19db::loader::Compression;
+
+    assert_eq!(Compression::detect("us-counties.csv.gz", b""), Compression::Gzip);
+    assert_eq!(Compression::detect("data.csv", b"\x1f\x8b\x08\x00"), Compression::Gzip);
+    assert_eq!(Compression::detect("bundle", b"PK\x03\x04"), Compression::Zip);
+    assert_eq!(Compression::detect("data.csv", b"date,state"), Compression::Plain);
+    ```
+    */
+    pub fn detect(filename: &str, head: &[u8]) -> Self {
+        KINDS
+            .iter()
+            .find(|(_, ext, _)| filename.ends_with(ext))
+            .or_else(|| {
+                KINDS.iter().find(|(kind, _, magic)| {
+                    head.starts_with(magic)
+                        // "BZh" is followed by the block size, 1 to 9.
+                        && (*kind != Compression::Bzip2
+                            || head.get(3).is_some_and(|x| (b'1'..=b'9').contains(x)))
+                })
+            })
+            .map_or(Compression::Plain, |(kind, _, _)| *kind)
+    }
+
+    /// Like [`detect`](Compression::detect), reading the start of the file at `path`.
+    pub fn detect_file(filename: &str, path: &Path) -> Result<Self> {
+        let mut head = Vec::new();
+        File::open(path)?.take(8).read_to_end(&mut head)?;
+        Ok(Compression::detect(filename, &head))
+    }
+
+    /// The usual extension of files compressed this way, if any.
+    pub fn extension(self) -> Option<&'static str> {
+        KINDS
+            .iter()
+            .find(|(kind, _, _)| *kind == self)
+            .map(|(_, ext, _)| *ext)
+    }
+
+    /** Decompresses the file at `src` into `dest`.  From a zip archive, the
+    file named `member` is extracted; if `member` is not given, the archive
+    must hold exactly one file. */
+    pub fn decompress(self, src: &Path, member: Option<&str>, dest: &Path) -> Result<()> {
+        let input = BufReader::new(File::open(src)?);
+        let mut output = File::create(dest)?;
+        match self {
+            Compression::Plain => {
+                io::copy(&mut { input }, &mut output)?;
+            }
+            Compression::Gzip => {
+                io::copy(&mut flate2::read::MultiGzDecoder::new(input), &mut output)?;
+            }
+            Compression::Xz => {
+                io::copy(
+                    &mut xz2::read::XzDecoder::new_multi_decoder(input),
+                    &mut output,
+                )?;
+            }
+            Compression::Bzip2 => {
+                io::copy(&mut bzip2::read::MultiBzDecoder::new(input), &mut output)?;
+            }
+            Compression::Zstd => zstd::stream::copy_decode(input, &mut output)?,
+            Compression::Zip => {
+                let mut archive = zip::ZipArchive::new(input).map_err(io::Error::from)?;
+                let mut names: Vec<String> = archive
+                    .file_names()
+                    .filter(|x| !x.ends_with('/'))
+                    .map(String::from)
+                    .collect();
+                names.sort();
+                let name = match (member, names.as_slice()) {
+                    (Some(member), _) if names.iter().any(|x| x == member) => member,
+                    (None, [only]) => only.as_str(),
+                    (Some(member), _) => {
+                        return Err(zip_error(
+                            src,
+                            &format!("has no member {:?}", member),
+                            &names,
+                        ))
+                    }
+                    (None, _) => {
+                        return Err(zip_error(src, "holds more than one file", &names));
+                    }
+                };
+                let mut file = archive.by_name(name).map_err(io::Error::from)?;
+                io::copy(&mut file, &mut output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn zip_error(src: &Path, problem: &str, names: &[String]) -> crate::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} {}; name one of its files as the member: {}",
+            src.display(),
+            problem,
+            names.join(", ")
+        ),
+    )
+    .into()
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Plain => "plain",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+            Compression::Zstd => "zstd",
+            Compression::Zip => "zip",
+        })
+    }
+}
@@ -0,0 +1,311 @@
+/* Source configuration
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use sqlx::sqlite::SqlitePool;
+use std::path::{Path, PathBuf};
+
+use super::parseutil::Encoding;
+use super::source::{Coverage, LoadContext, LoadStats, Registry, Source, SourceFile};
+use crate::attribution::Credit;
+use crate::{Error, Result};
+
+/// The configuration built in to the crate.
+const BUILTIN: &str = include_str!("../sources.toml");
+
+/** Where each source gets its files, and whether it is built, as read from
+a source configuration file.  The format is described in
+[`src/sources.toml`](https://github.com/jgoerzen/covid19db/blob/master/src/sources.toml),
+which holds the built-in configuration.
+
+```
+use covid19db::loader::SourcesConfig;
+
+let mut config = SourcesConfig::builtin();
+config.extend(
+    SourcesConfig::from_toml(
+        r#"
+        [[source]]
+        name = "rtlive"
+        enabled = false
+
+        [[source]]
+        name = "covidtracking"
+
+        [[source.file]]
+        filename = "covidtracking-daily.csv"
+        url = "https://example.com/mirror/{revision}/daily.csv"
+        revision = "2021-03-07"
+        "#,
+    )
+    .unwrap(),
+);
+assert!(!config.get("rtlive").unwrap().enabled());
+let files = config.files("covidtracking");
+assert_eq!(files[0].url, "https://example.com/mirror/2021-03-07/daily.csv");
+```
+*/
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SourcesConfig {
+    #[serde(default, rename = "source")]
+    pub sources: Vec<SourceConfig>,
+}
+
+/// The configuration of one source.  Settings left out keep their built-in values.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    /// The name of the source, as used with `--only` and `--skip`.
+    pub name: String,
+    /// The built-in loader that reads the files; by default, the one with the same name.
+    pub parser: Option<String>,
+    /// Whether the source is built; by default, it is.
+    pub enabled: Option<bool>,
+    /// The encoding of the files; by default, the loader's.
+    pub encoding: Option<Encoding>,
+    /// The files, in the order the loader expects them; if empty, the built-in ones.
+    #[serde(default, rename = "file")]
+    pub files: Vec<FileConfig>,
+}
+
+/// Where to get one file of a source.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    /// The name under which the file is kept in a local source directory.
+    pub filename: String,
+    /// Where to download the file; `{revision}` is replaced by `revision`.
+    pub url: String,
+    /// A branch, tag, or commit to substitute into `url`.
+    pub revision: Option<String>,
+    /// The SHA-256 the file must have as downloaded, in hex.
+    pub sha256: Option<String>,
+    /// For a zip archive, the file in it to load.
+    pub member: Option<String>,
+}
+
+impl SourcesConfig {
+    /// Returns the built-in configuration, from `src/sources.toml`.
+    pub fn builtin() -> Self {
+        SourcesConfig::from_toml(BUILTIN).expect("built-in source configuration is invalid")
+    }
+
+    /// Parses a source configuration file.
+    pub fn from_toml(text: &str) -> Result<Self> {
+        toml::from_str(text)
+            .map_err(|e| Error::Config(format!("Invalid source configuration: {}", e)))
+    }
+
+    /// Reads a source configuration file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        SourcesConfig::from_toml(&std::fs::read_to_string(path)?)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    /** Applies `other` on top of this configuration.  A source already
+    present takes the settings `other` gives it, including its files, if
+    any; a new source is added at the end. */
+    pub fn extend(&mut self, other: SourcesConfig) {
+        for source in other.sources {
+            match self.sources.iter_mut().find(|x| x.name == source.name) {
+                Some(existing) => {
+                    existing.parser = source.parser.or_else(|| existing.parser.take());
+                    existing.enabled = source.enabled.or(existing.enabled);
+                    existing.encoding = source.encoding.or(existing.encoding);
+                    if !source.files.is_empty() {
+                        existing.files = source.files;
+                    }
+                }
+                None => self.sources.push(source),
+            }
+        }
+    }
+
+    /// Returns the configuration of the named source, if any.
+    pub fn get(&self, name: &str) -> Option<&SourceConfig> {
+        self.sources.iter().find(|x| x.name == name)
+    }
+
+    /// Returns the files of the named source, or none if it isn't configured.
+    pub fn files(&self, name: &str) -> Vec<SourceFile> {
+        self.get(name)
+            .map(|x| x.files.iter().map(FileConfig::source_file).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl SourceConfig {
+    /// The built-in loader that reads this source's files.
+    pub fn parser(&self) -> &str {
+        self.parser.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Whether this source is built.
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+}
+
+impl FileConfig {
+    /// Returns the file to fetch, with the revision substituted into its URL.
+    pub fn source_file(&self) -> SourceFile {
+        let url = match &self.revision {
+            Some(rev) => self.url.replace("{revision}", rev),
+            None => self.url.clone(),
+        };
+        SourceFile {
+            sha256: self.sha256.clone(),
+            member: self.member.clone(),
+            ..SourceFile::new(&self.filename, &url)
+        }
+    }
+}
+
+/// The files returned by the built-in source named `name`.
+pub(crate) fn builtin_files(name: &str) -> Vec<SourceFile> {
+    SourcesConfig::builtin().files(name)
+}
+
+/** Builds a registry from `config`: each configured source is loaded by the
+built-in source named by its parser, using the configured files, and under
+its configured name.  It depends on the sources configured with the parsers
+that its own parser depends on, preferring enabled ones.  Disabled sources
+are registered, so that their schema is created, but are never selected. */
+pub(crate) fn registry(
+    config: &SourcesConfig,
+    parser: impl Fn(&str) -> Option<Box<dyn Source>>,
+    parsers: &[&str],
+) -> Result<Registry> {
+    let mut registry = Registry::new();
+    for sc in &config.sources {
+        let inner = parser(sc.parser()).ok_or_else(|| {
+            Error::Config(format!(
+                "Source {:?} names unknown parser {:?}; valid parsers are: {}",
+                sc.name,
+                sc.parser(),
+                parsers.join(", ")
+            ))
+        })?;
+        if sc.files.is_empty() {
+            return Err(Error::Config(format!("Source {:?} has no files", sc.name)));
+        }
+        if sc.enabled() {
+            if let Some(other) = config
+                .sources
+                .iter()
+                .find(|x| x.name != sc.name && x.enabled() && x.parser() == sc.parser())
+            {
+                return Err(Error::Config(format!(
+                    "Sources {:?} and {:?} both load the tables of {}; disable one",
+                    other.name,
+                    sc.name,
+                    sc.parser()
+                )));
+            }
+        }
+        let dependencies = inner
+            .dependencies()
+            .into_iter()
+            .map(|dep| {
+                let loads = |x: &&SourceConfig| x.parser() == dep;
+                config
+                    .sources
+                    .iter()
+                    .filter(loads)
+                    .find(|x| x.enabled())
+                    .or_else(|| config.sources.iter().find(loads))
+                    .map_or_else(|| String::from(dep), |x| x.name.clone())
+            })
+            .collect();
+        registry.register(Box::new(Configured {
+            name: sc.name.clone(),
+            dependencies,
+            inner,
+            files: sc.files.iter().map(FileConfig::source_file).collect(),
+            encoding: sc.encoding,
+        }));
+        if !sc.enabled() {
+            registry.disable(&sc.name);
+        }
+    }
+    Ok(registry)
+}
+
+/// A built-in source, renamed and given files and an encoding according to the
+/// configuration.
+struct Configured {
+    name: String,
+    dependencies: Vec<String>,
+    inner: Box<dyn Source>,
+    files: Vec<SourceFile>,
+    encoding: Option<Encoding>,
+}
+
+#[async_trait]
+impl Source for Configured {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dependencies(&self) -> Vec<&str> {
+        self.dependencies.iter().map(String::as_str).collect()
+    }
+
+    fn files(&self) -> Vec<SourceFile> {
+        self.files.clone()
+    }
+
+    fn datasets(&self, files: &[SourceFile]) -> Vec<String> {
+        self.inner.datasets(files)
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding.unwrap_or_else(|| self.inner.encoding())
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.inner.schema()
+    }
+
+    fn coverage(&self) -> Option<Coverage> {
+        self.inner.coverage()
+    }
+
+    fn attribution(&self) -> Vec<Credit> {
+        self.inner.attribution()
+    }
+
+    async fn load(
+        &self,
+        files: &[PathBuf],
+        pool: &mut SqlitePool,
+        ctx: &LoadContext,
+    ) -> Result<Vec<LoadStats>> {
+        let ctx = LoadContext {
+            source: Some(self.name.clone()),
+            ..ctx.clone()
+        };
+        self.inner.load(files, pool, &ctx).await
+    }
+
+    async fn check(&self, pool: &mut SqlitePool) -> Result<()> {
+        self.inner.check(pool).await
+    }
+}
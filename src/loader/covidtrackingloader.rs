@@ -19,15 +19,85 @@ Copyright (c) 2019-2020 John Goerzen
 
 pub use crate::dateutil::*;
 pub use crate::dbschema::*;
+use crate::dbutil::*;
+use crate::loader::config::builtin_files;
 pub use crate::loader::parseutil::*;
+use crate::loader::source::{Coverage, LoadContext, LoadStats, Source, SourceFile};
+use crate::loader::subset::Place;
+use crate::Result;
+use async_trait::async_trait;
 use chrono::NaiveDate;
 use csv;
 use serde::Deserialize;
-use sqlx::Transaction;
+use sqlx::sqlite::SqlitePool;
+use std::fs::File;
+use std::path::PathBuf;
+
+const COLUMNS: &[&str] = &[
+    "date",
+    "state",
+    "positive",
+    "probableCases",
+    "negative",
+    "pending",
+    "totalTestResultsSource",
+    "totalTestResults",
+    "hospitalizedCurrently",
+    "hospitalizedCumulative",
+    "inIcuCurrently",
+    "inIcuCumulative",
+    "onVentilatorCurrently",
+    "onVentilatorCumulative",
+    "recovered",
+    "lastUpdateEt",
+    "dateModified",
+    "checkTimeEt",
+    "death",
+    "hospitalized",
+    "hospitalizedDischarged",
+    "dateChecked",
+    "totalTestsViral",
+    "positiveTestsViral",
+    "negativeTestsViral",
+    "positiveCasesViral",
+    "deathConfirmed",
+    "deathProbable",
+    "totalTestEncountersViral",
+    "totalTestsPeopleViral",
+    "totalTestsAntibody",
+    "positiveTestsAntibody",
+    "negativeTestsAntibody",
+    "totalTestsPeopleAntibody",
+    "positiveTestsPeopleAntibody",
+    "negativeTestsPeopleAntibody",
+    "totalTestsPeopleAntigen",
+    "positiveTestsPeopleAntigen",
+    "totalTestsAntigen",
+    "positiveTestsAntigen",
+    "fips",
+    "positiveIncrease",
+    "negativeIncrease",
+    "total",
+    "totalTestResultsIncrease",
+    "posNeg",
+    "dataQualityGrade",
+    "deathIncrease",
+    "hospitalizedIncrease",
+    "hash",
+    "commercialScore",
+    "negativeRegularScore",
+    "negativeScore",
+    "positiveScore",
+    "score",
+    "grade",
+];
+
+const REQUIRED_COLUMNS: &[&str] = &["date", "state", "totalTestResultsSource", "fips"];
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct CsvRec {
-    pub date: String,
+    #[serde(deserialize_with = "date_from_yyyymmdd")]
+    pub date: NaiveDate,
     pub state: String,
     pub positive: Option<i64>,
     pub probableCases: Option<i64>,
@@ -37,7 +107,9 @@ pub struct CsvRec {
     pub totalTestResults: Option<i64>,
     pub hospitalizedCurrently: Option<i64>,
     pub hospitalizedCumulative: Option<i64>,
+    #[serde(rename = "inIcuCurrently")]
     pub incluCurrently: Option<i64>,
+    #[serde(rename = "inIcuCumulative")]
     pub incluCumulative: Option<i64>,
     pub onVentilatorCurrently: Option<i64>,
     pub onVentilatorCumulative: Option<i64>,
@@ -85,147 +157,140 @@ pub struct CsvRec {
     pub grade: Option<String>,
 }
 
-pub fn parse_to_final<A: Iterator<Item = csv::StringRecord>>(
+pub fn parse_to_final<'a, A: Iterator<Item = Result<csv::StringRecord>> + 'a>(
+    source: &'a str,
+    headers: &'a csv::StringRecord,
     striter: A,
-) -> impl Iterator<Item = CsvRec> {
-    striter.filter_map(|x| rec_to_struct(&x).expect("rec_to_struct"))
+) -> impl Iterator<Item = Result<CsvRec>> + 'a {
+    striter.filter_map(move |x| {
+        x.and_then(|rec| rec_to_struct(source, headers, &rec))
+            .transpose()
+    })
 }
 
-/** Parse the CSV, loading it into the database.
- * Will panic on parse error.  */
-pub async fn load<'a, A: std::io::Read>(
-    rdr: &'a mut csv::Reader<A>,
-    mut transaction: Transaction<sqlx::pool::PoolConnection<sqlx::SqliteConnection>>,
-) {
-    assert_eq!(
-        vec![
-            "date",
-            "state",
-            "positive",
-            "probableCases",
-            "negative",
-            "pending",
-            "totalTestResultsSource",
-            "totalTestResults",
-            "hospitalizedCurrently",
-            "hospitalizedCumulative",
-            "inIcuCurrently",
-            "inIcuCumulative",
-            "onVentilatorCurrently",
-            "onVentilatorCumulative",
-            "recovered",
-            "lastUpdateEt",
-            "dateModified",
-            "checkTimeEt",
-            "death",
-            "hospitalized",
-            "hospitalizedDischarged",
-            "dateChecked",
-            "totalTestsViral",
-            "positiveTestsViral",
-            "negativeTestsViral",
-            "positiveCasesViral",
-            "deathConfirmed",
-            "deathProbable",
-            "totalTestEncountersViral",
-            "totalTestsPeopleViral",
-            "totalTestsAntibody",
-            "positiveTestsAntibody",
-            "negativeTestsAntibody",
-            "totalTestsPeopleAntibody",
-            "positiveTestsPeopleAntibody",
-            "negativeTestsPeopleAntibody",
-            "totalTestsPeopleAntigen",
-            "positiveTestsPeopleAntigen",
-            "totalTestsAntigen",
-            "positiveTestsAntigen",
-            "fips",
-            "positiveIncrease",
-            "negativeIncrease",
-            "total",
-            "totalTestResultsIncrease",
-            "posNeg",
-            "dataQualityGrade",
-            "deathIncrease",
-            "hospitalizedIncrease",
-            "hash",
-            "commercialScore",
-            "negativeRegularScore",
-            "negativeScore",
-            "positiveScore",
-            "score",
-            "grade"
-        ],
-        rdr.headers().unwrap().iter().collect::<Vec<&str>>()
-    );
-    let recs = parse_records(rdr.byte_records());
-    let finaliter = parse_to_final(recs);
-    for rec in finaliter {
-        let nd = NaiveDate::parse_from_str(rec.date.as_str(), "%Y%m%d").unwrap();
-        // from the schema: sed -e 's/ *\([^ ]*\).*/\1: rec.\1,/'
-        let dbrec = CovidTracking {
-            date_julian: nd_to_day(&nd),
-            state: rec.state,
-            positive: rec.positive,
-            probableCases: rec.probableCases,
-            negative: rec.negative,
-            pending: rec.pending,
-            totalTestResults: rec.totalTestResults,
-            hospitalizedCurrently: rec.hospitalizedCurrently,
-            hospitalizedCumulative: rec.hospitalizedCumulative,
-            incluCurrently: rec.incluCurrently,
-            incluCumulative: rec.incluCumulative,
-            onVentilatorCurrently: rec.onVentilatorCurrently,
-            onVentilatorCumulative: rec.onVentilatorCumulative,
-            recovered: rec.recovered,
-            dataQualityGrade: rec.dataQualityGrade,
-            lastUpdateEt: rec.lastUpdateEt,
-            dateModified: rec.dateModified,
-            checkTimeEt: rec.checkTimeEt,
-            death: rec.death,
-            hospitalized: rec.hospitalized,
-            hospitalizedDischarged: rec.hospitalizedDischarged,
-            dateChecked: rec.dateChecked,
-            totalTestsViral: rec.totalTestsViral,
-            positiveTestsViral: rec.positiveTestsViral,
-            negativeTestsViral: rec.negativeTestsViral,
-            positiveCasesViral: rec.positiveCasesViral,
-            deathConfirmed: rec.deathConfirmed,
-            deathProbable: rec.deathProbable,
-            totalTestEncountersViral: rec.totalTestEncountersViral,
-            totalTestsPeopleViral: rec.totalTestsPeopleViral,
-            totalTestsAntibody: rec.totalTestsAntibody,
-            positiveTestsAntibody: rec.positiveTestsAntibody,
-            negativeTestsAntibody: rec.negativeTestsAntibody,
-            totalTestsPeopleAntibody: rec.totalTestsPeopleAntibody,
-            positiveTestsPeopleAntibody: rec.positiveTestsPeopleAntibody,
-            negativeTestsPeopleAntibody: rec.negativeTestsPeopleAntibody,
-            totalTestsPeopleAntigen: rec.totalTestsPeopleAntigen,
-            positiveTestsPeopleAntigen: rec.positiveTestsPeopleAntigen,
-            totalTestsAntigen: rec.totalTestsAntigen,
-            positiveTestsAntigen: rec.positiveTestsAntigen,
-            fips: rec.fips,
-            positiveIncrease: rec.positiveIncrease,
-            negativeIncrease: rec.negativeIncrease,
-            total: rec.total,
-            totalTestResultsSource: rec.totalTestResultsSource,
-            totalTestResultsIncrease: rec.totalTestResultsIncrease,
-            posNeg: rec.posNeg,
-            deathIncrease: rec.deathIncrease,
-            hospitalizedIncrease: rec.hospitalizedIncrease,
-            commercialScore: rec.commercialScore,
-            negativeRegularScore: rec.negativeRegularScore,
-            negativeScore: rec.negativeScore,
-            positiveScore: rec.positiveScore,
-            score: rec.score,
-            grade: rec.grade,
-        };
-        let query = sqlx::query(CovidTracking::insert_str());
-        dbrec
-            .bind_query(query)
-            .execute(&mut transaction)
-            .await
-            .unwrap();
+/// The COVID Tracking Project state data, in `covidtracking`.
+pub struct CovidTrackingSource;
+
+#[async_trait]
+impl Source for CovidTrackingSource {
+    fn name(&self) -> &str {
+        "covidtracking"
+    }
+
+    fn files(&self) -> Vec<SourceFile> {
+        builtin_files(self.name())
+    }
+
+    fn schema(&self) -> Vec<String> {
+        covidtracking_schema()
+    }
+
+    fn coverage(&self) -> Option<Coverage> {
+        Some(Coverage::new("covidtracking_raw", "state"))
+    }
+
+    /// Parse the CSV, loading it into the database.
+    async fn load(
+        &self,
+        files: &[PathBuf],
+        pool: &mut SqlitePool,
+        ctx: &LoadContext,
+    ) -> Result<Vec<LoadStats>> {
+        let name = ctx.source_name(self.name());
+        let mut rdr = parse_init_file(File::open(&files[0])?)?;
+        let transcoder = Transcoder::new(ctx.encoding);
+        let headers = check_headers(
+            name,
+            COLUMNS,
+            REQUIRED_COLUMNS,
+            &mut rdr,
+            &transcoder,
+            &ctx.progress,
+        )?;
+        let mut transaction = pool.begin().await?;
+        record_headers(&mut transaction, name, &headers).await?;
+        let recs = parse_records(name, &transcoder, rdr.byte_records());
+        let finaliter = parse_to_final(name, &headers, recs);
+        let mut inserter = BulkInsert::new(CovidTracking::insert_str(), CovidTracking::bind_query)
+            .discard(ctx.dry_run);
+        let mut stats = LoadStats::default();
+        for rec in finaliter {
+            let rec = match rec {
+                Ok(rec) => rec,
+                Err(e) => {
+                    stats.reject(e)?;
+                    continue;
+                }
+            };
+            if !ctx.subset.keep(&rec.date, &Place::us_state(&rec.state)) {
+                continue;
+            }
+            stats.parsed += 1;
+            // from the schema: sed -e 's/ *\([^ ]*\).*/\1: rec.\1,/'
+            let dbrec = CovidTracking {
+                date_julian: nd_to_day(&rec.date),
+                state: rec.state,
+                positive: rec.positive,
+                probableCases: rec.probableCases,
+                negative: rec.negative,
+                pending: rec.pending,
+                totalTestResults: rec.totalTestResults,
+                hospitalizedCurrently: rec.hospitalizedCurrently,
+                hospitalizedCumulative: rec.hospitalizedCumulative,
+                incluCurrently: rec.incluCurrently,
+                incluCumulative: rec.incluCumulative,
+                onVentilatorCurrently: rec.onVentilatorCurrently,
+                onVentilatorCumulative: rec.onVentilatorCumulative,
+                recovered: rec.recovered,
+                dataQualityGrade: rec.dataQualityGrade,
+                lastUpdateEt: rec.lastUpdateEt,
+                dateModified: rec.dateModified,
+                checkTimeEt: rec.checkTimeEt,
+                death: rec.death,
+                hospitalized: rec.hospitalized,
+                hospitalizedDischarged: rec.hospitalizedDischarged,
+                dateChecked: rec.dateChecked,
+                totalTestsViral: rec.totalTestsViral,
+                positiveTestsViral: rec.positiveTestsViral,
+                negativeTestsViral: rec.negativeTestsViral,
+                positiveCasesViral: rec.positiveCasesViral,
+                deathConfirmed: rec.deathConfirmed,
+                deathProbable: rec.deathProbable,
+                totalTestEncountersViral: rec.totalTestEncountersViral,
+                totalTestsPeopleViral: rec.totalTestsPeopleViral,
+                totalTestsAntibody: rec.totalTestsAntibody,
+                positiveTestsAntibody: rec.positiveTestsAntibody,
+                negativeTestsAntibody: rec.negativeTestsAntibody,
+                totalTestsPeopleAntibody: rec.totalTestsPeopleAntibody,
+                positiveTestsPeopleAntibody: rec.positiveTestsPeopleAntibody,
+                negativeTestsPeopleAntibody: rec.negativeTestsPeopleAntibody,
+                totalTestsPeopleAntigen: rec.totalTestsPeopleAntigen,
+                positiveTestsPeopleAntigen: rec.positiveTestsPeopleAntigen,
+                totalTestsAntigen: rec.totalTestsAntigen,
+                positiveTestsAntigen: rec.positiveTestsAntigen,
+                fips: rec.fips,
+                positiveIncrease: rec.positiveIncrease,
+                negativeIncrease: rec.negativeIncrease,
+                total: rec.total,
+                totalTestResultsSource: rec.totalTestResultsSource,
+                totalTestResultsIncrease: rec.totalTestResultsIncrease,
+                posNeg: rec.posNeg,
+                deathIncrease: rec.deathIncrease,
+                hospitalizedIncrease: rec.hospitalizedIncrease,
+                commercialScore: rec.commercialScore,
+                negativeRegularScore: rec.negativeRegularScore,
+                negativeScore: rec.negativeScore,
+                positiveScore: rec.positiveScore,
+                score: rec.score,
+                grade: rec.grade,
+            };
+            stats.date(dbrec.date_julian);
+            inserter.push(dbrec, &mut transaction).await?;
+        }
+        stats.inserted = inserter.finish(&mut transaction).await?;
+        stats.replacements = transcoder.replacements();
+        stats.skipped = records_read(&rdr) - stats.parsed - stats.rejected();
+        transaction.commit().await?;
+        Ok(vec![stats])
     }
-    transaction.commit().await.unwrap();
 }
@@ -0,0 +1,332 @@
+/* Downloading source files
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::progress::{Event, Progress};
+use crate::{Error, Result};
+use reqwest::{header, StatusCode};
+use std::io::Write;
+use std::time::Duration;
+use tokio::time::{delay_for, timeout};
+
+/** HTTP cache validators for a downloaded file, as sent by the server in the
+`ETag` and `Last-Modified` headers. */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// True if the server sent neither header.
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/** Settings for downloading files.  A failed download is retried after
+`backoff`, then twice that, and so on, up to `max_backoff`; a download that
+broke off partway resumes where it left off if the server allows.
+
+```
+use covid19db::loader::DownloadOptions;
+use std::time::Duration;
+
+let opts = DownloadOptions {
+    retries: 2,
+    ..DownloadOptions::default()
+};
+assert_eq!(opts.backoff_for(0), Duration::from_secs(1));
+assert_eq!(opts.backoff_for(3), Duration::from_secs(8));
+assert_eq!(opts.backoff_for(30), opts.max_backoff);
+```
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct DownloadOptions {
+    /// How long to wait to connect to the server.
+    pub connect_timeout: Duration,
+    /// How long to wait for the server to respond, or to send more data.
+    pub read_timeout: Duration,
+    /// How many times to retry a failed download before giving up.
+    pub retries: u32,
+    /// How long to wait before the first retry.
+    pub backoff: Duration,
+    /// The longest to wait between retries.
+    pub max_backoff: Duration,
+    /// How many files to download at once; see [`Fetcher`](crate::loader::Fetcher).
+    pub concurrency: usize,
+    /// Where to report progress and retries.
+    pub progress: Progress,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            connect_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(60),
+            retries: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            concurrency: 4,
+            progress: Progress::default(),
+        }
+    }
+}
+
+impl DownloadOptions {
+    /// Returns how long to wait before retry number `attempt`, counting from 0.
+    pub fn backoff_for(&self, attempt: u32) -> Duration {
+        let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+        self.backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |x| x.min(self.max_backoff))
+    }
+}
+
+/// Downloads `url` into `file`, with the default [`DownloadOptions`].
+pub async fn downloadto<W: Write>(url: &str, file: &mut W) -> Result<()> {
+    downloadto_if_changed(
+        url,
+        &Validators::default(),
+        &DownloadOptions::default(),
+        file,
+    )
+    .await?;
+    Ok(())
+}
+
+/** Downloads `url` into `file`, using a conditional GET if `prev` holds the
+validators of an earlier copy.  Returns `None`, having written nothing, if the
+server says the file is unchanged; otherwise, returns the validators of the new
+copy.  Failures are retried as described in [`DownloadOptions`]; a body that
+doesn't match the `Content-Length` the server gave is an [`Error::ContentLength`]. */
+pub async fn downloadto_if_changed<W: Write>(
+    url: &str,
+    prev: &Validators,
+    opts: &DownloadOptions,
+    file: &mut W,
+) -> Result<Option<Validators>> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(opts.connect_timeout)
+        .build()
+        .map_err(|error| Error::Download {
+            url: String::from(url),
+            error,
+        })?;
+    let mut transfer = Transfer {
+        url,
+        written: 0,
+        expected: None,
+        validators: None,
+    };
+    let mut attempt = 0;
+    loop {
+        match transfer.attempt(&client, prev, opts, file).await {
+            Err(e) if attempt < opts.retries && retryable(&e) => {
+                let delay = opts.backoff_for(attempt);
+                opts.progress.event(Event::Retrying {
+                    url: String::from(url),
+                    error: e.to_string(),
+                    delay_ms: delay.as_millis() as u64,
+                });
+                delay_for(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// How often, in bytes, to report on a download's progress.
+const PROGRESS_STEP: u64 = 1 << 20;
+
+/// The progress of one download, across attempts.
+struct Transfer<'a> {
+    url: &'a str,
+    /// Bytes written to the output so far.
+    written: u64,
+    /// The full length of the file, if the server said.
+    expected: Option<u64>,
+    /// Validators from the first response with a body.
+    validators: Option<Validators>,
+}
+
+impl<'a> Transfer<'a> {
+    async fn attempt<W: Write>(
+        &mut self,
+        client: &reqwest::Client,
+        prev: &Validators,
+        opts: &DownloadOptions,
+        file: &mut W,
+    ) -> Result<Option<Validators>> {
+        let mut request = client.get(self.url);
+        if self.written > 0 {
+            request = request.header(header::RANGE, format!("bytes={}-", self.written));
+            // Ask for the whole file instead if it changed since the first attempt.
+            let validators = self.validators.clone().unwrap_or_default();
+            if let Some(ifrange) = validators.etag.or(validators.last_modified) {
+                request = request.header(header::IF_RANGE, ifrange);
+            }
+        } else {
+            if let Some(etag) = &prev.etag {
+                request = request.header(header::IF_NONE_MATCH, etag.as_str());
+            }
+            if let Some(last_modified) = &prev.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified.as_str());
+            }
+        }
+        let mut response = timeout(opts.read_timeout, request.send())
+            .await
+            .map_err(|_| self.timed_out())?
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| self.failed(e))?;
+
+        let mut skip = 0;
+        if response.status() == StatusCode::NOT_MODIFIED && self.written == 0 && !prev.is_empty() {
+            return Ok(None);
+        } else if response.status() == StatusCode::PARTIAL_CONTENT {
+            let (start, total) =
+                content_range(response.headers()).ok_or_else(|| Error::Transfer {
+                    url: String::from(self.url),
+                    message: String::from("missing or invalid Content-Range"),
+                })?;
+            if start != self.written {
+                return Err(Error::Transfer {
+                    url: String::from(self.url),
+                    message: format!("asked to resume at {}, got {}", self.written, start),
+                });
+            }
+            self.expected = total.or(self.expected);
+        } else {
+            let header_str = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|x: &header::HeaderValue| x.to_str().ok())
+                    .map(String::from)
+            };
+            let validators = Validators {
+                etag: header_str(header::ETAG),
+                last_modified: header_str(header::LAST_MODIFIED),
+            };
+            if self.written > 0 {
+                // The server sent the whole file again.  Skip what we already
+                // have, unless the file has changed, since we can't take back
+                // what was written.
+                if self.validators.as_ref() != Some(&validators) {
+                    return Err(Error::Transfer {
+                        url: String::from(self.url),
+                        message: String::from("file changed while being downloaded"),
+                    });
+                }
+                skip = self.written;
+            } else {
+                self.validators = Some(validators);
+            }
+            self.expected = response.content_length();
+        }
+
+        loop {
+            let chunk = timeout(opts.read_timeout, response.chunk())
+                .await
+                .map_err(|_| self.timed_out())?
+                .map_err(|e| self.failed(e))?;
+            let chunk = match chunk {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            let mut data = chunk.as_ref();
+            if skip > 0 {
+                let n = skip.min(data.len() as u64);
+                data = &data[n as usize..];
+                skip -= n;
+            }
+            file.write_all(data)?;
+            let before = self.written;
+            self.written += data.len() as u64;
+            if self.written / PROGRESS_STEP > before / PROGRESS_STEP {
+                self.report(opts);
+            }
+        }
+        self.report(opts);
+        match self.expected {
+            Some(expected) if expected != self.written || skip > 0 => Err(Error::ContentLength {
+                url: String::from(self.url),
+                expected,
+                received: self.written,
+            }),
+            _ => Ok(Some(self.validators.clone().unwrap_or_default())),
+        }
+    }
+
+    fn report(&self, opts: &DownloadOptions) {
+        opts.progress.event(Event::Downloaded {
+            url: String::from(self.url),
+            bytes: self.written,
+            total: self.expected,
+        });
+    }
+
+    fn timed_out(&self) -> Error {
+        Error::Timeout {
+            url: String::from(self.url),
+        }
+    }
+
+    /// Wraps a request error.  A body cut short is reported as a length mismatch.
+    fn failed(&self, error: reqwest::Error) -> Error {
+        match self.expected {
+            Some(expected) if error.is_body() || error.is_decode() => Error::ContentLength {
+                url: String::from(self.url),
+                expected,
+                received: self.written,
+            },
+            _ => Error::Download {
+                url: String::from(self.url),
+                error,
+            },
+        }
+    }
+}
+
+/// Parses the start and total length out of a `Content-Range` header.
+fn content_range(headers: &header::HeaderMap) -> Option<(u64, Option<u64>)> {
+    let value = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let mut parts = value.strip_prefix("bytes ")?.splitn(2, '/');
+    let start = parts.next()?.split('-').next()?.parse().ok()?;
+    let total = parts.next()?.parse().ok();
+    Some((start, total))
+}
+
+/// True if `e` may go away if the download is tried again.
+fn retryable(e: &Error) -> bool {
+    match e {
+        Error::Download { error, .. } => match error.status() {
+            Some(status) => {
+                status.is_server_error()
+                    || status == StatusCode::REQUEST_TIMEOUT
+                    || status == StatusCode::TOO_MANY_REQUESTS
+            }
+            None => !error.is_builder() && !error.is_redirect(),
+        },
+        Error::ContentLength {
+            expected, received, ..
+        } => received < expected,
+        Error::Timeout { .. } => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,217 @@
+/* Dry runs
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use futures::future::join_all;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tempfile::tempdir;
+
+use super::{check_rejects, warn_replacements, LoadOptions};
+use crate::dateutil::day_to_nd;
+use crate::dbschema;
+use crate::dbutil::open_pool;
+use crate::loader::{Encoding, Fetcher, HeaderDrift, LoadContext, LoadStats, Registry, Source};
+use crate::progress::{Event, Observer, Progress};
+use crate::{Error, Result};
+
+/// What a dry run found in one source.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceReport {
+    pub source: String,
+    /// What was parsed from each file, by filename.
+    pub files: Vec<(String, LoadStats)>,
+    /// How the columns of the source's files differ from those its loader knows.
+    pub drift: Vec<HeaderDrift>,
+    /// Why the source could not be loaded, if it couldn't.
+    pub error: Option<String>,
+}
+
+/// The outcome of [`Registry::dry_run`], with a report for each selected source.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DryRunReport {
+    pub sources: Vec<SourceReport>,
+}
+
+impl DryRunReport {
+    /// True if every source could be loaded.
+    pub fn passed(&self) -> bool {
+        self.sources.iter().all(|x| x.error.is_none())
+    }
+}
+
+impl fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for source in &self.sources {
+            writeln!(f, "{}:", source.source)?;
+            for (filename, stats) in &source.files {
+                write!(
+                    f,
+                    "  {}: {} records parsed, {} rows, {} skipped, {} rejected",
+                    filename,
+                    stats.parsed,
+                    stats.inserted,
+                    stats.skipped,
+                    stats.rejected()
+                )?;
+                if let (Some(min), Some(max)) = (stats.min_date_julian, stats.max_date_julian) {
+                    write!(f, ", {} to {}", day_to_nd(min), day_to_nd(max))?;
+                }
+                writeln!(f)?;
+            }
+            for drift in &source.drift {
+                writeln!(f, "  columns changed: {}", drift)?;
+            }
+            if let Some(error) = &source.error {
+                writeln!(f, "  FAILED: {}", error)?;
+            }
+        }
+        let failed = self.sources.iter().filter(|x| x.error.is_some()).count();
+        write!(
+            f,
+            "{} sources parsed, {} failed",
+            self.sources.len() - failed,
+            failed
+        )
+    }
+}
+
+/// Passes events on, keeping the header drift reported.
+struct DriftCollector {
+    progress: Progress,
+    drift: Mutex<Vec<(String, HeaderDrift)>>,
+}
+
+impl DriftCollector {
+    /// Removes and returns the drift reported for `source`.
+    fn take(&self, source: &str) -> Vec<HeaderDrift> {
+        let mut drift = self.drift.lock().unwrap();
+        let (taken, kept) = drift.drain(..).partition(|(x, _)| x == source);
+        *drift = kept;
+        taken.into_iter().map(|(_, x)| x).collect()
+    }
+}
+
+impl Observer for DriftCollector {
+    fn event(&self, event: &Event) {
+        if let Event::HeaderDrift { source, drift } = event {
+            self.drift
+                .lock()
+                .unwrap()
+                .push((source.clone(), drift.clone()));
+        }
+        self.progress.event(event.clone());
+    }
+}
+
+impl Registry {
+    /** Fetches and parses the files of the sources selected in `opts`, as
+    [`load`](Registry::load) would, counting the rows, rejects and dates
+    found, but leaving `opts.output` untouched.  The loaders are run with
+    [`LoadContext::dry_run`] set, so the rows of the data tables are counted
+    rather than inserted; only the schema and the small lookup tables, such
+    as `loc_lookup` and `cdataset_loc`, are written, to a scratch database
+    that is then thrown away.
+
+    The files are still fetched in full into a temporary directory, and
+    compressed ones decompressed there, so a dry run needs as much space as
+    the source files take, most of it for the `cdataset` database, and takes
+    about as long as fetching and reading them.  What a full build spends on
+    inserting and indexing rows, and the space for the output, is saved.
+
+    A source that fails to load, whether because its files couldn't be
+    fetched, its headers lack required columns, or too many records were
+    rejected, is reported, and the dry run goes on to the rest.  Sources that
+    depend on a failed one are not loaded.  The checks are not run. */
+    pub async fn dry_run(&self, opts: &LoadOptions) -> Result<DryRunReport> {
+        let sources = self.select(opts)?;
+        let scratch = tempdir()?;
+        let fetcher = Fetcher::new(opts.input_dir.clone(), opts.download(), scratch.path());
+        let collector = Arc::new(DriftCollector {
+            progress: opts.progress.clone(),
+            drift: Mutex::new(Vec::new()),
+        });
+        let ctx = LoadContext {
+            progress: Progress::from(collector.clone()),
+            subset: opts.subset.clone(),
+            dry_run: true,
+            encoding: Encoding::default(),
+            source: None,
+            locids_from: None,
+        };
+
+        let mut pool = open_pool(&scratch.path().join("dry-run.db"), 1).await?;
+        let mut queries = dbschema::meta_schema();
+        for source in self.iter() {
+            queries.extend(source.schema());
+        }
+        dbschema::execute_all(&mut pool.acquire().await?, &queries, &Progress::silent()).await?;
+
+        let fetched = join_all(sources.iter().map(|x| x.fetch(&fetcher))).await;
+        let mut report = DryRunReport::default();
+        let mut failed: Vec<&str> = Vec::new();
+        for (&source, files) in sources.iter().zip(fetched) {
+            let result = match source.dependencies().iter().find(|x| failed.contains(x)) {
+                Some(dep) => Err(Error::Config(format!("not loaded, since {} failed", dep))),
+                None => match files {
+                    Ok(files) => {
+                        opts.progress.event(Event::SourceStarted {
+                            source: String::from(source.name()),
+                        });
+                        load(source, &files, &mut pool, &ctx, opts).await
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            let mut sr = SourceReport {
+                source: String::from(source.name()),
+                drift: collector.take(source.name()),
+                ..SourceReport::default()
+            };
+            match result {
+                Ok(stats) => {
+                    let names = source.files().into_iter().map(|x| x.filename);
+                    sr.files = names.zip(stats).collect();
+                }
+                Err(e) => {
+                    failed.push(source.name());
+                    sr.error = Some(e.to_string());
+                }
+            }
+            report.sources.push(sr);
+        }
+        pool.close().await;
+        Ok(report)
+    }
+}
+
+async fn load(
+    source: &dyn Source,
+    files: &[std::path::PathBuf],
+    pool: &mut sqlx::sqlite::SqlitePool,
+    ctx: &LoadContext,
+    opts: &LoadOptions,
+) -> Result<Vec<LoadStats>> {
+    let ctx = LoadContext {
+        encoding: source.encoding(),
+        ..ctx.clone()
+    };
+    let stats = source.load(files, pool, &ctx).await?;
+    check_rejects(source, &stats, opts)?;
+    warn_replacements(source, &stats, &opts.progress);
+    Ok(stats)
+}
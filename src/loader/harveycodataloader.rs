@@ -18,15 +18,37 @@ Copyright (c) 2019-2020 John Goerzen
 
 pub use crate::dateutil::*;
 pub use crate::dbschema::*;
+use crate::dbutil::*;
+use crate::loader::config::builtin_files;
 pub use crate::loader::parseutil::*;
+use crate::loader::source::{Coverage, LoadContext, LoadStats, Source, SourceFile};
+use crate::loader::subset::Place;
+use crate::Result;
+use async_trait::async_trait;
 use chrono::NaiveDate;
 use csv;
 use serde::Deserialize;
-use sqlx::{Query, Transaction};
+use sqlx::sqlite::SqlitePool;
+use sqlx::Query;
+use std::fs::File;
+use std::path::PathBuf;
+
+const COLUMNS: &[&str] = &[
+    "date",
+    "kdhe_neg_results",
+    "kdhe_pos_results",
+    "harveyco_tot_results",
+    "harveyco_pos_results",
+    "harveyco_confirmed",
+    "harveyco_recovered",
+];
+
+const REQUIRED_COLUMNS: &[&str] = &["date"];
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct HarveyCountyRecord {
-    pub date: String,
+    #[serde(deserialize_with = "date_from_str")]
+    pub date: NaiveDate,
     pub kdhe_neg_results: Option<i64>,
     pub kdhe_pos_results: Option<i64>,
     pub harveyco_tot_results: Option<i64>,
@@ -38,9 +60,7 @@ pub struct HarveyCountyRecord {
 impl HarveyCountyRecord {
     pub fn bind_query<'q>(self, query: Query<'q, sqlx::Sqlite>) -> Query<'q, sqlx::Sqlite> {
         query
-            .bind(nd_to_day(
-                &NaiveDate::parse_from_str(self.date.as_str(), "%Y-%m-%d").unwrap(),
-            ))
+            .bind(nd_to_day(&self.date))
             .bind(self.kdhe_neg_results)
             .bind(self.kdhe_pos_results)
             .bind(self.harveyco_tot_results)
@@ -54,38 +74,86 @@ impl HarveyCountyRecord {
     }
 }
 
-pub fn parse_to_final<A: Iterator<Item = csv::StringRecord>>(
+pub fn parse_to_final<'a, A: Iterator<Item = Result<csv::StringRecord>> + 'a>(
+    source: &'a str,
+    headers: &'a csv::StringRecord,
     striter: A,
-) -> impl Iterator<Item = HarveyCountyRecord> {
-    striter.filter_map(|x| rec_to_struct(&x).expect("rec_to_struct"))
+) -> impl Iterator<Item = Result<HarveyCountyRecord>> + 'a {
+    striter.filter_map(move |x| {
+        x.and_then(|rec| rec_to_struct(source, headers, &rec))
+            .transpose()
+    })
 }
 
-/** Parse the CSV, loading it into the database.
- * Will panic on parse error.  */
-pub async fn load<'a, A: std::io::Read>(
-    rdr: &'a mut csv::Reader<A>,
-    mut transaction: Transaction<sqlx::pool::PoolConnection<sqlx::SqliteConnection>>,
-) {
-    assert_eq!(
-        vec![
-            "date",
-            "kdhe_neg_results",
-            "kdhe_pos_results",
-            "harveyco_tot_results",
-            "harveyco_pos_results",
-            "harveyco_confirmed",
-            "harveyco_recovered",
-        ],
-        rdr.headers().unwrap().iter().collect::<Vec<&str>>()
-    );
-    let recs = parse_records(rdr.byte_records());
-    let finaliter = parse_to_final(recs);
-    for rec in finaliter {
-        let query = sqlx::query(HarveyCountyRecord::insert_str());
-        rec.bind_query(query)
-            .execute(&mut transaction)
-            .await
-            .unwrap();
+/// Harvey County, Kansas testing data, in `harveycodata`.
+pub struct HarveyCoDataSource;
+
+#[async_trait]
+impl Source for HarveyCoDataSource {
+    fn name(&self) -> &str {
+        "harveycodata"
+    }
+
+    fn files(&self) -> Vec<SourceFile> {
+        builtin_files(self.name())
+    }
+
+    fn schema(&self) -> Vec<String> {
+        harveycodata_schema()
+    }
+
+    fn coverage(&self) -> Option<Coverage> {
+        Some(Coverage::new("harveycodata_raw", "'Harvey, Kansas'"))
+    }
+
+    /// Parse the CSV, loading it into the database.
+    async fn load(
+        &self,
+        files: &[PathBuf],
+        pool: &mut SqlitePool,
+        ctx: &LoadContext,
+    ) -> Result<Vec<LoadStats>> {
+        let name = ctx.source_name(self.name());
+        let mut rdr = parse_init_file(File::open(&files[0])?)?;
+        let transcoder = Transcoder::new(ctx.encoding);
+        let headers = check_headers(
+            name,
+            COLUMNS,
+            REQUIRED_COLUMNS,
+            &mut rdr,
+            &transcoder,
+            &ctx.progress,
+        )?;
+        let mut transaction = pool.begin().await?;
+        record_headers(&mut transaction, name, &headers).await?;
+        let recs = parse_records(name, &transcoder, rdr.byte_records());
+        let finaliter = parse_to_final(name, &headers, recs);
+        let mut inserter = BulkInsert::new(
+            HarveyCountyRecord::insert_str(),
+            HarveyCountyRecord::bind_query,
+        )
+        .discard(ctx.dry_run);
+        let mut stats = LoadStats::default();
+        for rec in finaliter {
+            let rec = match rec {
+                Ok(rec) => rec,
+                Err(e) => {
+                    stats.reject(e)?;
+                    continue;
+                }
+            };
+            let place = Place::us_county("Kansas", Some(20079));
+            if !ctx.subset.keep(&rec.date, &place) {
+                continue;
+            }
+            stats.parsed += 1;
+            stats.date(nd_to_day(&rec.date));
+            inserter.push(rec, &mut transaction).await?;
+        }
+        stats.inserted = inserter.finish(&mut transaction).await?;
+        stats.replacements = transcoder.replacements();
+        stats.skipped = records_read(&rdr) - stats.parsed - stats.rejected();
+        transaction.commit().await?;
+        Ok(vec![stats])
     }
-    transaction.commit().await.unwrap();
 }
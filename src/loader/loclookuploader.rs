@@ -16,98 +16,254 @@ Copyright (c) 2019-2020 John Goerzen
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use crate::dbschema::*;
+use crate::dbutil::*;
+use crate::loader::config::builtin_files;
 pub use crate::loader::parseutil::*;
+use crate::loader::source::{LoadContext, LoadStats, Source, SourceFile};
+use crate::loader::subset::Place;
+use crate::Result;
+use async_trait::async_trait;
 use csv;
 use serde::Deserialize;
-use sqlx::Transaction;
+use sqlx::prelude::*;
+use sqlx::sqlite::SqlitePool;
+use sqlx::Query;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fs::File;
+use std::path::PathBuf;
+
+const COLUMNS: &[&str] = &[
+    "UID",
+    "iso2",
+    "iso3",
+    "code3",
+    "FIPS",
+    "Admin2",
+    "Province_State",
+    "Country_Region",
+    "Lat",
+    "Long_",
+    "Combined_Key",
+    "Population",
+];
+
+const REQUIRED_COLUMNS: &[&str] = &[
+    "UID",
+    "iso2",
+    "iso3",
+    "Admin2",
+    "Province_State",
+    "Country_Region",
+    "Combined_Key",
+];
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct LocRecord {
+    #[serde(rename = "UID")]
     pub uid: u32,
     pub iso2: String,
     pub iso3: String,
     pub code3: Option<u32>,
+    #[serde(rename = "FIPS")]
     pub fips: Option<u32>,
+    #[serde(rename = "Admin2")]
     pub admin2: String,
+    #[serde(rename = "Province_State")]
     pub province_state: String,
+    #[serde(rename = "Country_Region")]
     pub country_region: String,
+    #[serde(rename = "Lat")]
     pub lat: Option<f64>,
+    #[serde(rename = "Long_")]
     pub lon: Option<f64>,
+    #[serde(rename = "Combined_Key")]
     pub combined_key: String,
-    pub population: Option<u64>,
-}
-
-pub fn parse_to_final<A: Iterator<Item = csv::StringRecord>>(
-    striter: A,
-) -> impl Iterator<Item = LocRecord> {
-    striter.filter_map(|x| rec_to_struct(&x).expect("rec_to_struct"))
+    #[serde(rename = "Population")]
+    pub population: Option<i64>,
 }
 
-/** Parse the CSV, loading it into the database, and returning a hashmap of fips to population.
- * Will panic on parse error.  */
-pub async fn load<'a, A: std::io::Read>(
-    rdr: &'a mut csv::Reader<A>,
-    mut transaction: Transaction<sqlx::pool::PoolConnection<sqlx::SqliteConnection>>,
-) -> HashMap<u32, u64> {
-    assert_eq!(
-        vec![
-            "UID",
-            "iso2",
-            "iso3",
-            "code3",
-            "FIPS",
-            "Admin2",
-            "Province_State",
-            "Country_Region",
-            "Lat",
-            "Long_",
-            "Combined_Key",
-            "Population"
-        ],
-        rdr.headers().unwrap().iter().collect::<Vec<&str>>()
-    );
-    let recs = parse_records(rdr.byte_records());
-    let finaliter = parse_to_final(recs);
-    let mut hm = HashMap::new();
-    for rec in finaliter {
-        match (rec.fips, rec.population) {
-            (Some(fipsi), Some(popi)) => {
-                hm.insert(fipsi, popi);
-            }
-            _ => (),
-        }
-        let query =
-            sqlx::query("INSERT INTO loc_lookup VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)");
+impl LocRecord {
+    pub fn bind_query<'q>(self, query: Query<'q, sqlx::Sqlite>) -> Query<'q, sqlx::Sqlite> {
         query
-            .bind(i64::from(rec.uid))
-            .bind(rec.iso2)
-            .bind(rec.iso3)
-            .bind(rec.code3.map(i64::from))
-            .bind(rec.fips.map(i64::from))
-            .bind(if rec.admin2.len() == 0 {
+            .bind(i64::from(self.uid))
+            .bind(self.iso2)
+            .bind(self.iso3)
+            .bind(self.code3.map(i64::from))
+            .bind(self.fips.map(i64::from))
+            .bind(if self.admin2.is_empty() {
                 None
             } else {
-                Some(rec.admin2)
+                Some(self.admin2)
             })
-            .bind(if rec.province_state.len() == 0 {
+            .bind(if self.province_state.is_empty() {
                 None
             } else {
-                Some(rec.province_state)
+                Some(self.province_state)
             })
-            .bind(rec.country_region)
-            .bind(rec.lat)
-            .bind(rec.lon)
-            .bind(rec.combined_key)
-            .bind(
-                rec.population
-                    .map(|x| i64::try_from(x).expect("population range")),
-            )
-            .execute(&mut transaction)
-            .await
-            .unwrap();
+            .bind(self.country_region)
+            .bind(self.lat)
+            .bind(self.lon)
+            .bind(self.combined_key)
+            .bind(self.population)
+    }
+
+    pub fn insert_str() -> &'static str {
+        "INSERT INTO loc_lookup VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    }
+}
+
+pub fn parse_to_final<'a, A: Iterator<Item = Result<csv::StringRecord>> + 'a>(
+    source: &'a str,
+    headers: &'a csv::StringRecord,
+    striter: A,
+) -> impl Iterator<Item = Result<LocRecord>> + 'a {
+    striter.filter_map(move |x| {
+        x.and_then(|rec| rec_to_struct(source, headers, &rec))
+            .transpose()
+    })
+}
+
+/** Returns a map of FIPS code to population, taken from the `loc_lookup` table. */
+pub async fn fips_populations(pool: &SqlitePool) -> Result<HashMap<u32, u64>> {
+    let mut conn = pool.acquire().await?;
+    let rows: Vec<(i64, i64)> = sqlx::query_as(
+        "SELECT fips, population FROM loc_lookup
+         WHERE fips IS NOT NULL AND population IS NOT NULL ORDER BY uid",
+    )
+    .fetch_all(&mut conn)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(fips, pop)| Some((u32::try_from(fips).ok()?, u64::try_from(pop).ok()?)))
+        .collect())
+}
+
+/** The Johns Hopkins UIDs of the locations in the `loc_lookup` table, by FIPS
+code and by country code, province or state, and county or other second-level
+division, for giving locations from other sources identifiers that match. */
+#[derive(Clone, Debug, Default)]
+pub struct JhuUids {
+    by_fips: HashMap<u32, i64>,
+    by_name: HashMap<(String, String, String), i64>,
+}
+
+impl JhuUids {
+    /// Reads the UIDs from `loc_lookup`.  Where names repeat, the lowest UID wins.
+    pub async fn load(pool: &SqlitePool) -> Result<Self> {
+        let mut conn = pool.acquire().await?;
+        let mut cursor = sqlx::query(
+            "SELECT uid, iso2, fips, province_state, admin2 FROM loc_lookup ORDER BY uid",
+        )
+        .fetch(&mut conn);
+        let mut uids = JhuUids::default();
+        while let Some(row) = cursor.next().await? {
+            let uid: i64 = row.try_get("uid")?;
+            let fips: Option<i64> = row.try_get("fips")?;
+            if let Some(fips) = fips.and_then(|x| u32::try_from(x).ok()) {
+                uids.by_fips.entry(fips).or_insert(uid);
+            }
+            let name = (
+                row.try_get("iso2")?,
+                row.try_get::<Option<String>, &str>("province_state")?
+                    .unwrap_or_default(),
+                row.try_get::<Option<String>, &str>("admin2")?
+                    .unwrap_or_default(),
+            );
+            uids.by_name.entry(name).or_insert(uid);
+        }
+        Ok(uids)
+    }
+
+    /** Returns the UID of the county with FIPS code `fips`, if given, or else
+    of the place named by `country_code`, `province` and `administrative`,
+    which are empty for a whole country or province. */
+    pub fn find(
+        &self,
+        fips: Option<u32>,
+        country_code: &str,
+        province: &str,
+        administrative: &str,
+    ) -> Option<i64> {
+        match fips {
+            Some(fips) => self.by_fips.get(&fips).copied(),
+            None => {
+                let name = (
+                    String::from(country_code),
+                    String::from(province),
+                    String::from(administrative),
+                );
+                self.by_name.get(&name).copied()
+            }
+        }
+    }
+}
+
+/// The Johns Hopkins location and population lookup table, in `loc_lookup`.
+pub struct LocLookupSource;
+
+#[async_trait]
+impl Source for LocLookupSource {
+    fn name(&self) -> &str {
+        "loc_lookup"
+    }
+
+    fn files(&self) -> Vec<SourceFile> {
+        builtin_files(self.name())
+    }
+
+    fn schema(&self) -> Vec<String> {
+        loc_lookup_schema()
+    }
+
+    /// Parse the CSV, loading it into the database.
+    async fn load(
+        &self,
+        files: &[PathBuf],
+        pool: &mut SqlitePool,
+        ctx: &LoadContext,
+    ) -> Result<Vec<LoadStats>> {
+        let name = ctx.source_name(self.name());
+        let mut rdr = parse_init_file(File::open(&files[0])?)?;
+        let transcoder = Transcoder::new(ctx.encoding);
+        let headers = check_headers(
+            name,
+            COLUMNS,
+            REQUIRED_COLUMNS,
+            &mut rdr,
+            &transcoder,
+            &ctx.progress,
+        )?;
+        let mut transaction = pool.begin().await?;
+        record_headers(&mut transaction, name, &headers).await?;
+        let recs = parse_records(name, &transcoder, rdr.byte_records());
+        let finaliter = parse_to_final(name, &headers, recs);
+        let mut inserter = BulkInsert::new(LocRecord::insert_str(), LocRecord::bind_query);
+        let mut stats = LoadStats::default();
+        for rec in finaliter {
+            let rec = match rec {
+                Ok(rec) => rec,
+                Err(e) => {
+                    stats.reject(e)?;
+                    continue;
+                }
+            };
+            let place = Place {
+                country: Some(&rec.iso2),
+                state: Some(&rec.province_state),
+                fips: rec.fips.map(i64::from),
+            };
+            if !ctx.subset.keep_place(&place) {
+                continue;
+            }
+            inserter.push(rec, &mut transaction).await?;
+            stats.parsed += 1;
+        }
+        stats.inserted = inserter.finish(&mut transaction).await?;
+        stats.replacements = transcoder.replacements();
+        stats.skipped = records_read(&rdr) - stats.parsed - stats.rejected();
+        transaction.commit().await?;
+        Ok(vec![stats])
     }
-    transaction.commit().await.unwrap();
-    hm
 }
@@ -0,0 +1,174 @@
+/* Build manifests
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::prelude::*;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::dbutil::open_pool;
+use crate::{Error, Result};
+
+/// A source file that went into a database.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ManifestInput {
+    pub source: String,
+    pub filename: String,
+    pub url: String,
+    pub revision: Option<String>,
+    /// The SHA-256 of the file as fetched, in hex.
+    pub sha256: String,
+    pub bytes: u64,
+}
+
+/** The hashes of a database and of the source files it was built from, as
+written next to it by [`Registry::load`](crate::loader::Registry::load) in
+`<output>.manifest.json`.  Since builds from the same files with the same
+[`LoadOptions::timestamp`](crate::loader::LoadOptions::timestamp) are
+byte-identical, anyone with the inputs can check that a published database
+was built from them.  A build that kept the location ids of an earlier
+database, as [`LoadOptions::locids_from`](crate::loader::LoadOptions::locids_from)
+allows, depends on that database too, so its hash is listed as well. */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The SHA-256 of the database, in hex.
+    pub sha256: String,
+    pub bytes: u64,
+    /// The source files, ordered by source and filename.
+    pub inputs: Vec<ManifestInput>,
+    /// The SHA-256 of the database whose location ids were kept, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locids_from: Option<String>,
+}
+
+impl Manifest {
+    /// Hashes the database at `db` and lists the files recorded in its
+    /// `covid19db_sources` table, and the database recorded as `locids_from`
+    /// in its `covid19db_meta`.
+    pub async fn of(db: &Path) -> Result<Self> {
+        let (sha256, bytes) = hash_file(db)?;
+        let pool = open_pool(db, 1).await?;
+        let mut conn = pool.acquire().await?;
+        let rows: Vec<(String, String, String, Option<String>, String, i64)> = sqlx::query_as(
+            "SELECT source, filename, url, revision, sha256, bytes FROM covid19db_sources
+             ORDER BY source, filename",
+        )
+        .fetch_all(&mut conn)
+        .await?;
+        let locids_from: Option<(String,)> =
+            sqlx::query_as("SELECT value FROM covid19db_meta WHERE field = 'locids_from'")
+                .fetch_optional(&mut conn)
+                .await?;
+        drop(conn);
+        pool.close().await;
+        let inputs = rows
+            .into_iter()
+            .map(
+                |(source, filename, url, revision, sha256, bytes)| ManifestInput {
+                    source,
+                    filename,
+                    url,
+                    revision,
+                    sha256,
+                    bytes: bytes as u64,
+                },
+            )
+            .collect();
+        Ok(Manifest {
+            sha256,
+            bytes,
+            inputs,
+            locids_from: locids_from.map(|x| x.0),
+        })
+    }
+
+    /// Where the manifest of the database at `db` is written.
+    pub fn path(db: &Path) -> PathBuf {
+        let mut path = db.to_owned().into_os_string();
+        path.push(".manifest.json");
+        PathBuf::from(path)
+    }
+
+    /// Reads a manifest written by [`write`](Manifest::write).
+    pub fn from_file(path: &Path) -> Result<Self> {
+        serde_json::from_reader(File::open(path)?)
+            .map_err(|e| Error::Config(format!("{}: invalid manifest: {}", path.display(), e)))
+    }
+
+    /// Writes the manifest to `path` as JSON.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut text = serde_json::to_string_pretty(self).expect("manifest serializes");
+        text.push('\n');
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    /** Describes each way `other`, typically the manifest of a database
+    being checked, differs from this one; empty if they match. */
+    pub fn differences(&self, other: &Manifest) -> Vec<String> {
+        let mut found = Vec::new();
+        if (&self.sha256, self.bytes) != (&other.sha256, other.bytes) {
+            found.push(format!(
+                "the database has SHA-256 {} ({} bytes), not {} ({} bytes)",
+                other.sha256, other.bytes, self.sha256, self.bytes
+            ));
+        }
+        if self.locids_from != other.locids_from {
+            let describe = |x: &Option<String>| match x {
+                Some(sha256) => format!("those of the database with SHA-256 {}", sha256),
+                None => String::from("none"),
+            };
+            found.push(format!(
+                "the database kept {} as location ids, not {}",
+                describe(&other.locids_from),
+                describe(&self.locids_from)
+            ));
+        }
+        let key = |x: &ManifestInput| (x.source.clone(), x.filename.clone());
+        for input in &self.inputs {
+            match other.inputs.iter().find(|x| key(x) == key(input)) {
+                None => found.push(format!(
+                    "{} {} is not in the database",
+                    input.source, input.filename
+                )),
+                Some(x) if x != input => found.push(format!(
+                    "{} {} has SHA-256 {}, not {}",
+                    input.source, input.filename, x.sha256, input.sha256
+                )),
+                Some(_) => (),
+            }
+        }
+        for input in &other.inputs {
+            if !self.inputs.iter().any(|x| key(x) == key(input)) {
+                found.push(format!(
+                    "{} {} is not in the manifest",
+                    input.source, input.filename
+                ));
+            }
+        }
+        found
+    }
+}
+
+/// Returns the SHA-256 of the file at `path`, in hex, and its size in bytes.
+pub(crate) fn hash_file(path: &Path) -> Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let bytes = std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok((format!("{:x}", hasher.finalize()), bytes))
+}
@@ -18,17 +18,30 @@ Copyright (c) 2019-2020 John Goerzen
 
 pub use crate::dateutil::*;
 pub use crate::dbschema::*;
+use crate::dbutil::BulkInsert;
+use crate::loader::config::builtin_files;
 pub use crate::loader::parseutil::*;
+use crate::loader::source::{Coverage, LoadContext, LoadStats, Source, SourceFile};
+use crate::loader::subset::Place;
+use crate::progress::Event;
+use crate::Result;
+use async_trait::async_trait;
 use chrono::NaiveDate;
 use csv;
 use serde::Deserialize;
-use sqlx::{Query, Transaction};
-use std::io;
-use std::io::Write;
+use sqlx::sqlite::SqlitePool;
+use sqlx::Query;
+use std::fs::File;
+use std::path::PathBuf;
+
+const COLUMNS: &[&str] = &["date", "county", "state", "fips", "cases", "deaths"];
+
+const REQUIRED_COLUMNS: &[&str] = &["date", "county", "state", "cases"];
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct NYTCountyRecord {
-    pub date: String,
+    #[serde(deserialize_with = "date_from_str")]
+    pub date: NaiveDate,
     pub county: String,
     pub state: String,
     pub fips: Option<i64>,
@@ -39,9 +52,7 @@ pub struct NYTCountyRecord {
 impl NYTCountyRecord {
     pub fn bind_query<'q>(self, query: Query<'q, sqlx::Sqlite>) -> Query<'q, sqlx::Sqlite> {
         query
-            .bind(nd_to_day(
-                &NaiveDate::parse_from_str(self.date.as_str(), "%Y-%m-%d").unwrap(),
-            ))
+            .bind(nd_to_day(&self.date))
             .bind(self.county)
             .bind(self.state)
             .bind(self.fips)
@@ -54,47 +65,91 @@ impl NYTCountyRecord {
     }
 }
 
-pub fn parse_to_final<A: Iterator<Item = csv::StringRecord>>(
+pub fn parse_to_final<'a, A: Iterator<Item = Result<csv::StringRecord>> + 'a>(
+    source: &'a str,
+    headers: &'a csv::StringRecord,
     striter: A,
-) -> impl Iterator<Item = NYTCountyRecord> {
-    striter.filter_map(|x| rec_to_struct(&x).expect("rec_to_struct"))
+) -> impl Iterator<Item = Result<NYTCountyRecord>> + 'a {
+    striter.filter_map(move |x| {
+        x.and_then(|rec| rec_to_struct(source, headers, &rec))
+            .transpose()
+    })
 }
 
-/** Parse the CSV, loading it into the database.
- * Will panic on parse error.  */
-pub async fn load<'a, A: std::io::Read>(
-    rdr: &'a mut csv::Reader<A>,
-    mut transaction: Transaction<sqlx::pool::PoolConnection<sqlx::SqliteConnection>>,
-) {
-    assert_eq!(
-        vec![
-            "date",
-            "county",
-            "state",
-            "fips",
-            "cases",
-            "deaths",
-        ],
-        rdr.headers().unwrap().iter().collect::<Vec<&str>>()
-    );
-    let recs = parse_records(rdr.byte_records());
-    let finaliter = parse_to_final(recs);
-    let mut processedrecs: i64 = 0;
-    for rec in finaliter {
-        let query = sqlx::query(NYTCountyRecord::insert_str());
-        rec.bind_query(query)
-            .execute(&mut transaction)
-            .await
-            .unwrap();
-        processedrecs += 1;
-        if processedrecs % 100000 == 0 {
-            print!(
-                "Processed {} records\r",
-                processedrecs,
-            );
-            io::stdout().flush().unwrap();
+/// The New York Times county-level data, in `nytcounties`.
+pub struct NYTCountiesSource;
+
+#[async_trait]
+impl Source for NYTCountiesSource {
+    fn name(&self) -> &str {
+        "nytcounties"
+    }
+
+    fn files(&self) -> Vec<SourceFile> {
+        builtin_files(self.name())
+    }
+
+    fn schema(&self) -> Vec<String> {
+        nytcounties_schema()
+    }
+
+    fn coverage(&self) -> Option<Coverage> {
+        Some(Coverage::new("nytcounties_raw", "county || ', ' || state"))
+    }
+
+    /// Parse the CSV, loading it into the database.
+    async fn load(
+        &self,
+        files: &[PathBuf],
+        pool: &mut SqlitePool,
+        ctx: &LoadContext,
+    ) -> Result<Vec<LoadStats>> {
+        let name = ctx.source_name(self.name());
+        let mut rdr = parse_init_file(File::open(&files[0])?)?;
+        let transcoder = Transcoder::new(ctx.encoding);
+        let headers = check_headers(
+            name,
+            COLUMNS,
+            REQUIRED_COLUMNS,
+            &mut rdr,
+            &transcoder,
+            &ctx.progress,
+        )?;
+        let mut transaction = pool.begin().await?;
+        record_headers(&mut transaction, name, &headers).await?;
+        let recs = parse_records(name, &transcoder, rdr.byte_records());
+        let finaliter = parse_to_final(name, &headers, recs);
+        let mut inserter =
+            BulkInsert::new(NYTCountyRecord::insert_str(), NYTCountyRecord::bind_query)
+                .discard(ctx.dry_run);
+        let mut stats = LoadStats::default();
+        for rec in finaliter {
+            let rec = match rec {
+                Ok(rec) => rec,
+                Err(e) => {
+                    stats.reject(e)?;
+                    continue;
+                }
+            };
+            let place = Place::us_county(&rec.state, rec.fips);
+            if !ctx.subset.keep(&rec.date, &place) {
+                continue;
+            }
+            stats.parsed += 1;
+            stats.date(nd_to_day(&rec.date));
+            inserter.push(rec, &mut transaction).await?;
+            if stats.parsed % 100000 == 0 {
+                ctx.progress.event(Event::Rows {
+                    source: String::from(name),
+                    processed: stats.parsed,
+                    total: None,
+                });
+            }
         }
+        stats.inserted = inserter.finish(&mut transaction).await?;
+        stats.replacements = transcoder.replacements();
+        stats.skipped = records_read(&rdr) - stats.parsed - stats.rejected();
+        transaction.commit().await?;
+        Ok(vec![stats])
     }
-    println!("Processed {} records; now committing...", processedrecs);
-    transaction.commit().await.unwrap();
 }
@@ -18,18 +18,92 @@ Copyright (c) 2019-2020 John Goerzen
 
 pub use crate::dateutil::*;
 pub use crate::dbschema::*;
+use crate::dbutil::BulkInsert;
+use crate::loader::config::builtin_files;
 pub use crate::loader::parseutil::*;
+use crate::loader::source::{Coverage, LoadContext, LoadStats, Source, SourceFile};
+use crate::loader::subset::Place;
+use crate::Result;
+use async_trait::async_trait;
 use chrono::NaiveDate;
 use csv;
 use serde::Deserialize;
-use sqlx::Transaction;
+use sqlx::sqlite::SqlitePool;
+use std::fs::File;
+use std::path::PathBuf;
+
+const COLUMNS: &[&str] = &[
+    "iso_code",
+    "continent",
+    "location",
+    "date",
+    "total_cases",
+    "new_cases",
+    "new_cases_smoothed",
+    "total_deaths",
+    "new_deaths",
+    "new_deaths_smoothed",
+    "total_cases_per_million",
+    "new_cases_per_million",
+    "new_cases_smoothed_per_million",
+    "total_deaths_per_million",
+    "new_deaths_per_million",
+    "new_deaths_smoothed_per_million",
+    "reproduction_rate",
+    "icu_patients",
+    "icu_patients_per_million",
+    "hosp_patients",
+    "hosp_patients_per_million",
+    "weekly_icu_admissions",
+    "weekly_icu_admissions_per_million",
+    "weekly_hosp_admissions",
+    "weekly_hosp_admissions_per_million",
+    "new_tests",
+    "total_tests",
+    "total_tests_per_thousand",
+    "new_tests_per_thousand",
+    "new_tests_smoothed",
+    "new_tests_smoothed_per_thousand",
+    "positive_rate",
+    "tests_per_case",
+    "tests_units",
+    "total_vaccinations",
+    "people_vaccinated",
+    "people_fully_vaccinated",
+    "new_vaccinations",
+    "new_vaccinations_smoothed",
+    "total_vaccinations_per_hundred",
+    "people_vaccinated_per_hundred",
+    "people_fully_vaccinated_per_hundred",
+    "new_vaccinations_smoothed_per_million",
+    "stringency_index",
+    "population",
+    "population_density",
+    "median_age",
+    "aged_65_older",
+    "aged_70_older",
+    "gdp_per_capita",
+    "extreme_poverty",
+    "cardiovasc_death_rate",
+    "diabetes_prevalence",
+    "female_smokers",
+    "male_smokers",
+    "handwashing_facilities",
+    "hospital_beds_per_thousand",
+    "life_expectancy",
+    "human_development_index",
+    "excess_mortality",
+];
+
+const REQUIRED_COLUMNS: &[&str] = &["location", "date"];
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct OWIDRecord {
     pub iso_code: Option<String>,
     pub continent: Option<String>,
     pub location: String,
-    pub date: String,
+    #[serde(deserialize_with = "date_from_str")]
+    pub date: NaiveDate,
     pub total_cases: Option<f64>,
     pub new_cases: Option<f64>,
     pub new_cases_smoothed: Option<f64>,
@@ -88,155 +162,148 @@ pub struct OWIDRecord {
     pub excess_mortality: Option<f64>,
 }
 
-pub fn parse_to_final<A: Iterator<Item = csv::StringRecord>>(
+pub fn parse_to_final<'a, A: Iterator<Item = Result<csv::StringRecord>> + 'a>(
+    source: &'a str,
+    headers: &'a csv::StringRecord,
     striter: A,
-) -> impl Iterator<Item = OWIDRecord> {
-    striter.filter_map(|x| rec_to_struct(&x).expect("rec_to_struct"))
+) -> impl Iterator<Item = Result<OWIDRecord>> + 'a {
+    striter.filter_map(move |x| {
+        x.and_then(|rec| rec_to_struct(source, headers, &rec))
+            .transpose()
+    })
 }
 
-/** Parse the CSV, loading it into the database.
- * Will panic on parse error.  */
-pub async fn load<'a, A: std::io::Read>(
-    rdr: &'a mut csv::Reader<A>,
-    mut transaction: Transaction<sqlx::pool::PoolConnection<sqlx::SqliteConnection>>,
-) {
-    assert_eq!(
-        vec![
-            "iso_code",
-            "continent",
-            "location",
-            "date",
-            "total_cases",
-            "new_cases",
-            "new_cases_smoothed",
-            "total_deaths",
-            "new_deaths",
-            "new_deaths_smoothed",
-            "total_cases_per_million",
-            "new_cases_per_million",
-            "new_cases_smoothed_per_million",
-            "total_deaths_per_million",
-            "new_deaths_per_million",
-            "new_deaths_smoothed_per_million",
-            "reproduction_rate",
-            "icu_patients",
-            "icu_patients_per_million",
-            "hosp_patients",
-            "hosp_patients_per_million",
-            "weekly_icu_admissions",
-            "weekly_icu_admissions_per_million",
-            "weekly_hosp_admissions",
-            "weekly_hosp_admissions_per_million",
-            "new_tests",
-            "total_tests",
-            "total_tests_per_thousand",
-            "new_tests_per_thousand",
-            "new_tests_smoothed",
-            "new_tests_smoothed_per_thousand",
-            "positive_rate",
-            "tests_per_case",
-            "tests_units",
-            "total_vaccinations",
-            "people_vaccinated",
-            "people_fully_vaccinated",
-            "new_vaccinations",
-            "new_vaccinations_smoothed",
-            "total_vaccinations_per_hundred",
-            "people_vaccinated_per_hundred",
-            "people_fully_vaccinated_per_hundred",
-            "new_vaccinations_smoothed_per_million",
-            "stringency_index",
-            "population",
-            "population_density",
-            "median_age",
-            "aged_65_older",
-            "aged_70_older",
-            "gdp_per_capita",
-            "extreme_poverty",
-            "cardiovasc_death_rate",
-            "diabetes_prevalence",
-            "female_smokers",
-            "male_smokers",
-            "handwashing_facilities",
-            "hospital_beds_per_thousand",
-            "life_expectancy",
-            "human_development_index",
-            "excess_mortality",
-        ],
-        rdr.headers().unwrap().iter().collect::<Vec<&str>>()
-    );
-    let recs = parse_records(rdr.byte_records());
-    let finaliter = parse_to_final(recs);
-    for rec in finaliter {
-        let nd = NaiveDate::parse_from_str(rec.date.as_str(), "%Y-%m-%d").unwrap();
-        let dbrec = OWID {
-            date_julian: nd_to_day(&nd),
-            iso_code: rec.iso_code,
-            continent: rec.continent,
-            location: rec.location,
-            total_cases: rec.total_cases,
-            new_cases: rec.new_cases,
-            new_cases_smoothed: rec.new_cases_smoothed,
-            total_deaths: rec.total_deaths,
-            new_deaths: rec.new_deaths,
-            new_deaths_smoothed: rec.new_deaths_smoothed,
-            total_cases_per_million: rec.total_cases_per_million,
-            new_cases_per_million: rec.new_cases_per_million,
-            new_cases_smoothed_per_million: rec.new_cases_smoothed_per_million,
-            total_deaths_per_million: rec.total_deaths_per_million,
-            new_deaths_per_million: rec.new_deaths_per_million,
-            new_deaths_smoothed_per_million: rec.new_deaths_smoothed_per_million,
-            reproduction_rate: rec.reproduction_rate,
-            icu_patients: rec.icu_patients,
-            icu_patients_per_million: rec.icu_patients_per_million,
-            hosp_patients: rec.hosp_patients,
-            hosp_patients_per_million: rec.hosp_patients_per_million,
-            weekly_icu_admissions: rec.weekly_icu_admissions,
-            weekly_icu_admissions_per_million: rec.weekly_icu_admissions_per_million,
-            weekly_hosp_admissions: rec.weekly_hosp_admissions,
-            weekly_hosp_admissions_per_million: rec.weekly_hosp_admissions_per_million,
-            new_tests_smoothed: rec.new_tests_smoothed,
-            new_tests: rec.new_tests,
-            total_tests: rec.total_tests,
-            total_tests_per_thousand: rec.total_tests_per_thousand,
-            new_tests_per_thousand: rec.new_tests_per_thousand,
-            new_tests_smoothed_per_thousand: rec.new_tests_smoothed_per_thousand,
-            tests_per_case: rec.tests_per_case,
-            positive_rate: rec.positive_rate,
-            tests_units: rec.tests_units,
-            total_vaccinations: rec.total_vaccinations,
-            people_vaccinated: rec.people_vaccinated,
-            people_fully_vaccinated: rec.people_fully_vaccinated,
-            new_vaccinations: rec.new_vaccinations,
-            new_vaccinations_smoothed: rec.new_vaccinations_smoothed,
-            total_vaccinations_per_hundred: rec.total_vaccinations_per_hundred,
-            people_vaccinated_per_hundred: rec.people_vaccinated_per_hundred,
-            people_fully_vaccinated_per_hundred: rec.people_fully_vaccinated_per_hundred,
-            new_vaccinations_smoothed_per_million: rec.new_vaccinations_smoothed_per_million,
-            stringency_index: rec.stringency_index,
-            population: rec.population,
-            population_density: rec.population_density,
-            median_age: rec.median_age,
-            aged_65_older: rec.aged_65_older,
-            aged_70_older: rec.aged_70_older,
-            gdp_per_capita: rec.gdp_per_capita,
-            extreme_poverty: rec.extreme_poverty,
-            cardiovasc_death_rate: rec.cardiovasc_death_rate,
-            diabetes_prevalence: rec.diabetes_prevalence,
-            female_smokers: rec.female_smokers,
-            male_smokers: rec.male_smokers,
-            handwashing_facilities: rec.handwashing_facilities,
-            hospital_beds_per_thousand: rec.hospital_beds_per_thousand,
-            life_expectancy: rec.life_expectancy,
-            human_development_index: rec.human_development_index,
-            excess_mortality: rec.excess_mortality,
-        };
-        let query = sqlx::query(OWID::insert_str());
-        dbrec
-            .bind_query(query)
-            .execute(&mut transaction)
-            .await
-            .unwrap();
+/// The Our World in Data dataset, in `owid`.
+pub struct OWIDSource;
+
+#[async_trait]
+impl Source for OWIDSource {
+    fn name(&self) -> &str {
+        "owid"
+    }
+
+    fn files(&self) -> Vec<SourceFile> {
+        builtin_files(self.name())
+    }
+
+    fn schema(&self) -> Vec<String> {
+        owid_schema()
+    }
+
+    fn coverage(&self) -> Option<Coverage> {
+        Some(Coverage::new("owid_raw", "location"))
+    }
+
+    /// Parse the CSV, loading it into the database.
+    async fn load(
+        &self,
+        files: &[PathBuf],
+        pool: &mut SqlitePool,
+        ctx: &LoadContext,
+    ) -> Result<Vec<LoadStats>> {
+        let name = ctx.source_name(self.name());
+        let mut rdr = parse_init_file(File::open(&files[0])?)?;
+        let transcoder = Transcoder::new(ctx.encoding);
+        let headers = check_headers(
+            name,
+            COLUMNS,
+            REQUIRED_COLUMNS,
+            &mut rdr,
+            &transcoder,
+            &ctx.progress,
+        )?;
+        let mut transaction = pool.begin().await?;
+        record_headers(&mut transaction, name, &headers).await?;
+        let recs = parse_records(name, &transcoder, rdr.byte_records());
+        let finaliter = parse_to_final(name, &headers, recs);
+        let mut inserter =
+            BulkInsert::new(OWID::insert_str(), OWID::bind_query).discard(ctx.dry_run);
+        let mut stats = LoadStats::default();
+        for rec in finaliter {
+            let rec = match rec {
+                Ok(rec) => rec,
+                Err(e) => {
+                    stats.reject(e)?;
+                    continue;
+                }
+            };
+            let place = Place {
+                country: rec.iso_code.as_deref(),
+                ..Place::default()
+            };
+            if !ctx.subset.keep(&rec.date, &place) {
+                continue;
+            }
+            stats.parsed += 1;
+            let dbrec = OWID {
+                date_julian: nd_to_day(&rec.date),
+                iso_code: rec.iso_code,
+                continent: rec.continent,
+                location: rec.location,
+                total_cases: rec.total_cases,
+                new_cases: rec.new_cases,
+                new_cases_smoothed: rec.new_cases_smoothed,
+                total_deaths: rec.total_deaths,
+                new_deaths: rec.new_deaths,
+                new_deaths_smoothed: rec.new_deaths_smoothed,
+                total_cases_per_million: rec.total_cases_per_million,
+                new_cases_per_million: rec.new_cases_per_million,
+                new_cases_smoothed_per_million: rec.new_cases_smoothed_per_million,
+                total_deaths_per_million: rec.total_deaths_per_million,
+                new_deaths_per_million: rec.new_deaths_per_million,
+                new_deaths_smoothed_per_million: rec.new_deaths_smoothed_per_million,
+                reproduction_rate: rec.reproduction_rate,
+                icu_patients: rec.icu_patients,
+                icu_patients_per_million: rec.icu_patients_per_million,
+                hosp_patients: rec.hosp_patients,
+                hosp_patients_per_million: rec.hosp_patients_per_million,
+                weekly_icu_admissions: rec.weekly_icu_admissions,
+                weekly_icu_admissions_per_million: rec.weekly_icu_admissions_per_million,
+                weekly_hosp_admissions: rec.weekly_hosp_admissions,
+                weekly_hosp_admissions_per_million: rec.weekly_hosp_admissions_per_million,
+                new_tests_smoothed: rec.new_tests_smoothed,
+                new_tests: rec.new_tests,
+                total_tests: rec.total_tests,
+                total_tests_per_thousand: rec.total_tests_per_thousand,
+                new_tests_per_thousand: rec.new_tests_per_thousand,
+                new_tests_smoothed_per_thousand: rec.new_tests_smoothed_per_thousand,
+                tests_per_case: rec.tests_per_case,
+                positive_rate: rec.positive_rate,
+                tests_units: rec.tests_units,
+                total_vaccinations: rec.total_vaccinations,
+                people_vaccinated: rec.people_vaccinated,
+                people_fully_vaccinated: rec.people_fully_vaccinated,
+                new_vaccinations: rec.new_vaccinations,
+                new_vaccinations_smoothed: rec.new_vaccinations_smoothed,
+                total_vaccinations_per_hundred: rec.total_vaccinations_per_hundred,
+                people_vaccinated_per_hundred: rec.people_vaccinated_per_hundred,
+                people_fully_vaccinated_per_hundred: rec.people_fully_vaccinated_per_hundred,
+                new_vaccinations_smoothed_per_million: rec.new_vaccinations_smoothed_per_million,
+                stringency_index: rec.stringency_index,
+                population: rec.population,
+                population_density: rec.population_density,
+                median_age: rec.median_age,
+                aged_65_older: rec.aged_65_older,
+                aged_70_older: rec.aged_70_older,
+                gdp_per_capita: rec.gdp_per_capita,
+                extreme_poverty: rec.extreme_poverty,
+                cardiovasc_death_rate: rec.cardiovasc_death_rate,
+                diabetes_prevalence: rec.diabetes_prevalence,
+                female_smokers: rec.female_smokers,
+                male_smokers: rec.male_smokers,
+                handwashing_facilities: rec.handwashing_facilities,
+                hospital_beds_per_thousand: rec.hospital_beds_per_thousand,
+                life_expectancy: rec.life_expectancy,
+                human_development_index: rec.human_development_index,
+                excess_mortality: rec.excess_mortality,
+            };
+            stats.date(dbrec.date_julian);
+            inserter.push(dbrec, &mut transaction).await?;
+        }
+        stats.inserted = inserter.finish(&mut transaction).await?;
+        stats.replacements = transcoder.replacements();
+        stats.skipped = records_read(&rdr) - stats.parsed - stats.rejected();
+        transaction.commit().await?;
+        Ok(vec![stats])
     }
-    transaction.commit().await.unwrap();
 }
@@ -16,15 +16,20 @@ Copyright (c) 2019-2020 John Goerzen
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use crate::progress::{Event, Progress};
+use crate::{Error, Result};
+use chrono::NaiveDate;
 use csv;
-use serde::{de, Deserialize, Deserializer};
-use std::error::Error;
-use std::fmt::Display;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use sqlx::Transaction;
+use std::borrow::Cow;
+use std::fmt::{self, Display};
 use std::fs::File;
+use std::io;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-#[allow(dead_code)]
-pub fn date_from_str<'de, S, D>(deserializer: D) -> Result<S, D::Error>
+pub fn date_from_str<'de, S, D>(deserializer: D) -> std::result::Result<S, D::Error>
 where
     S: FromStr,      // Required for S::from_str...
     S::Err: Display, // Required for .map_err(de::Error::custom)
@@ -34,13 +39,169 @@ where
     S::from_str(&s).map_err(de::Error::custom)
 }
 
+/// Parses a date in `YYYYMMDD` form, as used by the COVID Tracking Project.
+pub fn date_from_yyyymmdd<'de, D>(deserializer: D) -> std::result::Result<NaiveDate, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    NaiveDate::parse_from_str(&s, "%Y%m%d").map_err(de::Error::custom)
+}
+
+/// Deserializes a record, mapping fields to columns by the names in `headers`,
+/// and reporting failure as an [`Error::RowParse`] for `source`, holding the record.
 pub fn rec_to_struct<'a, A: serde::Deserialize<'a>>(
+    source: &str,
+    headers: &'a csv::StringRecord,
     record: &'a csv::StringRecord,
-) -> Result<A, csv::Error> {
-    record.deserialize(None)
+) -> Result<A> {
+    record
+        .deserialize(Some(headers))
+        .map_err(|e| Error::row_parse(source, &e).with_record(record))
+}
+
+/** How the header row of a source file differs from the columns its loader
+knows about.
+
+```
+use covid19db::loader::HeaderDrift;
+
+let drift = HeaderDrift::compare(
+    &["date", "state", "cases"],
+    &["date", "region", "cases", "deaths"],
+);
+assert_eq!(drift.added, vec!["deaths"]);
+assert!(drift.removed.is_empty());
+assert_eq!(drift.renamed, vec![(String::from("state"), String::from("region"))]);
+assert_eq!(drift.to_string(), r#"added "deaths"; renamed "state" to "region""#);
+assert!(HeaderDrift::compare(&["a", "b"], &["b", "a"]).is_empty());
+```
+*/
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct HeaderDrift {
+    /// Columns in the file that the loader doesn't know about.
+    pub added: Vec<String>,
+    /// Known columns that are absent from the file.
+    pub removed: Vec<String>,
+    /// Known columns that seem to have been renamed, as `(old, new)` pairs.  A
+    /// column is taken to be renamed if an added column sits in its old position.
+    pub renamed: Vec<(String, String)>,
+}
+
+impl HeaderDrift {
+    /// Compares the `expected` columns with those `found` in a file.  Order is ignored.
+    pub fn compare(expected: &[&str], found: &[&str]) -> Self {
+        let mut added: Vec<String> = found
+            .iter()
+            .filter(|x| !expected.contains(x))
+            .map(|x| String::from(*x))
+            .collect();
+        let mut removed = Vec::new();
+        let mut renamed = Vec::new();
+        for (idx, col) in expected.iter().enumerate() {
+            if found.contains(col) {
+                continue;
+            }
+            match found
+                .get(idx)
+                .and_then(|x| added.iter().position(|a| a == x))
+            {
+                Some(pos) => renamed.push((String::from(*col), added.remove(pos))),
+                None => removed.push(String::from(*col)),
+            }
+        }
+        HeaderDrift {
+            added,
+            removed,
+            renamed,
+        }
+    }
+
+    /// True if the file has exactly the expected columns.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.renamed.is_empty()
+    }
+}
+
+/// Lists the changes on one line, such as `added "deaths"; renamed "state" to "region"`.
+impl fmt::Display for HeaderDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut changes = Vec::new();
+        changes.extend(self.added.iter().map(|x| format!("added {:?}", x)));
+        changes.extend(self.removed.iter().map(|x| format!("removed {:?}", x)));
+        changes.extend(
+            self.renamed
+                .iter()
+                .map(|(old, new)| format!("renamed {:?} to {:?}", old, new)),
+        );
+        f.write_str(&changes.join("; "))
+    }
+}
+
+/** Reads the header row of `rdr`, decoding it with `transcoder` as the records
+that follow are, and compares it with `columns`, the columns the loader for
+`source` knows about.  Added, removed, and renamed columns are
+reported to `progress` as an [`Event::HeaderDrift`].  Returns
+[`Error::HeaderMismatch`] if any of `required` is absent; otherwise, returns
+the header row for use with [`rec_to_struct`]. */
+pub fn check_headers<A: std::io::Read>(
+    source: &str,
+    columns: &[&str],
+    required: &[&str],
+    rdr: &mut csv::Reader<A>,
+    transcoder: &Transcoder,
+    progress: &Progress,
+) -> Result<csv::StringRecord> {
+    let headers = transcoder.record(
+        rdr.byte_headers()
+            .map_err(|e| Error::row_parse(source, &e))?,
+    );
+    let found: Vec<&str> = headers.iter().collect();
+    let drift = HeaderDrift::compare(columns, &found);
+    if !drift.is_empty() {
+        progress.event(Event::HeaderDrift {
+            source: String::from(source),
+            drift,
+        });
+    }
+    let missing: Vec<String> = required
+        .iter()
+        .filter(|x| !found.contains(x))
+        .map(|x| String::from(*x))
+        .collect();
+    if missing.is_empty() {
+        Ok(headers)
+    } else {
+        Err(Error::HeaderMismatch {
+            source: String::from(source),
+            missing,
+            found: found.into_iter().map(String::from).collect(),
+        })
+    }
+}
+
+/// Records the header row observed for `source` in `covid19db_meta`, as a line of CSV.
+pub async fn record_headers(
+    transaction: &mut Transaction<sqlx::pool::PoolConnection<sqlx::SqliteConnection>>,
+    source: &str,
+    headers: &csv::StringRecord,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    {
+        let mut wtr = csv::Writer::from_writer(&mut buf);
+        wtr.write_record(headers).map_err(io::Error::from)?;
+        wtr.flush()?;
+    }
+    let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+    sqlx::query("INSERT INTO covid19db_meta VALUES (?, ?)")
+        .bind(format!("headers:{}", source))
+        .bind(line)
+        .execute(&mut *transaction)
+        .await?;
+    Ok(())
 }
 
-pub fn parse_init_file(file: File) -> Result<csv::Reader<File>, Box<dyn Error>> {
+pub fn parse_init_file(file: File) -> Result<csv::Reader<File>> {
     let rdr = csv::ReaderBuilder::new()
         .delimiter(b',')
         .flexible(true)
@@ -48,17 +209,111 @@ pub fn parse_init_file(file: File) -> Result<csv::Reader<File>, Box<dyn Error>>
     Ok(rdr)
 }
 
+/** The character encoding of a source's text files.  Text that isn't valid in
+the declared encoding is replaced with U+FFFD, and counted; see [`Transcoder`]. */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum Encoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    /// ISO 8859-1, in which every byte is the code point of the same value.
+    #[serde(rename = "latin-1")]
+    Latin1,
+    #[serde(rename = "windows-1252")]
+    Windows1252,
+}
+
+impl Encoding {
+    /** Decodes `bytes` from this encoding.
+
+    ```
+    use covid19db::loader::Encoding;
+
+    assert_eq!(Encoding::Latin1.decode(b"Cura\xe7ao"), "Curaçao");
+    assert_eq!(Encoding::Windows1252.decode(b"\x93Quoted\x94"), "\u{201c}Quoted\u{201d}");
+    assert_eq!(Encoding::Utf8.decode(b"Cura\xe7ao"), "Cura\u{fffd}ao");
+    ```
+    */
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes),
+            Encoding::Latin1 => match std::str::from_utf8(bytes) {
+                Ok(s) if s.is_ascii() => Cow::Borrowed(s),
+                _ => Cow::Owned(bytes.iter().map(|&b| char::from(b)).collect()),
+            },
+            Encoding::Windows1252 => {
+                encoding_rs::WINDOWS_1252
+                    .decode_without_bom_handling(bytes)
+                    .0
+            }
+        }
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Latin1 => "Latin-1",
+            Encoding::Windows1252 => "Windows-1252",
+        })
+    }
+}
+
+/** Turns the byte records of a file into string records, decoding them from
+the source's [`Encoding`] and counting the U+FFFD replacement characters in the
+result, whether put there by decoding or already present in the file. */
+pub struct Transcoder {
+    encoding: Encoding,
+    replacements: AtomicU64,
+}
+
+impl Transcoder {
+    pub fn new(encoding: Encoding) -> Self {
+        Transcoder {
+            encoding,
+            replacements: AtomicU64::new(0),
+        }
+    }
+
+    /// Decodes one record, keeping its position for error messages.
+    pub fn record(&self, rec: &csv::ByteRecord) -> csv::StringRecord {
+        let mut result = csv::StringRecord::with_capacity(rec.as_slice().len(), rec.len());
+        for field in rec.iter() {
+            let field = self.encoding.decode(field);
+            let found = field.matches(char::REPLACEMENT_CHARACTER).count() as u64;
+            if found > 0 {
+                self.replacements.fetch_add(found, Ordering::Relaxed);
+            }
+            result.push_field(&field);
+        }
+        result.set_position(rec.position().cloned());
+        result
+    }
+
+    /// The number of replacement characters seen so far.
+    pub fn replacements(&self) -> u64 {
+        self.replacements.load(Ordering::Relaxed)
+    }
+}
+
 /*
 
 This type signature with hints from https://stackoverflow.com/questions/27535289/what-is-the-correct-way-to-return-an-iterator-or-any-other-trait
 */
 pub fn parse_records<'a, A: std::io::Read>(
+    source: &'a str,
+    transcoder: &'a Transcoder,
     byteiter: csv::ByteRecordsIter<'a, A>,
-) -> impl Iterator<Item = csv::StringRecord> + 'a {
-    byteiter.map(|x| csv::StringRecord::from_byte_record_lossy(x.expect("Error in parse_records")))
+) -> impl Iterator<Item = Result<csv::StringRecord>> + 'a {
+    byteiter.map(move |x| {
+        x.map(|rec| transcoder.record(&rec))
+            .map_err(|e| Error::row_parse(source, &e))
+    })
 }
 
-#[allow(dead_code)]
-pub fn parse_record(rec: csv::ByteRecord) -> csv::StringRecord {
-    csv::StringRecord::from_byte_record_lossy(rec)
+/// Returns the number of records read from `rdr` so far, not counting the header row.
+/// Subtract those parsed and rejected to find how many were skipped.
+pub fn records_read<R: std::io::Read>(rdr: &csv::Reader<R>) -> u64 {
+    rdr.position().record().saturating_sub(1)
 }
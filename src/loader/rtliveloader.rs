@@ -18,16 +18,60 @@ Copyright (c) 2019-2020 John Goerzen
 
 pub use crate::dateutil::*;
 pub use crate::dbschema::*;
+use crate::dbutil::*;
+use crate::loader::config::builtin_files;
 pub use crate::loader::parseutil::*;
+use crate::loader::source::{Coverage, LoadContext, LoadStats, Source, SourceFile};
+use crate::loader::subset::Place;
+use crate::Result;
+use async_trait::async_trait;
 use chrono::NaiveDate;
 use csv;
 use serde::Deserialize;
-use sqlx::Transaction;
+use sqlx::sqlite::SqlitePool;
+use std::fs::File;
+use std::path::PathBuf;
+
+const COLUMNS: &[&str] = &[
+    "date",
+    "region",
+    "index",
+    "mean",
+    "median",
+    "lower_80",
+    "upper_80",
+    "infections",
+    "test_adjusted_positive",
+    "test_adjusted_positive_raw",
+    "positive",
+    "tests",
+    "new_tests",
+    "new_cases",
+    "new_deaths",
+];
+
+const REQUIRED_COLUMNS: &[&str] = &[
+    "date",
+    "region",
+    "index",
+    "mean",
+    "median",
+    "lower_80",
+    "upper_80",
+    "infections",
+    "test_adjusted_positive",
+    "test_adjusted_positive_raw",
+    "positive",
+    "tests",
+];
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct RTLiveRecord {
-    pub date: String,
+    #[serde(deserialize_with = "date_from_str")]
+    pub date: NaiveDate,
+    #[serde(rename = "region")]
     pub state: String,
+    #[serde(rename = "index")]
     pub rtindex: i64,
     pub mean: f64,
     pub median: f64,
@@ -43,65 +87,99 @@ pub struct RTLiveRecord {
     pub new_deaths: Option<f64>,
 }
 
-pub fn parse_to_final<A: Iterator<Item = csv::StringRecord>>(
+pub fn parse_to_final<'a, A: Iterator<Item = Result<csv::StringRecord>> + 'a>(
+    source: &'a str,
+    headers: &'a csv::StringRecord,
     striter: A,
-) -> impl Iterator<Item = RTLiveRecord> {
-    striter.filter_map(|x| rec_to_struct(&x).expect("rec_to_struct"))
+) -> impl Iterator<Item = Result<RTLiveRecord>> + 'a {
+    striter.filter_map(move |x| {
+        x.and_then(|rec| rec_to_struct(source, headers, &rec))
+            .transpose()
+    })
 }
 
-/** Parse the CSV, loading it into the database.
- * Will panic on parse error.  */
-pub async fn load<'a, A: std::io::Read>(
-    rdr: &'a mut csv::Reader<A>,
-    mut transaction: Transaction<sqlx::pool::PoolConnection<sqlx::SqliteConnection>>,
-) {
-    assert_eq!(
-        vec![
-            "date",
-            "region",
-            "index",
-            "mean",
-            "median",
-            "lower_80",
-            "upper_80",
-            "infections",
-            "test_adjusted_positive",
-            "test_adjusted_positive_raw",
-            "positive",
-            "tests",
-            "new_tests",
-            "new_cases",
-            "new_deaths"
-        ],
-        rdr.headers().unwrap().iter().collect::<Vec<&str>>()
-    );
-    let recs = parse_records(rdr.byte_records());
-    let finaliter = parse_to_final(recs);
-    for rec in finaliter {
-        let nd = NaiveDate::parse_from_str(rec.date.as_str(), "%Y-%m-%d").unwrap();
-        let dbrec = RTLive {
-            date_julian: nd_to_day(&nd),
-            state: rec.state,
-            rtindex: rec.rtindex,
-            mean: rec.mean,
-            median: rec.median,
-            lower_80: rec.lower_80,
-            upper_80: rec.upper_80,
-            infections: rec.infections,
-            test_adjusted_positive: rec.test_adjusted_positive,
-            test_adjusted_positive_raw: rec.test_adjusted_positive_raw,
-            positive: rec.positive.round() as i64,
-            tests: rec.tests.round() as i64,
-            new_tests: rec.new_tests.map(|x| x.round() as i64),
-            new_cases: rec.new_cases.map(|x| x.round() as i64),
-            new_deaths: rec.new_deaths.map(|x| x.round() as i64),
-        };
-        let query = sqlx::query(RTLive::insert_str());
-        dbrec
-            .bind_query(query)
-            .execute(&mut transaction)
-            .await
-            .unwrap();
+/// rt.live estimates, in `rtlive`.
+pub struct RTLiveSource;
+
+#[async_trait]
+impl Source for RTLiveSource {
+    fn name(&self) -> &str {
+        "rtlive"
+    }
+
+    fn files(&self) -> Vec<SourceFile> {
+        builtin_files(self.name())
+    }
+
+    fn schema(&self) -> Vec<String> {
+        rtlive_schema()
+    }
+
+    fn coverage(&self) -> Option<Coverage> {
+        Some(Coverage::new("rtlive_raw", "state"))
+    }
+
+    /// Parse the CSV, loading it into the database.
+    async fn load(
+        &self,
+        files: &[PathBuf],
+        pool: &mut SqlitePool,
+        ctx: &LoadContext,
+    ) -> Result<Vec<LoadStats>> {
+        let name = ctx.source_name(self.name());
+        let mut rdr = parse_init_file(File::open(&files[0])?)?;
+        let transcoder = Transcoder::new(ctx.encoding);
+        let headers = check_headers(
+            name,
+            COLUMNS,
+            REQUIRED_COLUMNS,
+            &mut rdr,
+            &transcoder,
+            &ctx.progress,
+        )?;
+        let mut transaction = pool.begin().await?;
+        record_headers(&mut transaction, name, &headers).await?;
+        let recs = parse_records(name, &transcoder, rdr.byte_records());
+        let finaliter = parse_to_final(name, &headers, recs);
+        let mut inserter =
+            BulkInsert::new(RTLive::insert_str(), RTLive::bind_query).discard(ctx.dry_run);
+        let mut stats = LoadStats::default();
+        for rec in finaliter {
+            let rec = match rec {
+                Ok(rec) => rec,
+                Err(e) => {
+                    stats.reject(e)?;
+                    continue;
+                }
+            };
+            if !ctx.subset.keep(&rec.date, &Place::us_state(&rec.state)) {
+                continue;
+            }
+            stats.parsed += 1;
+            let dbrec = RTLive {
+                date_julian: nd_to_day(&rec.date),
+                state: rec.state,
+                rtindex: rec.rtindex,
+                mean: rec.mean,
+                median: rec.median,
+                lower_80: rec.lower_80,
+                upper_80: rec.upper_80,
+                infections: rec.infections,
+                test_adjusted_positive: rec.test_adjusted_positive,
+                test_adjusted_positive_raw: rec.test_adjusted_positive_raw,
+                positive: rec.positive.round() as i64,
+                tests: rec.tests.round() as i64,
+                new_tests: rec.new_tests.map(|x| x.round() as i64),
+                new_cases: rec.new_cases.map(|x| x.round() as i64),
+                new_deaths: rec.new_deaths.map(|x| x.round() as i64),
+            };
+            stats.date(dbrec.date_julian);
+            inserter.push(dbrec, &mut transaction).await?;
+        }
+        stats.inserted = inserter.finish(&mut transaction).await?;
+        stats.replacements = transcoder.replacements();
+        stats.skipped = records_read(&rdr) - stats.parsed - stats.rejected();
+        transaction.commit().await?;
+        Ok(vec![stats])
     }
-    transaction.commit().await.unwrap();
 }
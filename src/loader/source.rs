@@ -0,0 +1,624 @@
+/* Data sources
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::future::try_join_all;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqlitePool;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, Semaphore};
+
+use crate::attribution::{Credit, Credits};
+use crate::loader::{
+    downloadto_if_changed, Compression, DownloadOptions, Encoding, Subset, Validators,
+};
+use crate::progress::{Event, Progress};
+use crate::{Error, Result};
+
+/** A file fetched from the Internet for a [`Source`].  `filename` is the
+name under which it is kept in a local source directory; see
+[`Registry::fetch`] and [`LoadOptions::input_dir`](crate::loader::LoadOptions::input_dir).
+It may contain slashes to place the file in a subdirectory.  If `sha256` is
+given, the file must match it as downloaded, or [`Fetcher::obtain`] fails.
+A compressed file is decompressed once obtained (see [`Compression`]); if it
+is a zip archive, `member` names the file in it to use. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceFile {
+    pub filename: String,
+    pub url: String,
+    pub sha256: Option<String>,
+    pub member: Option<String>,
+}
+
+impl SourceFile {
+    pub fn new(filename: &str, url: &str) -> Self {
+        SourceFile {
+            filename: String::from(filename),
+            url: String::from(url),
+            sha256: None,
+            member: None,
+        }
+    }
+
+    /** The name under which the file is kept once decompressed: its
+    filename without the extension for `compression`, flattened to a single
+    path component, and followed by the member for a zip archive.
+
+    ```
+    use covid19db::loader::{Compression, SourceFile};
+
+    let sf = SourceFile::new("jhu/v1/daily/values-sqlite.db.zst", "https://example.com/");
+    assert_eq!(sf.decompressed_name(Compression::Zstd), "jhu_v1_daily_values-sqlite.db");
+    let sf = SourceFile {
+        member: Some(String::from("data/us.csv")),
+        ..SourceFile::new("bundle.zip", "https://example.com/bundle.zip")
+    };
+    assert_eq!(sf.decompressed_name(Compression::Zip), "bundle_data_us.csv");
+    ```
+    */
+    pub fn decompressed_name(&self, compression: Compression) -> String {
+        let mut name = match compression.extension() {
+            Some(ext) if self.filename.ends_with(ext) && self.filename.len() > ext.len() => {
+                self.filename[..self.filename.len() - ext.len()].to_string()
+            }
+            // Detected from the contents; keep the name distinct from the original.
+            _ => format!("{}.{}", self.filename, compression),
+        };
+        if let (Compression::Zip, Some(member)) = (compression, &self.member) {
+            name = format!("{}_{}", name, member);
+        }
+        name.replace('/', "_")
+    }
+
+    /** Returns the branch, tag, or commit named in a GitHub URL, which is
+    pinned if it is a commit hash.
+
+    ```
+    use covid19db::loader::SourceFile;
+
+    let sf = SourceFile::new("x.tsv", "https://github.com/a/b/raw/5444d3e/exports/x.tsv");
+    assert_eq!(sf.revision(), Some("5444d3e"));
+    let sf = SourceFile::new("x.csv", "https://raw.githubusercontent.com/a/b/master/x.csv");
+    assert_eq!(sf.revision(), Some("master"));
+    let sf = SourceFile::new("x.csv", "https://example.com/data/x.csv");
+    assert_eq!(sf.revision(), None);
+    ```
+    */
+    pub fn revision(&self) -> Option<&str> {
+        let (_, rest) = self.url.split_once("://")?;
+        let parts: Vec<&str> = rest.split('/').collect();
+        match parts.as_slice() {
+            ["github.com", _, _, "raw", rev, _, ..] => Some(rev),
+            ["raw.githubusercontent.com", _, _, rev, _, ..] => Some(rev),
+            _ => None,
+        }
+    }
+}
+
+/** Obtains source files so they can be loaded, either by downloading them or
+by reading them from a local directory.  Compressed files are then
+decompressed into a scratch directory; see [`Compression`].  Each file is
+obtained at most once, and the HTTP validators of downloaded files are
+remembered, along with their checksums; see [`info`](Fetcher::info).
+
+Each file is fetched on a task of its own, so files requested at the same time
+are downloaded concurrently, up to [`DownloadOptions::concurrency`] at once.
+Clones share their files, what is known about them, and the limit. */
+#[derive(Clone)]
+pub struct Fetcher {
+    input_dir: Option<PathBuf>,
+    download: DownloadOptions,
+    tmp_path: PathBuf,
+    obtained: Arc<Mutex<HashMap<String, Slot>>>,
+    info: Arc<Mutex<HashMap<String, FileInfo>>>,
+    limit: Arc<Semaphore>,
+}
+
+/// The path of an obtained file; locked while the file is being obtained.
+type Slot = Arc<AsyncMutex<Option<PathBuf>>>;
+
+impl Fetcher {
+    /// Creates a `Fetcher` that reads from `input_dir` if given, or else
+    /// downloads according to `download`, and uses `tmp_path` for scratch files.
+    pub fn new(input_dir: Option<PathBuf>, download: DownloadOptions, tmp_path: &Path) -> Self {
+        let limit = Arc::new(Semaphore::new(download.concurrency.max(1)));
+        Fetcher {
+            input_dir,
+            download,
+            tmp_path: tmp_path.to_owned(),
+            obtained: Arc::new(Mutex::new(HashMap::new())),
+            info: Arc::new(Mutex::new(HashMap::new())),
+            limit,
+        }
+    }
+
+    /// Makes the given file available for loading, and returns its path.
+    pub async fn obtain(&self, sf: &SourceFile) -> Result<PathBuf> {
+        let slot = self.slot(sf);
+        let mut obtained = slot.lock().await;
+        if let Some(path) = obtained.as_ref() {
+            return Ok(path.clone());
+        }
+        // Without validators, the request isn't conditional, so there is always a file.
+        let path = self
+            .spawn(sf, &Validators::default())
+            .await?
+            .expect("unconditional fetch returned no file");
+        *obtained = Some(path.clone());
+        Ok(path)
+    }
+
+    /** Like [`obtain`](Fetcher::obtain), but if `prev` holds the validators
+    from an earlier download, asks the server to send the file only if it has
+    changed.  Returns `None` if it has not.  Files in a local directory are
+    always taken to have changed. */
+    pub async fn obtain_if_changed(
+        &self,
+        sf: &SourceFile,
+        prev: &Validators,
+    ) -> Result<Option<PathBuf>> {
+        let slot = self.slot(sf);
+        let mut obtained = slot.lock().await;
+        let path = self.spawn(sf, prev).await?;
+        if path.is_some() {
+            *obtained = path.clone();
+        }
+        Ok(path)
+    }
+
+    /// Returns the validators the server sent for `url`, if it has been downloaded.
+    pub fn validators(&self, url: &str) -> Option<Validators> {
+        self.info(url).map(|x| x.validators)
+    }
+
+    /// Returns what is known about the file at `url`, if it has been obtained.
+    pub fn info(&self, url: &str) -> Option<FileInfo> {
+        self.info.lock().unwrap().get(url).cloned()
+    }
+
+    fn slot(&self, sf: &SourceFile) -> Slot {
+        self.obtained
+            .lock()
+            .unwrap()
+            .entry(sf.filename.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Runs [`get`](Fetcher::get) on a task of its own once the limit allows.
+    async fn spawn(&self, sf: &SourceFile, prev: &Validators) -> Result<Option<PathBuf>> {
+        let fetcher = self.clone();
+        let sf = sf.clone();
+        let prev = prev.clone();
+        tokio::spawn(async move {
+            let _permit = fetcher.limit.acquire().await;
+            fetcher.get(&sf, &prev).await
+        })
+        .await
+        .expect("fetch task panicked")
+    }
+
+    async fn get(&self, sf: &SourceFile, prev: &Validators) -> Result<Option<PathBuf>> {
+        let (raw, info) = match &self.input_dir {
+            Some(dir) => {
+                let path = dir.join(&sf.filename);
+                let mut hashing = Hashing::new(std::io::sink());
+                std::io::copy(&mut File::open(&path)?, &mut hashing)?;
+                // The file was fetched when it was written.
+                let fetched = std::fs::metadata(&path)?.modified()?.into();
+                (path, hashing.finish(fetched, Validators::default()))
+            }
+            None => {
+                // Several sources use the same base name, so keep the directory structure.
+                let dir = self.tmp_path.join("download");
+                std::fs::create_dir_all(&dir)?;
+                let path = dir.join(sf.filename.replace('/', "_"));
+                self.download.progress.event(Event::DownloadStarted {
+                    url: sf.url.clone(),
+                    path: path.clone(),
+                });
+                let mut hashing = Hashing::new(File::create(&path)?);
+                let validators =
+                    downloadto_if_changed(&sf.url, prev, &self.download, &mut hashing).await?;
+                hashing.flush()?;
+                match validators {
+                    Some(validators) => (path, hashing.finish(Utc::now(), validators)),
+                    None => {
+                        self.download.progress.event(Event::Unchanged {
+                            url: sf.url.clone(),
+                        });
+                        std::fs::remove_file(&path)?;
+                        return Ok(None);
+                    }
+                }
+            }
+        };
+        if let Some(expected) = &sf.sha256 {
+            if !expected.eq_ignore_ascii_case(&info.sha256) {
+                return Err(Error::Checksum {
+                    url: sf.url.clone(),
+                    expected: expected.clone(),
+                    found: info.sha256,
+                });
+            }
+        }
+        let compression = Compression::detect_file(&sf.filename, &raw)?;
+        let path = if compression == Compression::Plain {
+            raw
+        } else {
+            let path = self.tmp_path.join(sf.decompressed_name(compression));
+            self.download.progress.step(format!(
+                "Decompressing {:#?} ({}) to {:#?}",
+                raw, compression, path
+            ));
+            compression.decompress(&raw, sf.member.as_deref(), &path)?;
+            path
+        };
+        self.info.lock().unwrap().insert(sf.url.clone(), info);
+        Ok(Some(path))
+    }
+}
+
+/** What is known about a file that a [`Fetcher`] obtained, for the
+`covid19db_sources` table. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileInfo {
+    /// When the file was downloaded; for a file in a local directory, when it was written.
+    pub fetched: DateTime<Utc>,
+    /// The SHA-256 of the file as downloaded, before any decompression, in hex.
+    pub sha256: String,
+    /// The size of the file as downloaded.
+    pub bytes: u64,
+    /// The validators the server sent; empty for a file in a local directory.
+    pub validators: Validators,
+}
+
+/// A writer that hashes and counts what passes through it.
+struct Hashing<W> {
+    inner: W,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<W: Write> Hashing<W> {
+    fn new(inner: W) -> Self {
+        Hashing {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    fn finish(self, fetched: DateTime<Utc>, validators: Validators) -> FileInfo {
+        FileInfo {
+            fetched,
+            sha256: format!("{:x}", self.hasher.finalize()),
+            bytes: self.bytes,
+            validators,
+        }
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/** Counts kept while loading one file, for the `covid19db_sources` table.
+
+```
+use covid19db::loader::LoadStats;
+
+let mut stats = LoadStats::default();
+stats.date(2459000);
+stats.date(2458900);
+assert_eq!(stats.min_date_julian, Some(2458900));
+assert_eq!(stats.max_date_julian, Some(2459000));
+```
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadStats {
+    /// Records read from the file.
+    pub parsed: u64,
+    /// Rows inserted into the database; more than `parsed` if the loader fills in rows.
+    pub inserted: u64,
+    /// Records read but not loaded.
+    pub skipped: u64,
+    /// The earliest `date_julian` loaded, if the data has dates.
+    pub min_date_julian: Option<i32>,
+    /// The latest `date_julian` loaded, if the data has dates.
+    pub max_date_julian: Option<i32>,
+    /// Records that could not be parsed, and were left out.
+    pub rejects: Vec<Reject>,
+    /// U+FFFD replacement characters in the text loaded, marking bytes that
+    /// weren't valid in the source's [`Encoding`].
+    pub replacements: u64,
+}
+
+/// A record that could not be parsed, for the `load_rejects` table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reject {
+    /// The line number in the file, or the row number for sources that aren't text.
+    pub line: u64,
+    /// The text of the record, or empty if it isn't known.
+    pub record: String,
+    /// Why the record could not be parsed.
+    pub error: String,
+}
+
+impl LoadStats {
+    /// Widens the date range to take in `julian`.
+    pub fn date(&mut self, julian: i32) {
+        self.min_date_julian = Some(self.min_date_julian.map_or(julian, |x| x.min(julian)));
+        self.max_date_julian = Some(self.max_date_julian.map_or(julian, |x| x.max(julian)));
+    }
+
+    /** Notes that a record was left out because of `err`, if it is an
+    [`Error::RowParse`](crate::Error::RowParse).  Any other error is returned,
+    so that loaders can write `stats.reject(e)?` and go on to the next record.
+
+    ```
+    use covid19db::loader::LoadStats;
+    use covid19db::Error;
+
+    let mut stats = LoadStats::default();
+    let err = Error::RowParse {
+        source: String::from("test"),
+        line: 3,
+        message: String::from("invalid digit"),
+        record: String::from("1,x"),
+    };
+    stats.reject(err).unwrap();
+    assert_eq!(stats.rejected(), 1);
+    assert_eq!(stats.rejects[0].record, "1,x");
+    assert!(stats.reject(Error::Config(String::from("bad"))).is_err());
+    ```
+    */
+    pub fn reject(&mut self, err: Error) -> Result<()> {
+        match err {
+            Error::RowParse {
+                line,
+                message,
+                record,
+                ..
+            } => {
+                self.rejects.push(Reject {
+                    line,
+                    record,
+                    error: message,
+                });
+                Ok(())
+            }
+            e => Err(e),
+        }
+    }
+
+    /// The number of records rejected.
+    pub fn rejected(&self) -> u64 {
+        self.rejects.len() as u64
+    }
+}
+
+/// What a [`Source`] is given to load with, besides its files and the database.
+#[derive(Clone, Debug, Default)]
+pub struct LoadContext {
+    /// Where to report progress and warnings.
+    pub progress: Progress,
+    /// The part of the data to load.  Records outside it are to be left out,
+    /// and counted as skipped.
+    pub subset: Subset,
+    /// The encoding of the source's text files, as given by its
+    /// [`encoding`](Source::encoding); the registry sets it for each source.
+    pub encoding: Encoding,
+    /// The name the source is loaded under, if not its own, as when the
+    /// [source configuration](crate::loader::SourcesConfig) gives a built-in
+    /// loader's files another name; see [`source_name`](LoadContext::source_name).
+    pub source: Option<String>,
+    /// An earlier database whose identifiers to keep, as given by
+    /// [`LoadOptions::locids_from`](crate::loader::LoadOptions::locids_from).
+    /// Sources that give rows identifiers of their own, as `cdataset` does
+    /// locations, read it.
+    pub locids_from: Option<PathBuf>,
+    /// If set, records are parsed and counted but not inserted into the data
+    /// tables, as for [`Registry::dry_run`]; see
+    /// [`BulkInsert::discard`](crate::dbutil::BulkInsert::discard).
+    /// Small tables that other sources look things up in, such as
+    /// `loc_lookup`, are still written.
+    pub dry_run: bool,
+}
+
+impl LoadContext {
+    /// The name to record a source's headers, rejects and progress under:
+    /// [`source`](LoadContext::source) if set, or else `own`, its own name.
+    pub fn source_name<'a>(&'a self, own: &'a str) -> &'a str {
+        self.source.as_deref().unwrap_or(own)
+    }
+}
+
+/** Where a [`Source`]'s dated rows are kept, for the `source_coverage` table,
+which gives the first and last day each source, and each of its locations,
+has data for.
+
+```
+use covid19db::loader::Coverage;
+
+let coverage = Coverage::new("rtlive_raw", "state");
+assert_eq!(coverage.date_column, "date_julian");
+```
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coverage {
+    /// The table holding the rows, or a join of tables.
+    pub table: String,
+    /// An SQL expression naming the location each row is for.
+    pub location: String,
+    /// The column holding the Julian day of each row.
+    pub date_column: String,
+}
+
+impl Coverage {
+    /// Rows in `table`, dated by their `date_julian` column.
+    pub fn new(table: &str, location: &str) -> Self {
+        Coverage {
+            table: String::from(table),
+            location: String::from(location),
+            date_column: String::from("date_julian"),
+        }
+    }
+}
+
+/** A source of data for the database.  Each source owns a set of tables and
+views, knows where to get its files, and how to load them.
+
+Sources are collected in a [`Registry`], which the loader walks in order.  To
+add a source of your own, implement this trait and
+[`register`](Registry::register) it.  */
+#[async_trait]
+pub trait Source: Send + Sync {
+    /// The short name of the source, as used with `--only` and `--skip`.
+    fn name(&self) -> &str;
+
+    /// Names of other sources whose data this one uses while loading.  These
+    /// must be earlier in the [`Registry`].
+    fn dependencies(&self) -> Vec<&str> {
+        Vec::new()
+    }
+
+    /// The files this source reads.
+    fn files(&self) -> Vec<SourceFile>;
+
+    /// The names of the datasets held in `files`, among which
+    /// [`Subset::datasets`](crate::loader::Subset::datasets) chooses; empty
+    /// for a source that isn't divided into datasets.
+    fn datasets(&self, _files: &[SourceFile]) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The character encoding of the source's text files.
+    fn encoding(&self) -> Encoding {
+        Encoding::Utf8
+    }
+
+    /// Statements to drop and re-create this source's tables, indices, and views.
+    fn schema(&self) -> Vec<String>;
+
+    /// Where the source's dated rows are, for `source_coverage`; `None` for a
+    /// source without dates.
+    fn coverage(&self) -> Option<Coverage> {
+        None
+    }
+
+    /// Makes this source's files available for loading, returning their paths
+    /// in the same order as [`files`](Source::files).  The files are fetched
+    /// concurrently.
+    async fn fetch(&self, fetcher: &Fetcher) -> Result<Vec<PathBuf>> {
+        let files = self.files();
+        try_join_all(files.iter().map(|sf| fetcher.obtain(sf))).await
+    }
+
+    /// Loads the files returned by [`fetch`](Source::fetch) into the database,
+    /// returning what was loaded from each, in the same order.
+    async fn load(
+        &self,
+        files: &[PathBuf],
+        pool: &mut SqlitePool,
+        ctx: &LoadContext,
+    ) -> Result<Vec<LoadStats>>;
+
+    /// Whom to credit for the source's data, for `covid19db_attribution`.  By
+    /// default, the built-in credits for a source of this name.
+    fn attribution(&self) -> Vec<Credit> {
+        Credits::builtin().for_source(self.name())
+    }
+
+    /// Checks the loaded data, returning [`Error::Check`](crate::Error::Check)
+    /// if something is wrong.  This is for checks that can't be expressed in a
+    /// check file (see [`validate`](crate::validate)); the built-in sources have none.
+    async fn check(&self, _pool: &mut SqlitePool) -> Result<()> {
+        Ok(())
+    }
+}
+
+/** An ordered collection of [`Source`]s.  Sources are fetched concurrently,
+and loaded in the order in which their files arrive, but never before the
+sources they depend on; see [`Registry::load`]. */
+#[derive(Default)]
+pub struct Registry {
+    sources: Vec<Box<dyn Source>>,
+    disabled: Vec<String>,
+}
+
+impl Registry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    /** Adds a source to the end of the registry.  A source with the same name
+    as one already present replaces it in place. */
+    pub fn register(&mut self, source: Box<dyn Source>) {
+        match self.sources.iter().position(|x| x.name() == source.name()) {
+            Some(idx) => self.sources[idx] = source,
+            None => self.sources.push(source),
+        }
+    }
+
+    /** Keeps the named source from being selected.  Its schema is still
+    created, so that views using its tables remain valid. */
+    pub fn disable(&mut self, name: &str) {
+        if !self.is_disabled(name) {
+            self.disabled.push(String::from(name));
+        }
+    }
+
+    /// Returns whether the named source has been [`disable`](Registry::disable)d.
+    pub fn is_disabled(&self, name: &str) -> bool {
+        self.disabled.iter().any(|x| x == name)
+    }
+
+    /// Returns the source with the given name, if any.
+    pub fn get(&self, name: &str) -> Option<&dyn Source> {
+        self.sources
+            .iter()
+            .find(|x| x.name() == name)
+            .map(|x| x.as_ref())
+    }
+
+    /// Returns the names of all registered sources, in order.
+    pub fn names(&self) -> Vec<&str> {
+        self.sources.iter().map(|x| x.name()).collect()
+    }
+
+    /// Iterates over the registered sources, in order.
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Source> {
+        self.sources.iter().map(|x| x.as_ref())
+    }
+}
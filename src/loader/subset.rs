@@ -0,0 +1,374 @@
+/* Subset builds
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use chrono::NaiveDate;
+
+use crate::dateutil::nd_to_day;
+use crate::{Error, Result};
+
+/** The part of the data to build, for a database smaller than the whole.
+Each loader checks its records against the subset as it reads them, and
+leaves out, as skipped, those that fall outside it.  Every criterion left
+empty admits everything.
+
+The `countries` criterion applies to every place.  The `states` and `fips`
+criteria apply only to places in the US, and leave out the US as a whole,
+whose figures take in other states; places elsewhere are unaffected by them.
+
+```
+use chrono::NaiveDate;
+use covid19db::loader::{Place, Subset};
+
+let subset = Subset {
+    countries: vec![String::from("US")],
+    states: vec![String::from("KS")],
+    ..Subset::default()
+};
+assert!(subset.keep_place(&Place::us_county("Kansas", Some(20079))));
+assert!(subset.keep_place(&Place::us_state("Kansas")));
+assert!(!subset.keep_place(&Place::us_state("MO")));
+assert!(!subset.keep_place(&Place::country("USA")));
+assert!(!subset.keep_place(&Place::country("FR")));
+
+let subset = Subset {
+    fips: vec![String::from("20079")],
+    from: Some(NaiveDate::from_ymd(2020, 6, 1)),
+    ..Subset::default()
+};
+assert!(subset.keep_place(&Place::us_county("Kansas", Some(20079))));
+assert!(!subset.keep_place(&Place::us_state("Kansas")));
+assert!(subset.keep_place(&Place::country("FRA")));
+assert!(!subset.keep_date(&NaiveDate::from_ymd(2020, 5, 31)));
+```
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Subset {
+    /// The earliest date to load.
+    pub from: Option<NaiveDate>,
+    /// The latest date to load.
+    pub to: Option<NaiveDate>,
+    /// Countries to load, as ISO 3166-1 codes, either alpha-2 or alpha-3.
+    pub countries: Vec<String>,
+    /// US states to load, by name, postal code or FIPS code.
+    pub states: Vec<String>,
+    /// Prefixes of the FIPS codes of the US places to load: "20" for Kansas
+    /// and its counties, "20079" for Harvey County alone.
+    pub fips: Vec<String>,
+    /// `cdataset` datasets to load, such as "jhu/daily".
+    pub datasets: Vec<String>,
+}
+
+/// Where a record's data is from, for [`Subset::keep_place`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Place<'a> {
+    /// The ISO 3166-1 code of the country, either alpha-2 or alpha-3.
+    pub country: Option<&'a str>,
+    /// For places in the US, the state, by name or postal code.
+    pub state: Option<&'a str>,
+    /// For places in the US, the FIPS code: that of the state, or of the county.
+    pub fips: Option<i64>,
+}
+
+impl<'a> Place<'a> {
+    /// A whole country.
+    pub fn country(code: &'a str) -> Self {
+        Place {
+            country: Some(code),
+            ..Place::default()
+        }
+    }
+
+    /// A US state.
+    pub fn us_state(state: &'a str) -> Self {
+        Place {
+            country: Some("US"),
+            state: Some(state),
+            fips: None,
+        }
+    }
+
+    /// A US county, in `state`, with the given FIPS code if it has one.
+    pub fn us_county(state: &'a str, fips: Option<i64>) -> Self {
+        Place {
+            country: Some("US"),
+            state: Some(state),
+            fips,
+        }
+    }
+}
+
+/// The US states and territories: name, postal code and FIPS code.
+const STATES: &[(&str, &str, i64)] = &[
+    ("Alabama", "AL", 1),
+    ("Alaska", "AK", 2),
+    ("Arizona", "AZ", 4),
+    ("Arkansas", "AR", 5),
+    ("California", "CA", 6),
+    ("Colorado", "CO", 8),
+    ("Connecticut", "CT", 9),
+    ("Delaware", "DE", 10),
+    ("District of Columbia", "DC", 11),
+    ("Florida", "FL", 12),
+    ("Georgia", "GA", 13),
+    ("Hawaii", "HI", 15),
+    ("Idaho", "ID", 16),
+    ("Illinois", "IL", 17),
+    ("Indiana", "IN", 18),
+    ("Iowa", "IA", 19),
+    ("Kansas", "KS", 20),
+    ("Kentucky", "KY", 21),
+    ("Louisiana", "LA", 22),
+    ("Maine", "ME", 23),
+    ("Maryland", "MD", 24),
+    ("Massachusetts", "MA", 25),
+    ("Michigan", "MI", 26),
+    ("Minnesota", "MN", 27),
+    ("Mississippi", "MS", 28),
+    ("Missouri", "MO", 29),
+    ("Montana", "MT", 30),
+    ("Nebraska", "NE", 31),
+    ("Nevada", "NV", 32),
+    ("New Hampshire", "NH", 33),
+    ("New Jersey", "NJ", 34),
+    ("New Mexico", "NM", 35),
+    ("New York", "NY", 36),
+    ("North Carolina", "NC", 37),
+    ("North Dakota", "ND", 38),
+    ("Ohio", "OH", 39),
+    ("Oklahoma", "OK", 40),
+    ("Oregon", "OR", 41),
+    ("Pennsylvania", "PA", 42),
+    ("Rhode Island", "RI", 44),
+    ("South Carolina", "SC", 45),
+    ("South Dakota", "SD", 46),
+    ("Tennessee", "TN", 47),
+    ("Texas", "TX", 48),
+    ("Utah", "UT", 49),
+    ("Vermont", "VT", 50),
+    ("Virginia", "VA", 51),
+    ("Washington", "WA", 53),
+    ("West Virginia", "WV", 54),
+    ("Wisconsin", "WI", 55),
+    ("Wyoming", "WY", 56),
+    ("American Samoa", "AS", 60),
+    ("Guam", "GU", 66),
+    ("Northern Mariana Islands", "MP", 69),
+    ("Puerto Rico", "PR", 72),
+    ("Virgin Islands", "VI", 78),
+];
+
+/// ISO 3166-1 alpha-2 and alpha-3 codes, in pairs.
+const COUNTRIES: &str = "
+    AD AND AE ARE AF AFG AG ATG AI AIA AL ALB AM ARM AO AGO AQ ATA AR ARG AS ASM AT AUT
+    AU AUS AW ABW AX ALA AZ AZE BA BIH BB BRB BD BGD BE BEL BF BFA BG BGR BH BHR BI BDI
+    BJ BEN BL BLM BM BMU BN BRN BO BOL BQ BES BR BRA BS BHS BT BTN BV BVT BW BWA BY BLR
+    BZ BLZ CA CAN CC CCK CD COD CF CAF CG COG CH CHE CI CIV CK COK CL CHL CM CMR CN CHN
+    CO COL CR CRI CU CUB CV CPV CW CUW CX CXR CY CYP CZ CZE DE DEU DJ DJI DK DNK DM DMA
+    DO DOM DZ DZA EC ECU EE EST EG EGY EH ESH ER ERI ES ESP ET ETH FI FIN FJ FJI FK FLK
+    FM FSM FO FRO FR FRA GA GAB GB GBR GD GRD GE GEO GF GUF GG GGY GH GHA GI GIB GL GRL
+    GM GMB GN GIN GP GLP GQ GNQ GR GRC GS SGS GT GTM GU GUM GW GNB GY GUY HK HKG HM HMD
+    HN HND HR HRV HT HTI HU HUN ID IDN IE IRL IL ISR IM IMN IN IND IO IOT IQ IRQ IR IRN
+    IS ISL IT ITA JE JEY JM JAM JO JOR JP JPN KE KEN KG KGZ KH KHM KI KIR KM COM KN KNA
+    KP PRK KR KOR KW KWT KY CYM KZ KAZ LA LAO LB LBN LC LCA LI LIE LK LKA LR LBR LS LSO
+    LT LTU LU LUX LV LVA LY LBY MA MAR MC MCO MD MDA ME MNE MF MAF MG MDG MH MHL MK MKD
+    ML MLI MM MMR MN MNG MO MAC MP MNP MQ MTQ MR MRT MS MSR MT MLT MU MUS MV MDV MW MWI
+    MX MEX MY MYS MZ MOZ NA NAM NC NCL NE NER NF NFK NG NGA NI NIC NL NLD NO NOR NP NPL
+    NR NRU NU NIU NZ NZL OM OMN PA PAN PE PER PF PYF PG PNG PH PHL PK PAK PL POL PM SPM
+    PN PCN PR PRI PS PSE PT PRT PW PLW PY PRY QA QAT RE REU RO ROU RS SRB RU RUS RW RWA
+    SA SAU SB SLB SC SYC SD SDN SE SWE SG SGP SH SHN SI SVN SJ SJM SK SVK SL SLE SM SMR
+    SN SEN SO SOM SR SUR SS SSD ST STP SV SLV SX SXM SY SYR SZ SWZ TC TCA TD TCD TF ATF
+    TG TGO TH THA TJ TJK TK TKL TL TLS TM TKM TN TUN TO TON TR TUR TT TTO TV TUV TW TWN
+    TZ TZA UA UKR UG UGA UM UMI US USA UY URY UZ UZB VA VAT VC VCT VE VEN VG VGB VI VIR
+    VN VNM VU VUT WF WLF WS WSM XK XKX YE YEM YT MYT ZA ZAF ZM ZMB ZW ZWE
+";
+
+/// Returns the alpha-2 code of the country with the ISO 3166-1 code `code`.
+fn country_alpha2(code: &str) -> Option<&'static str> {
+    let mut codes = COUNTRIES.split_whitespace();
+    while let (Some(alpha2), Some(alpha3)) = (codes.next(), codes.next()) {
+        if alpha2.eq_ignore_ascii_case(code) || alpha3.eq_ignore_ascii_case(code) {
+            return Some(alpha2);
+        }
+    }
+    None
+}
+
+/// True if the ISO 3166-1 codes `a` and `b` are for the same country.
+fn same_country(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+        || (a.len() != b.len() && country_alpha2(a).is_some_and(|x| country_alpha2(b) == Some(x)))
+}
+
+/// Returns the FIPS code of the US state named, or with the postal or FIPS code, `state`.
+fn state_fips(state: &str) -> Option<i64> {
+    STATES
+        .iter()
+        .find(|(name, postal, fips)| {
+            name.eq_ignore_ascii_case(state)
+                || postal.eq_ignore_ascii_case(state)
+                || state.parse() == Ok(*fips)
+        })
+        .map(|(_, _, fips)| *fips)
+}
+
+impl Subset {
+    /// True if the subset takes in all of the data.
+    pub fn is_everything(&self) -> bool {
+        *self == Subset::default()
+    }
+
+    /// Returns an error describing the first criterion that names no known
+    /// place, or a date range that is empty.
+    pub fn check(&self) -> Result<()> {
+        if let Some(code) = self.countries.iter().find(|x| country_alpha2(x).is_none()) {
+            return Err(Error::Config(format!(
+                "Unknown country {:?}; use an ISO 3166-1 code such as US or USA",
+                code
+            )));
+        }
+        if let Some(state) = self.states.iter().find(|x| state_fips(x).is_none()) {
+            return Err(Error::Config(format!(
+                "Unknown US state {:?}; use its name or postal code",
+                state
+            )));
+        }
+        if let Some(prefix) = self
+            .fips
+            .iter()
+            .find(|x| x.is_empty() || x.len() > 5 || !x.bytes().all(|y| y.is_ascii_digit()))
+        {
+            return Err(Error::Config(format!(
+                "Invalid FIPS prefix {:?}; expected up to 5 digits",
+                prefix
+            )));
+        }
+        match (self.from, self.to) {
+            (Some(from), Some(to)) if from > to => Err(Error::Config(format!(
+                "The subset starts on {}, after it ends on {}",
+                from, to
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns an error naming the first of [`datasets`](Subset::datasets)
+    /// that is not among `known`, the datasets of the configured sources.
+    pub fn check_datasets(&self, known: &[String]) -> Result<()> {
+        match self.datasets.iter().find(|x| !known.contains(x)) {
+            Some(dataset) => Err(Error::Config(format!(
+                "Unknown dataset {:?}; valid datasets are: {}",
+                dataset,
+                known.join(", ")
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// True if data for `place` on `date` is to be loaded.
+    pub fn keep(&self, date: &NaiveDate, place: &Place) -> bool {
+        self.keep_date(date) && self.keep_place(place)
+    }
+
+    /// True if data for `date` is to be loaded.
+    pub fn keep_date(&self, date: &NaiveDate) -> bool {
+        self.keep_julian(nd_to_day(date))
+    }
+
+    /// Like [`keep_date`](Subset::keep_date), for a Julian day.
+    pub fn keep_julian(&self, julian: i32) -> bool {
+        self.from.is_none_or(|x| julian >= nd_to_day(&x))
+            && self.to.is_none_or(|x| julian <= nd_to_day(&x))
+    }
+
+    /// True if the `cdataset` dataset named `dataset` is to be loaded.
+    pub fn keep_dataset(&self, dataset: &str) -> bool {
+        self.datasets.is_empty() || self.datasets.iter().any(|x| x == dataset)
+    }
+
+    /// True if data for `place` is to be loaded.  A place whose country isn't
+    /// known is left out if `countries` is given.
+    pub fn keep_place(&self, place: &Place) -> bool {
+        let country = place.country.filter(|x| !x.is_empty());
+        if !self.countries.is_empty()
+            && !country.is_some_and(|x| self.countries.iter().any(|y| same_country(y, x)))
+        {
+            return false;
+        }
+        if (self.states.is_empty() && self.fips.is_empty())
+            || !country.is_some_and(|x| same_country("US", x))
+        {
+            return true;
+        }
+        let fips = place.fips.filter(|x| *x > 0);
+        let state = place
+            .state
+            .and_then(state_fips)
+            .or_else(|| fips.map(|x| if x < 100 { x } else { x / 1000 }));
+        if !self.states.is_empty()
+            && !state.is_some_and(|x| self.states.iter().any(|y| state_fips(y) == Some(x)))
+        {
+            return false;
+        }
+        if !self.fips.is_empty() {
+            let code = match (fips, state) {
+                (Some(x), _) if x >= 100 => format!("{:05}", x),
+                (_, Some(x)) => format!("{:02}", x),
+                _ => return false,
+            };
+            if !self.fips.iter().any(|x| code.starts_with(x.as_str())) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /** The criteria given, as `covid19db_meta` fields and values, which are
+    stored in the database so that its users can tell what it holds.
+
+    ```
+    use covid19db::loader::Subset;
+
+    let subset = Subset {
+        states: vec![String::from("Kansas"), String::from("MO")],
+        ..Subset::default()
+    };
+    assert_eq!(subset.meta(), vec![("subset:states", String::from("Kansas,MO"))]);
+    assert!(Subset::default().meta().is_empty());
+    ```
+    */
+    pub fn meta(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        if let Some(from) = self.from {
+            fields.push(("subset:from", from.to_string()));
+        }
+        if let Some(to) = self.to {
+            fields.push(("subset:to", to.to_string()));
+        }
+        for (field, values) in &[
+            ("subset:countries", &self.countries),
+            ("subset:states", &self.states),
+            ("subset:fips", &self.fips),
+            ("subset:datasets", &self.datasets),
+        ] {
+            if !values.is_empty() {
+                fields.push((*field, values.join(",")));
+            }
+        }
+        fields
+    }
+}
@@ -16,9 +16,436 @@ Copyright (c) 2019-2020 John Goerzen
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use covid19db::loader;
+use chrono::{NaiveDate, TimeZone, Utc};
+use covid19db::attribution::Credits;
+use covid19db::dbutil;
+use covid19db::loader::{self, DownloadOptions, LoadOptions, Manifest, Subset};
+use covid19db::progress::{JsonLines, Progress, Silent, Terminal};
+use covid19db::validate::{CheckResult, Outcome};
+use sqlx::prelude::*;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// Build and inspect the COVID-19 database.  With no subcommand, builds
+/// `covid19.db` in the current directory from all sources.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "covid19db-loader")]
+struct Opt {
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+// Parsed once, so the size of `Build` doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Download the data and build the database
+    Build {
+        /// Path of the database to write
+        #[structopt(short, long, parse(from_os_str), default_value = "covid19.db")]
+        output: PathBuf,
+        /// Read source files from this directory, as populated by `fetch`, instead of downloading them
+        #[structopt(short, long, parse(from_os_str))]
+        input_dir: Option<PathBuf>,
+        /// Update an existing database, reloading only the sources whose files have changed
+        #[structopt(long)]
+        incremental: bool,
+        /// Keep the database being replaced as `<output>.prev`
+        #[structopt(long)]
+        keep_previous: bool,
+        /// Continue an unfinished build from `<output>.partial`, skipping the sources it completed
+        #[structopt(long)]
+        resume: bool,
+        /// Parse every source and report what was found, without writing the database.
+        /// The files are still fetched into a temporary directory, so this needs as much
+        /// space as they take and as long as fetching and reading them
+        #[structopt(long, conflicts_with_all = &["incremental", "keep-previous", "resume"])]
+        dry_run: bool,
+        /// Also run the checks in this TOML file
+        #[structopt(long, parse(from_os_str))]
+        checks: Option<PathBuf>,
+        /// Fail if a source's latest data was more than this many days old when last fetched or found unchanged
+        #[structopt(long)]
+        max_staleness: Option<u32>,
+        /// Keep the cdataset location ids of this database, typically the one being replaced; its hash is recorded in the manifest
+        #[structopt(long, parse(from_os_str))]
+        locids_from: Option<PathBuf>,
+        /// Fail if more than this many records of a source can't be parsed
+        #[structopt(long, default_value = "10")]
+        max_rejects: u64,
+        /// Set --max-rejects for particular sources, as SOURCE=N (comma-separated)
+        #[structopt(long, use_delimiter = true, parse(try_from_str = parse_budget))]
+        max_rejects_for: Vec<(String, u64)>,
+        /// Record this time, in seconds since 1970, as when each file was fetched, so that builds from the same files are byte-identical
+        #[structopt(long, env = "SOURCE_DATE_EPOCH")]
+        source_date_epoch: Option<i64>,
+        #[structopt(flatten)]
+        selection: Selection,
+        #[structopt(flatten)]
+        subsetting: Subsetting,
+        #[structopt(flatten)]
+        network: Network,
+        #[structopt(flatten)]
+        reporting: Reporting,
+    },
+    /// Download the source files into a directory for later use with `build --input-dir`
+    Fetch {
+        /// Directory in which to store the files
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+        #[structopt(flatten)]
+        selection: Selection,
+        #[structopt(flatten)]
+        network: Network,
+        #[structopt(flatten)]
+        reporting: Reporting,
+    },
+    /// Run the checks against an existing database and report the outcome of each
+    Verify {
+        /// Path of the database to check
+        #[structopt(long, parse(from_os_str), default_value = "covid19.db")]
+        db: PathBuf,
+        /// Also run the checks in this TOML file
+        #[structopt(long, parse(from_os_str))]
+        checks: Option<PathBuf>,
+        /// Fail if a source's latest data was more than this many days old when last fetched or found unchanged
+        #[structopt(long)]
+        max_staleness: Option<u32>,
+        /// Also check that the database matches this manifest, as written by `build`
+        #[structopt(long, parse(from_os_str))]
+        manifest: Option<PathBuf>,
+        #[structopt(flatten)]
+        selection: Selection,
+    },
+    /// Write a table or view of an existing database as CSV
+    Export {
+        /// Path of the database to read
+        #[structopt(long, parse(from_os_str), default_value = "covid19.db")]
+        db: PathBuf,
+        /// File to write; standard output if not given
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+        /// Table or view to export
+        table: String,
+    },
+    /// Print whom to credit for the data in the given tables and views, and their licenses
+    Cite {
+        /// Path of the database to read
+        #[structopt(long, parse(from_os_str), default_value = "covid19.db")]
+        db: PathBuf,
+        /// Print plain text, or BibTeX entries for the publications to cite
+        #[structopt(long, default_value = "text", possible_values = &["text", "bibtex"])]
+        format: String,
+        /// Tables and views used; all of them if none are given
+        tables: Vec<String>,
+    },
+    /// Show the metadata and row counts of an existing database
+    Info {
+        /// Path of the database to read
+        #[structopt(long, parse(from_os_str), default_value = "covid19.db")]
+        db: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+struct Selection {
+    /// Process only these sources (comma-separated)
+    #[structopt(long, use_delimiter = true)]
+    only: Vec<String>,
+    /// Do not process these sources (comma-separated)
+    #[structopt(long, use_delimiter = true)]
+    skip: Vec<String>,
+    /// Read source URLs, pins and enablement from this TOML file, on top of the built-in ones
+    #[structopt(long, parse(from_os_str))]
+    sources: Option<PathBuf>,
+}
+
+impl Selection {
+    fn options(&self, output: PathBuf) -> LoadOptions {
+        let opts = LoadOptions {
+            output,
+            only: self.only.clone(),
+            skip: self.skip.clone(),
+            sources: self.sources.clone(),
+            ..LoadOptions::default()
+        };
+        if let Err(e) = loader::registry(&opts).and_then(|x| x.select(&opts).map(drop)) {
+            fail::<_, ()>(e);
+        }
+        opts
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct Subsetting {
+    /// Load only data from this date on (YYYY-MM-DD)
+    #[structopt(long)]
+    from: Option<NaiveDate>,
+    /// Load only data up to this date (YYYY-MM-DD)
+    #[structopt(long)]
+    to: Option<NaiveDate>,
+    /// Load only these countries, as ISO codes such as US or USA (comma-separated)
+    #[structopt(long, use_delimiter = true)]
+    countries: Vec<String>,
+    /// Load only these US states, by name or postal code (comma-separated)
+    #[structopt(long, use_delimiter = true)]
+    states: Vec<String>,
+    /// Load only the US places whose FIPS codes start with these (comma-separated)
+    #[structopt(long, use_delimiter = true)]
+    fips: Vec<String>,
+    /// Load only these cdataset datasets, such as jhu/daily (comma-separated)
+    #[structopt(long, use_delimiter = true)]
+    datasets: Vec<String>,
+}
+
+impl Subsetting {
+    fn subset(&self) -> Subset {
+        Subset {
+            from: self.from,
+            to: self.to,
+            countries: self.countries.clone(),
+            states: self.states.clone(),
+            fips: self.fips.clone(),
+            datasets: self.datasets.clone(),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct Network {
+    /// Retry a failed download this many times
+    #[structopt(long, default_value = "5")]
+    retries: u32,
+    /// Give up on a download attempt after this many seconds without data
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+    /// Download at most this many files at once
+    #[structopt(short, long, default_value = "4")]
+    jobs: usize,
+}
+
+#[derive(Debug, StructOpt)]
+struct Reporting {
+    /// How to report progress: `terminal`, `json` (one JSON object per line), or `silent`
+    #[structopt(long, default_value = "terminal", possible_values = &["terminal", "json", "silent"])]
+    progress: String,
+}
+
+impl Reporting {
+    fn progress(&self) -> Progress {
+        match self.progress.as_str() {
+            "json" => Progress::new(JsonLines::stdout()),
+            "silent" => Progress::new(Silent),
+            _ => Progress::new(Terminal),
+        }
+    }
+}
+
+impl Network {
+    fn options(&self) -> DownloadOptions {
+        DownloadOptions {
+            retries: self.retries,
+            read_timeout: Duration::from_secs(self.timeout),
+            concurrency: self.jobs,
+            ..DownloadOptions::default()
+        }
+    }
+}
+
+fn parse_budget(arg: &str) -> Result<(String, u64), String> {
+    let (source, n) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected SOURCE=N, got {:?}", arg))?;
+    let n = n.parse().map_err(|e| format!("{:?}: {}", arg, e))?;
+    Ok((String::from(source), n))
+}
+
+fn fail<E: std::fmt::Display, T>(e: E) -> T {
+    eprintln!("covid19db-loader: {}", e);
+    exit(1);
+}
+
+fn existing(db: &Path) {
+    if !db.exists() {
+        fail::<_, ()>(format!("{}: no such database", db.display()));
+    }
+}
+
+async fn info(db: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = dbutil::open_pool(db, 1).await?;
+    let mut conn = pool.acquire().await?;
+    let relations = dbutil::list_relations(&mut conn).await?;
+    if relations.iter().any(|(name, _)| name == "covid19db_meta") {
+        let meta: Vec<(String, String)> = sqlx::query_as("SELECT field, value FROM covid19db_meta")
+            .fetch_all(&mut conn)
+            .await?;
+        for (field, value) in meta {
+            println!("{}: {}", field, value);
+        }
+    }
+    for (name, reltype) in relations {
+        let rows = dbutil::count_rows(&mut conn, &name).await?;
+        println!("{} {}: {} rows", reltype, name, rows);
+    }
+    Ok(())
+}
+
+async fn export(
+    db: &Path,
+    table: &str,
+    output: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = dbutil::open_pool(db, 1).await?;
+    let mut conn = pool.acquire().await?;
+    match output {
+        Some(path) => {
+            let mut wtr = csv::Writer::from_writer(File::create(path)?);
+            dbutil::export_csv(&mut conn, table, &mut wtr).await?;
+        }
+        None => {
+            let mut wtr = csv::Writer::from_writer(io::stdout());
+            dbutil::export_csv(&mut conn, table, &mut wtr).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn cite(
+    db: &Path,
+    format: &str,
+    tables: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = dbutil::open_pool(db, 1).await?;
+    let mut conn = pool.acquire().await?;
+    let relations = dbutil::list_relations(&mut conn).await?;
+    if let Some(table) = tables
+        .iter()
+        .find(|x| !relations.iter().any(|(name, _)| name == *x))
+    {
+        return Err(format!("{:#?} has no table or view {}", db, table).into());
+    }
+    let tables: Vec<&str> = tables.iter().map(String::as_str).collect();
+    let credits = Credits::read(&mut conn).await?.covering(&tables);
+    match format {
+        "bibtex" => print!("{}", credits.bibtex()),
+        _ => print!("{}", credits.text()),
+    }
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
-    loader::load().await;
+    match Opt::from_args().cmd {
+        None => loader::load().await.unwrap_or_else(fail),
+        Some(Command::Build {
+            output,
+            input_dir,
+            incremental,
+            keep_previous,
+            resume,
+            dry_run,
+            checks,
+            max_staleness,
+            locids_from,
+            max_rejects,
+            max_rejects_for,
+            source_date_epoch,
+            selection,
+            subsetting,
+            network,
+            reporting,
+        }) => {
+            let opts = LoadOptions {
+                progress: reporting.progress(),
+                input_dir,
+                incremental,
+                keep_previous,
+                resume,
+                checks,
+                max_staleness,
+                locids_from,
+                max_rejects,
+                max_rejects_for: max_rejects_for.into_iter().collect(),
+                download: network.options(),
+                subset: subsetting.subset(),
+                timestamp: source_date_epoch.map(|x| Utc.timestamp(x, 0)),
+                ..selection.options(output)
+            };
+            if dry_run {
+                let report = loader::dry_run(&opts).await.unwrap_or_else(fail);
+                println!("{}", report);
+                if !report.passed() {
+                    exit(1);
+                }
+            } else {
+                loader::load_with(&opts).await.unwrap_or_else(fail)
+            }
+        }
+        Some(Command::Fetch {
+            dir,
+            selection,
+            network,
+            reporting,
+        }) => {
+            let opts = LoadOptions {
+                progress: reporting.progress(),
+                download: network.options(),
+                ..selection.options(PathBuf::new())
+            };
+            loader::fetch(&opts, &dir).await.unwrap_or_else(fail)
+        }
+        Some(Command::Verify {
+            db,
+            checks,
+            max_staleness,
+            manifest,
+            selection,
+        }) => {
+            existing(&db);
+            let opts = LoadOptions {
+                checks,
+                max_staleness,
+                ..selection.options(db.clone())
+            };
+            let mut report = loader::verify(&opts).await.unwrap_or_else(fail);
+            if let Some(path) = manifest {
+                let expected = Manifest::from_file(&path).unwrap_or_else(fail);
+                let found = Manifest::of(&db).await.unwrap_or_else(fail);
+                let differences = expected.differences(&found);
+                report.results.push(CheckResult {
+                    check: format!("matches {}", path.display()),
+                    outcome: if differences.is_empty() {
+                        Outcome::Pass
+                    } else {
+                        Outcome::Fail
+                    },
+                    detail: if differences.is_empty() {
+                        String::from("database and inputs match")
+                    } else {
+                        differences.join("; ")
+                    },
+                });
+            }
+            println!("{}", report);
+            if !report.passed() {
+                exit(1);
+            }
+        }
+        Some(Command::Export { db, output, table }) => {
+            existing(&db);
+            export(&db, &table, output).await.unwrap_or_else(fail)
+        }
+        Some(Command::Cite { db, format, tables }) => {
+            existing(&db);
+            cite(&db, &format, &tables).await.unwrap_or_else(fail)
+        }
+        Some(Command::Info { db }) => {
+            existing(&db);
+            info(&db).await.unwrap_or_else(fail)
+        }
+    }
 }
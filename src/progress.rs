@@ -0,0 +1,346 @@
+/* Progress reporting
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Reports on what the loader is doing.
+
+Everything the loader has to say while it works is sent, as an [`Event`], to
+an [`Observer`].  [`Terminal`] prints it for a person to read, [`JsonLines`]
+writes it as newline-delimited JSON for other programs, and [`Silent`]
+discards it.  A program embedding the loader can implement [`Observer`] to
+track builds itself. */
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::loader::HeaderDrift;
+use crate::validate::CheckResult;
+
+/// Something that happened during a build.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A step of the build not tied to one source, such as vacuuming.
+    Step { message: String },
+    /// A statement is being run to set up the schema.
+    Statement { sql: String },
+    /// Downloading `url` into `path` has begun.
+    DownloadStarted { url: String, path: PathBuf },
+    /// `bytes` of `url` have arrived, out of `total` if the server said.
+    /// Sent every megabyte or so, and when the download is complete.
+    Downloaded {
+        url: String,
+        bytes: u64,
+        total: Option<u64>,
+    },
+    /// A download failed with `error`, and will be tried again after `delay_ms`.
+    Retrying {
+        url: String,
+        error: String,
+        delay_ms: u64,
+    },
+    /// The server says `url` hasn't changed since it was last downloaded.
+    Unchanged { url: String },
+    /// Loading `source` has begun.
+    SourceStarted { source: String },
+    /// `source` is unchanged, so an incremental build leaves it alone.
+    SourceSkipped { source: String },
+    /// `source` was loaded before a resumed build was interrupted, so is left alone.
+    SourceResumed { source: String },
+    /// `processed` records of `source` have been read, out of `total` if known.
+    Rows {
+        source: String,
+        processed: u64,
+        total: Option<u64>,
+    },
+    /// `source` has been loaded and committed to the database.
+    Committed {
+        source: String,
+        parsed: u64,
+        inserted: u64,
+        skipped: u64,
+        rejected: u64,
+    },
+    /// The columns of a file of `source` differ from those its loader knows.
+    HeaderDrift {
+        source: String,
+        #[serde(flatten)]
+        drift: HeaderDrift,
+    },
+    /// Something looks wrong, but the build goes on.
+    Warning {
+        source: Option<String>,
+        message: String,
+    },
+    /// A check on the loaded data has been run.
+    Check(CheckResult),
+    /// All the checks have been run.
+    Checked {
+        passed: usize,
+        warnings: usize,
+        failed: usize,
+    },
+    /// The database at `output` is complete.
+    Finished { output: PathBuf },
+}
+
+/// Receives the [`Event`]s of a build.  Events may come from several tasks at once.
+pub trait Observer: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+/** A shared handle to an [`Observer`], as kept in the loader's options.
+Cloning it is cheap, and the default prints to the terminal.
+
+```
+use covid19db::progress::{Event, Observer, Progress};
+use std::sync::{Arc, Mutex};
+
+struct Collect(Mutex<Vec<Event>>);
+
+impl Observer for Collect {
+    fn event(&self, event: &Event) {
+        self.0.lock().unwrap().push(event.clone());
+    }
+}
+
+let seen = Arc::new(Collect(Mutex::new(Vec::new())));
+let progress = Progress::from(seen.clone());
+progress.warning(Some("owid"), "something odd");
+assert_eq!(
+    seen.0.lock().unwrap()[0],
+    Event::Warning {
+        source: Some(String::from("owid")),
+        message: String::from("something odd"),
+    }
+);
+```
+*/
+#[derive(Clone)]
+pub struct Progress(Arc<dyn Observer>);
+
+impl Progress {
+    pub fn new<O: Observer + 'static>(observer: O) -> Self {
+        Progress(Arc::new(observer))
+    }
+
+    /// A handle that discards every event.
+    pub fn silent() -> Self {
+        Progress::new(Silent)
+    }
+
+    /// Passes `event` to the observer.
+    pub fn event(&self, event: Event) {
+        self.0.event(&event);
+    }
+
+    /// Sends an [`Event::Step`].
+    pub fn step<S: Into<String>>(&self, message: S) {
+        self.event(Event::Step {
+            message: message.into(),
+        });
+    }
+
+    /// Sends an [`Event::Warning`].
+    pub fn warning<S: Into<String>>(&self, source: Option<&str>, message: S) {
+        self.event(Event::Warning {
+            source: source.map(String::from),
+            message: message.into(),
+        });
+    }
+}
+
+impl<O: Observer + 'static> From<Arc<O>> for Progress {
+    fn from(observer: Arc<O>) -> Self {
+        Progress(observer)
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress::new(Terminal)
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// The observer doesn't change what is built, so it is ignored when comparing options.
+impl PartialEq for Progress {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+/// Prints events to standard output for a person to read.
+pub struct Terminal;
+
+impl Observer for Terminal {
+    fn event(&self, event: &Event) {
+        match event {
+            Event::Step { message } => println!("{}", message),
+            Event::Statement { sql } => println!("PREP: executing {}", sql),
+            Event::DownloadStarted { url, path } => println!("Downloading {} to {:#?}", url, path),
+            Event::Downloaded { .. } => (),
+            Event::Retrying {
+                error, delay_ms, ..
+            } => println!(
+                "{}; retrying in {:?}",
+                error,
+                std::time::Duration::from_millis(*delay_ms)
+            ),
+            Event::Unchanged { url } => println!("{} is unchanged", url),
+            Event::SourceStarted { source } => println!("Loading {}", source),
+            Event::SourceSkipped { source } => println!("{} is unchanged; skipping", source),
+            Event::SourceResumed { source } => {
+                println!("{} was loaded before the build stopped; skipping", source)
+            }
+            Event::Rows {
+                source,
+                processed,
+                total,
+            } => {
+                match total {
+                    Some(total) => {
+                        print!("{}: processed {} of {} records\r", source, processed, total)
+                    }
+                    None => print!("{}: processed {} records\r", source, processed),
+                }
+                io::stdout().flush().ok();
+            }
+            Event::Committed {
+                source,
+                parsed,
+                inserted,
+                ..
+            } => println!(
+                "{}: loaded {} records into {} rows",
+                source, parsed, inserted
+            ),
+            Event::HeaderDrift { source, drift } => {
+                for col in &drift.added {
+                    println!("WARNING: {}: new column {:?} will be ignored", source, col);
+                }
+                for col in &drift.removed {
+                    println!("WARNING: {}: column {:?} is no longer present", source, col);
+                }
+                for (old, new) in &drift.renamed {
+                    println!(
+                        "WARNING: {}: column {:?} appears to be renamed to {:?}",
+                        source, old, new
+                    );
+                }
+            }
+            Event::Warning {
+                source: Some(source),
+                message,
+            } => println!("WARNING: {}: {}", source, message),
+            Event::Warning {
+                source: None,
+                message,
+            } => println!("WARNING: {}", message),
+            Event::Check(result) => println!("{}", result),
+            Event::Checked {
+                passed,
+                warnings,
+                failed,
+            } => println!(
+                "{} passed, {} warnings, {} failed",
+                passed, warnings, failed
+            ),
+            Event::Finished { .. } => println!("Finished successfully!"),
+        }
+    }
+}
+
+/** Writes each event as a line of JSON, with the time it was sent.
+
+```
+use covid19db::progress::{JsonLines, Progress};
+use std::sync::{Arc, Mutex};
+
+let buf = Arc::new(Mutex::new(Vec::new()));
+let progress = Progress::new(JsonLines::new(SharedBuf(buf.clone())));
+progress.step("Vacuuming");
+let line = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+assert!(line.starts_with(r#"{"time":""#));
+assert!(line.ends_with(r#"","event":"step","message":"Vacuuming"}
+"#));
+
+# struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+# impl std::io::Write for SharedBuf {
+#     fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+#         self.0.lock().unwrap().write(data)
+#     }
+#     fn flush(&mut self) -> std::io::Result<()> {
+#         Ok(())
+#     }
+# }
+```
+*/
+pub struct JsonLines {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLines {
+    pub fn new<W: Write + Send + 'static>(out: W) -> Self {
+        JsonLines {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Writes to standard output.
+    pub fn stdout() -> Self {
+        JsonLines::new(io::stdout())
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+impl Observer for JsonLines {
+    fn event(&self, event: &Event) {
+        let line = JsonLine {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            event,
+        };
+        let mut out = self.out.lock().unwrap();
+        // Progress reports are best-effort; a closed pipe shouldn't stop the build.
+        if serde_json::to_writer(&mut *out, &line).is_ok() {
+            writeln!(out).ok();
+            out.flush().ok();
+        }
+    }
+}
+
+/// Discards every event.
+pub struct Silent;
+
+impl Observer for Silent {
+    fn event(&self, _event: &Event) {}
+}
@@ -0,0 +1,448 @@
+/* Declarative checks on a loaded database
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Checks on the contents of a database, read from a TOML file.
+
+A check file holds a list of `[[check]]` tables; see `src/checks.toml`,
+which holds the checks built in to this crate, for the full syntax.
+Running the checks yields a [`Report`] giving each one's outcome, rather
+than stopping at the first failure. */
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::pool::PoolConnection;
+use sqlx::prelude::*;
+use sqlx::SqliteConnection;
+use std::fmt;
+use std::path::Path;
+
+use crate::dateutil::nd_to_day;
+use crate::dbutil::quote_ident;
+use crate::{Error, Result};
+
+const BUILTIN: &str = include_str!("checks.toml");
+
+/** A list of checks, as read from a check file.
+
+```
+use covid19db::validate::{Checks, Kind};
+
+let checks = Checks::from_toml(
+    r#"
+    [[check]]
+    table = "nytcounties"
+    where = "state = 'Kansas' AND county = 'Harvey'"
+    kind = "min_rows"
+    rows = 300
+    severity = "warn"
+    "#,
+)
+.unwrap();
+assert_eq!(checks.checks[0].kind, Kind::MinRows { rows: 300 });
+assert!(Checks::from_toml("[[check]]\ntable = \"x\"\nkind = \"bogus\"").is_err());
+```
+*/
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Checks {
+    #[serde(default, rename = "check")]
+    pub checks: Vec<Check>,
+}
+
+/// A single check on one table or view.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Check {
+    /// If set, the check is run only when this source is among those selected.
+    pub source: Option<String>,
+    /// The table or view to check.
+    pub table: String,
+    /// An SQL condition limiting the rows the check considers.
+    #[serde(rename = "where")]
+    pub filter: Option<String>,
+    /// What a failure of this check means for the build.
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(flatten)]
+    pub kind: Kind,
+}
+
+/// What is checked, and the parameters for it.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Kind {
+    /// The table has at least `rows` rows.
+    MinRows { rows: i64 },
+    /// `column` is `expect` in every row, and there is at least one row.  `None`
+    /// means NULL.
+    Value {
+        column: String,
+        expect: Option<Expected>,
+    },
+    /// At least `min_ratio` (from 0 to 1) of the rows have a non-NULL `column`.
+    NotNull { column: String, min_ratio: f64 },
+    /// Every day from `from` to `to` appears in `column`, which holds Julian
+    /// days.  If `to` is not given, the latest day present is used.
+    DateCoverage {
+        #[serde(default = "default_date_column")]
+        column: String,
+        #[serde(deserialize_with = "date")]
+        from: NaiveDate,
+        #[serde(default, deserialize_with = "opt_date")]
+        to: Option<NaiveDate>,
+    },
+    /// No two rows have the same values in all of `columns`.
+    Unique { columns: Vec<String> },
+    /// `column` is no more than `max` in every row, and there is at least one row.
+    AtMost { column: String, max: f64 },
+}
+
+impl Kind {
+    /** True if a check of this kind can hold for a subset of the data as
+    well as for the whole.  Row counts, particular values, bounds and date
+    coverage can't be expected of a subset, but non-NULL ratios and
+    uniqueness can. */
+    pub fn holds_for_subsets(&self) -> bool {
+        matches!(self, Kind::NotNull { .. } | Kind::Unique { .. })
+    }
+}
+
+/// A value expected by [`Kind::Value`].
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Expected {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+/// Whether a failed check fails the build, or only warns.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    #[default]
+    Fail,
+    Warn,
+}
+
+/// The outcome of one check.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// The result of running one check.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CheckResult {
+    /// What was checked, in words.
+    pub check: String,
+    pub outcome: Outcome,
+    /// What was found.
+    pub detail: String,
+}
+
+/// The results of running a set of checks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub results: Vec<CheckResult>,
+}
+
+fn default_date_column() -> String {
+    String::from("date_julian")
+}
+
+fn parse_date<E: serde::de::Error>(s: &str) -> std::result::Result<NaiveDate, E> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|e| E::custom(format!("invalid date {:?}: {}", s, e)))
+}
+
+fn date<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<NaiveDate, D::Error> {
+    parse_date(&String::deserialize(d)?)
+}
+
+fn opt_date<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<NaiveDate>, D::Error> {
+    Option::<String>::deserialize(d)?
+        .map(|s| parse_date(&s))
+        .transpose()
+}
+
+impl Checks {
+    /// Returns the checks built in to this crate.
+    pub fn builtin() -> Self {
+        Checks::from_toml(BUILTIN).expect("built-in checks are invalid")
+    }
+
+    /// Parses a check file.
+    pub fn from_toml(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|e| Error::Config(format!("Invalid checks: {}", e)))
+    }
+
+    /// Reads a check file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Checks::from_toml(&std::fs::read_to_string(path)?)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Adds the checks in `other` to these.
+    pub fn extend(&mut self, other: Checks) {
+        self.checks.extend(other.checks);
+    }
+
+    /** Runs the checks that apply to the sources named in `sources`.  A check
+    that can't be run at all, such as one naming a missing column, is reported
+    as failing (or warning, according to its severity); only errors reaching
+    the database itself are returned as `Err`. */
+    pub async fn run(
+        &self,
+        conn: &mut PoolConnection<SqliteConnection>,
+        sources: &[&str],
+    ) -> Result<Report> {
+        let mut report = Report::default();
+        for check in self.checks.iter().filter(|x| match &x.source {
+            Some(source) => sources.contains(&source.as_str()),
+            None => true,
+        }) {
+            let (passed, detail) = match check.evaluate(conn).await {
+                Ok(x) => x,
+                Err(sqlx::Error::Database(e)) => (false, e.to_string()),
+                Err(e) => return Err(e.into()),
+            };
+            report.results.push(CheckResult {
+                check: check.to_string(),
+                outcome: match (passed, check.severity) {
+                    (true, _) => Outcome::Pass,
+                    (false, Severity::Warn) => Outcome::Warn,
+                    (false, Severity::Fail) => Outcome::Fail,
+                },
+                detail,
+            });
+        }
+        Ok(report)
+    }
+}
+
+impl Check {
+    /// Builds a WHERE clause from `conditions` and the check's filter.
+    fn where_clause(&self, conditions: &[String]) -> String {
+        let mut all: Vec<String> = conditions.to_vec();
+        if let Some(filter) = &self.filter {
+            all.push(format!("({})", filter));
+        }
+        if all.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", all.join(" AND "))
+        }
+    }
+
+    /// Runs the check, returning whether it passed and what was found.
+    async fn evaluate(
+        &self,
+        conn: &mut PoolConnection<SqliteConnection>,
+    ) -> std::result::Result<(bool, String), sqlx::Error> {
+        let table = quote_ident(&self.table);
+        match &self.kind {
+            Kind::MinRows { rows } => {
+                let query = format!("SELECT COUNT(*) FROM {}{}", table, self.where_clause(&[]));
+                let (found,): (i64,) = sqlx::query_as(&query).fetch_one(conn).await?;
+                Ok((found >= *rows, format!("found {}", found)))
+            }
+            Kind::Value { column, expect } => {
+                let query = format!(
+                    "SELECT quote({col}), {col} IS ? FROM {}{}",
+                    table,
+                    self.where_clause(&[]),
+                    col = quote_ident(column)
+                );
+                let query = sqlx::query_as(&query);
+                let query = match expect {
+                    None => query.bind(None::<i64>),
+                    Some(Expected::Integer(x)) => query.bind(*x),
+                    Some(Expected::Real(x)) => query.bind(*x),
+                    Some(Expected::Text(x)) => query.bind(x.clone()),
+                };
+                let rows: Vec<(String, i64)> = query.fetch_all(conn).await?;
+                if rows.is_empty() {
+                    return Ok((false, String::from("no rows match")));
+                }
+                let mut found: Vec<&str> = rows.iter().map(|x| x.0.as_str()).collect();
+                found.dedup();
+                Ok((
+                    rows.iter().all(|x| x.1 != 0),
+                    format!("found {}", found.join(", ")),
+                ))
+            }
+            Kind::NotNull { column, min_ratio } => {
+                let query = format!(
+                    "SELECT COUNT(*), COUNT({}) FROM {}{}",
+                    quote_ident(column),
+                    table,
+                    self.where_clause(&[])
+                );
+                let (total, notnull): (i64, i64) = sqlx::query_as(&query).fetch_one(conn).await?;
+                let ratio = if total == 0 {
+                    0.0
+                } else {
+                    notnull as f64 / total as f64
+                };
+                Ok((
+                    ratio >= *min_ratio,
+                    format!("{:.1}% of {} rows", ratio * 100.0, total),
+                ))
+            }
+            Kind::DateCoverage { column, from, to } => {
+                let col = quote_ident(column);
+                let from = nd_to_day(from);
+                let mut conditions = vec![format!("{} >= {}", col, from)];
+                if let Some(to) = to {
+                    conditions.push(format!("{} <= {}", col, nd_to_day(to)));
+                }
+                let query = format!(
+                    "SELECT COUNT(DISTINCT {col}), MAX({col}) FROM {}{}",
+                    table,
+                    self.where_clause(&conditions),
+                    col = col
+                );
+                let (present, max): (i64, Option<i64>) =
+                    sqlx::query_as(&query).fetch_one(conn).await?;
+                let last = match to {
+                    Some(to) => i64::from(nd_to_day(to)),
+                    None => match max {
+                        Some(max) => max,
+                        None => return Ok((false, String::from("no dates present"))),
+                    },
+                };
+                let expected = (last - i64::from(from) + 1).max(0);
+                Ok((
+                    present >= expected,
+                    format!("missing {} of {} days", expected - present, expected),
+                ))
+            }
+            Kind::AtMost { column, max } => {
+                let query = format!(
+                    "SELECT COUNT(*), MAX({}) FROM {}{}",
+                    quote_ident(column),
+                    table,
+                    self.where_clause(&[])
+                );
+                let (rows, found): (i64, Option<f64>) =
+                    sqlx::query_as(&query).fetch_one(conn).await?;
+                match found {
+                    _ if rows == 0 => Ok((false, String::from("no rows match"))),
+                    Some(found) => Ok((found <= *max, format!("found {}", found))),
+                    None => Ok((false, String::from("found NULL"))),
+                }
+            }
+            Kind::Unique { columns } => {
+                let cols: Vec<String> = columns.iter().map(|x| quote_ident(x)).collect();
+                let query = format!(
+                    "SELECT COUNT(*) FROM (SELECT 1 FROM {}{} GROUP BY {} HAVING COUNT(*) > 1)",
+                    table,
+                    self.where_clause(&[]),
+                    cols.join(", ")
+                );
+                let (dups,): (i64,) = sqlx::query_as(&query).fetch_one(conn).await?;
+                Ok((dups == 0, format!("{} repeated values", dups)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            Kind::MinRows { rows } => write!(f, "{} has at least {} rows", self.table, rows)?,
+            Kind::Value { column, expect } => {
+                write!(f, "{}.{} is ", self.table, column)?;
+                match expect {
+                    None => write!(f, "NULL")?,
+                    Some(Expected::Integer(x)) => write!(f, "{}", x)?,
+                    Some(Expected::Real(x)) => write!(f, "{}", x)?,
+                    Some(Expected::Text(x)) => write!(f, "{:?}", x)?,
+                }
+            }
+            Kind::NotNull { column, min_ratio } => write!(
+                f,
+                "at least {}% of {}.{} is not NULL",
+                min_ratio * 100.0,
+                self.table,
+                column
+            )?,
+            Kind::DateCoverage { column, from, to } => {
+                write!(
+                    f,
+                    "{}.{} covers every day from {}",
+                    self.table, column, from
+                )?;
+                match to {
+                    Some(to) => write!(f, " to {}", to)?,
+                    None => write!(f, " on")?,
+                }
+            }
+            Kind::Unique { columns } => {
+                write!(f, "({}) is unique in {}", columns.join(", "), self.table)?
+            }
+            Kind::AtMost { column, max } => {
+                write!(f, "{}.{} is at most {}", self.table, column, max)?
+            }
+        }
+        if let Some(filter) = &self.filter {
+            write!(f, " where {}", filter)?;
+        }
+        Ok(())
+    }
+}
+
+impl Report {
+    /// Returns how many checks had the given outcome.
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.results.iter().filter(|x| x.outcome == outcome).count()
+    }
+
+    /// Returns true if no check failed.  Warnings don't count as failures.
+    pub fn passed(&self) -> bool {
+        self.count(Outcome::Fail) == 0
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.outcome {
+            Outcome::Pass => "PASS",
+            Outcome::Warn => "WARN",
+            Outcome::Fail => "FAIL",
+        };
+        write!(f, "{} {}: {}", label, self.check, self.detail)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            writeln!(f, "{}", result)?;
+        }
+        write!(
+            f,
+            "{} passed, {} warnings, {} failed",
+            self.count(Outcome::Pass),
+            self.count(Outcome::Warn),
+            self.count(Outcome::Fail)
+        )
+    }
+}
@@ -0,0 +1,84 @@
+/* Tests for replacing the database only after a successful build
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use common::source::LineSource;
+use covid19db::dbutil::{count_rows, open_pool};
+use covid19db::loader::{LoadOptions, Registry};
+use std::path::Path;
+
+async fn line_count(db: &Path) -> i64 {
+    let pool = open_pool(db, 1).await.unwrap();
+    let rows = count_rows(&mut pool.acquire().await.unwrap(), "lines")
+        .await
+        .unwrap();
+    pool.close().await;
+    rows
+}
+
+#[tokio::test]
+async fn failed_builds_keep_the_old_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut registry = Registry::new();
+    // The build fails unless there are at least two lines.
+    registry.register(Box::new(LineSource {
+        min_rows: 2,
+        ..LineSource::new("lines")
+    }));
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        keep_previous: true,
+        ..LoadOptions::default()
+    };
+    let prev = dir.path().join("test.db.prev");
+
+    std::fs::write(dir.path().join("lines.txt"), "a\nb\n").unwrap();
+    registry.load(&opts).await.unwrap();
+    assert_eq!(line_count(&opts.output).await, 2);
+    assert!(!prev.exists());
+
+    // Too few rows, so the check fails and the old database stays.
+    std::fs::write(dir.path().join("lines.txt"), "a\n").unwrap();
+    assert!(registry.load(&opts).await.is_err());
+    assert_eq!(line_count(&opts.output).await, 2);
+    assert!(!prev.exists());
+
+    std::fs::write(dir.path().join("lines.txt"), "a\nb\nc\n").unwrap();
+    registry.load(&opts).await.unwrap();
+    assert_eq!(line_count(&opts.output).await, 3);
+    assert_eq!(line_count(&prev).await, 2);
+
+    // Nothing but the database, its manifest, its predecessor, and the input
+    // is left behind.
+    let mut names: Vec<String> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "lines.txt",
+            "test.db",
+            "test.db.manifest.json",
+            "test.db.prev"
+        ]
+    );
+}
@@ -0,0 +1,58 @@
+/* Tests for attribution
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use covid19db::attribution::Credits;
+use covid19db::dbutil::open_pool;
+use covid19db::loader::{LoadOptions, LocLookupSource, NYTCountiesSource, Registry};
+use covid19db::progress::Progress;
+
+const COUNTIES: &str = "date,county,state,fips,cases,deaths
+2020-06-01,Harvey,Kansas,20079,41,1
+";
+
+#[tokio::test]
+async fn loaded_sources_are_credited() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("us-counties.csv"), COUNTIES).unwrap();
+    let mut registry = Registry::new();
+    registry.register(Box::new(LocLookupSource));
+    registry.register(Box::new(NYTCountiesSource));
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        progress: Progress::silent(),
+        only: vec![String::from("nytcounties")],
+        ..LoadOptions::default()
+    };
+    registry.load(&opts).await.unwrap();
+
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let credits = Credits::read(&mut pool.acquire().await.unwrap())
+        .await
+        .unwrap();
+    pool.close().await;
+    // Only the sources loaded are credited.
+    assert_eq!(credits, Credits::builtin().covering(&["nytcounties_raw"]));
+    assert_eq!(credits.credits[0].source, "nytcounties");
+    assert_eq!(credits.credits[0].license.as_deref(), Some("CC BY-NC 4.0"));
+    assert!(credits.covering(&["owid"]).credits.is_empty());
+    let text = credits.covering(&["nytcounties"]).text();
+    assert!(text.starts_with("The New York Times\n"), "{}", text);
+    assert!(text.contains("Attribution: Data from The New York Times"));
+    assert!(credits.bibtex().starts_with("@misc{nytimes,"));
+}
@@ -0,0 +1,208 @@
+/* A configurable source for tests
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use async_trait::async_trait;
+use covid19db::dbutil::{check_min_rows, BulkInsert};
+use covid19db::loader::{Coverage, HeaderDrift, LoadContext, LoadStats, Source, SourceFile};
+use covid19db::progress::Event;
+use covid19db::{Error, Result};
+use sqlx::sqlite::SqlitePool;
+use sqlx::{Query, Sqlite};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// A value for a column of a [`LineSource`]'s table.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Text(String),
+    Int(i64),
+}
+
+/// Turns a line into the values of a row, or says why it can't.
+pub type Parse = fn(&str) -> std::result::Result<Vec<Value>, String>;
+
+/** A source that loads each line of each of its files as a row of the table
+named after it, reporting each as it goes.  A line that doesn't parse is
+rejected, and an empty file fails the load.  [`new`](LineSource::new) gives
+the plainest such source; tests change only the fields they need. */
+#[derive(Clone)]
+pub struct LineSource {
+    pub name: &'static str,
+    pub dependencies: Vec<&'static str>,
+    /// By default, `<name>.txt` from `http://localhost/<name>.txt`.
+    pub files: Vec<SourceFile>,
+    /// The columns of the table, as in `create table`; if `None`, there is
+    /// no table, and lines are only counted.
+    pub columns: Option<&'static str>,
+    /// By default, each line is the one value of a row.
+    pub parse: Parse,
+    /// If set, the first line of each file is reported as a new column,
+    /// rather than loaded.
+    pub header_drift: bool,
+    /// Whether the source is expected to be loaded for a dry run; loading
+    /// asserts that it is or isn't.
+    pub dry_run: bool,
+    /// The fewest rows the table may have once loaded, for [`Source::check`].
+    pub min_rows: i64,
+    pub coverage: Option<Coverage>,
+    /// The name of each source, as it is loaded, shared among sources.
+    pub loaded: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl LineSource {
+    pub fn new(name: &'static str) -> Self {
+        let filename = format!("{}.txt", name);
+        LineSource {
+            name,
+            dependencies: Vec::new(),
+            files: vec![SourceFile::new(
+                &filename,
+                &format!("http://localhost/{}", filename),
+            )],
+            columns: Some("line text not null"),
+            parse: |line| Ok(vec![Value::Text(String::from(line))]),
+            header_drift: false,
+            dry_run: false,
+            min_rows: 0,
+            coverage: None,
+            loaded: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// How many times a source named `name` has been loaded.
+    pub fn loads(&self, name: &str) -> usize {
+        self.loaded
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|x| **x == name)
+            .count()
+    }
+}
+
+fn bind(row: Vec<Value>, mut query: Query<Sqlite>) -> Query<Sqlite> {
+    for value in row {
+        query = match value {
+            Value::Text(x) => query.bind(x),
+            Value::Int(x) => query.bind(x),
+        };
+    }
+    query
+}
+
+#[async_trait]
+impl Source for LineSource {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn dependencies(&self) -> Vec<&str> {
+        self.dependencies.clone()
+    }
+
+    fn files(&self) -> Vec<SourceFile> {
+        self.files.clone()
+    }
+
+    fn schema(&self) -> Vec<String> {
+        match self.columns {
+            Some(columns) => vec![
+                format!("drop table if exists {}", self.name),
+                format!("create table {} ({})", self.name, columns),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    fn coverage(&self) -> Option<Coverage> {
+        self.coverage.clone()
+    }
+
+    async fn load(
+        &self,
+        files: &[PathBuf],
+        pool: &mut SqlitePool,
+        ctx: &LoadContext,
+    ) -> Result<Vec<LoadStats>> {
+        assert_eq!(ctx.dry_run, self.dry_run);
+        assert_eq!(files.len(), self.files.len());
+        self.loaded.lock().unwrap().push(self.name);
+        let mut conn = pool.acquire().await?;
+        let mut all = Vec::new();
+        let mut processed = 0;
+        for path in files {
+            let text = std::fs::read_to_string(path)?;
+            if text.is_empty() {
+                return Err(Error::Check(format!("{} is empty", self.name)));
+            }
+            let mut lines = text.lines().enumerate();
+            if self.header_drift {
+                let (_, header) = lines.next().unwrap();
+                ctx.progress.event(Event::HeaderDrift {
+                    source: String::from(self.name),
+                    drift: HeaderDrift {
+                        added: vec![String::from(header)],
+                        ..HeaderDrift::default()
+                    },
+                });
+            }
+            let mut inserter = self.columns.map(|columns| {
+                let row = vec!["?"; columns.split(',').count()].join(", ");
+                let insert = format!("INSERT INTO {} VALUES ({})", self.name, row);
+                BulkInsert::new(&insert, bind).discard(ctx.dry_run)
+            });
+            let mut stats = LoadStats::default();
+            for (idx, line) in lines {
+                let row = match (self.parse)(line) {
+                    Ok(row) => row,
+                    Err(message) => {
+                        stats.reject(Error::RowParse {
+                            source: String::from(self.name),
+                            line: idx as u64 + 1,
+                            message,
+                            record: String::from(line),
+                        })?;
+                        continue;
+                    }
+                };
+                if let Some(inserter) = &mut inserter {
+                    inserter.push(row, &mut conn).await?;
+                }
+                stats.parsed += 1;
+                processed += 1;
+                ctx.progress.event(Event::Rows {
+                    source: String::from(self.name),
+                    processed,
+                    total: None,
+                });
+            }
+            if let Some(inserter) = inserter {
+                stats.inserted = inserter.finish(&mut conn).await?;
+            }
+            all.push(stats);
+        }
+        Ok(all)
+    }
+
+    async fn check(&self, pool: &mut SqlitePool) -> Result<()> {
+        if self.min_rows > 0 {
+            check_min_rows(self.min_rows, self.name, &mut pool.acquire().await?).await?;
+        }
+        Ok(())
+    }
+}
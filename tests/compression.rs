@@ -0,0 +1,114 @@
+/* Tests for decompressing source files
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use covid19db::loader::{DownloadOptions, Fetcher, SourceFile};
+use covid19db::progress::Progress;
+use std::io::Write;
+use std::path::Path;
+
+const DATA: &str = "date,state,cases\n2020-06-01,Kansas,10\n";
+
+fn compressed(kind: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    match kind {
+        "gz" => {
+            let mut enc = flate2::write::GzEncoder::new(&mut out, flate2::Compression::default());
+            enc.write_all(DATA.as_bytes()).unwrap();
+            enc.finish().unwrap();
+        }
+        "xz" => {
+            let mut enc = xz2::write::XzEncoder::new(&mut out, 6);
+            enc.write_all(DATA.as_bytes()).unwrap();
+            enc.finish().unwrap();
+        }
+        "bz2" => {
+            let mut enc = bzip2::write::BzEncoder::new(&mut out, bzip2::Compression::default());
+            enc.write_all(DATA.as_bytes()).unwrap();
+            enc.finish().unwrap();
+        }
+        "zst" => out = zstd::encode_all(DATA.as_bytes(), 0).unwrap(),
+        "zip" => {
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut out));
+            let options = zip::write::FileOptions::default();
+            zip.start_file("README.txt", options).unwrap();
+            zip.write_all(b"not this one").unwrap();
+            zip.start_file("data/us.csv", options).unwrap();
+            zip.write_all(DATA.as_bytes()).unwrap();
+            zip.finish().unwrap();
+        }
+        _ => panic!("unknown kind {}", kind),
+    }
+    out
+}
+
+fn fetcher(input: &Path, scratch: &Path) -> Fetcher {
+    let download = DownloadOptions {
+        progress: Progress::silent(),
+        ..DownloadOptions::default()
+    };
+    Fetcher::new(Some(input.to_owned()), download, scratch)
+}
+
+#[tokio::test]
+async fn every_format_is_decompressed() {
+    let input = tempfile::tempdir().unwrap();
+    let scratch = tempfile::tempdir().unwrap();
+    let fetcher = fetcher(input.path(), scratch.path());
+    for kind in &["gz", "xz", "bz2", "zst", "zip"] {
+        let member = Some(String::from("data/us.csv")).filter(|_| *kind == "zip");
+        // Recognized by extension, and by contents alone.
+        for filename in &[format!("data.csv.{}", kind), format!("{}-data", kind)] {
+            std::fs::write(input.path().join(filename), compressed(kind)).unwrap();
+            let sf = SourceFile {
+                member: member.clone(),
+                ..SourceFile::new(filename, &format!("https://example.com/{}", filename))
+            };
+            let path = fetcher.obtain(&sf).await.unwrap();
+            assert!(path.starts_with(scratch.path()));
+            assert_eq!(
+                std::fs::read_to_string(&path).unwrap(),
+                DATA,
+                "{}",
+                filename
+            );
+        }
+    }
+
+    std::fs::write(input.path().join("plain.csv"), DATA).unwrap();
+    let sf = SourceFile::new("plain.csv", "https://example.com/plain.csv");
+    assert_eq!(
+        fetcher.obtain(&sf).await.unwrap(),
+        input.path().join("plain.csv")
+    );
+}
+
+#[tokio::test]
+async fn zip_members_must_be_named() {
+    let input = tempfile::tempdir().unwrap();
+    let scratch = tempfile::tempdir().unwrap();
+    std::fs::write(input.path().join("bundle.zip"), compressed("zip")).unwrap();
+    let fetcher = fetcher(input.path(), scratch.path());
+    for member in &[None, Some(String::from("missing.csv"))] {
+        let sf = SourceFile {
+            member: member.clone(),
+            ..SourceFile::new("bundle.zip", "https://example.com/bundle.zip")
+        };
+        let err = fetcher.obtain(&sf).await.unwrap_err().to_string();
+        assert!(err.contains("README.txt, data/us.csv"), "{}", err);
+    }
+}
@@ -0,0 +1,93 @@
+/* Tests for concurrent fetching and loading
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use common::source::LineSource;
+use common::{Response, Server};
+use covid19db::loader::{DownloadOptions, LoadOptions, Registry, SourceFile};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+fn options(dir: &tempfile::TempDir, concurrency: usize) -> LoadOptions {
+    LoadOptions {
+        output: dir.path().join("test.db"),
+        download: DownloadOptions {
+            concurrency,
+            ..DownloadOptions::default()
+        },
+        ..LoadOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn downloads_are_limited() {
+    let active = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let (a, p) = (active.clone(), peak.clone());
+    let server = Server::start(move |_| {
+        let now = a.fetch_add(1, Ordering::SeqCst) + 1;
+        p.fetch_max(now, Ordering::SeqCst);
+        sleep(Duration::from_millis(200));
+        a.fetch_sub(1, Ordering::SeqCst);
+        Response::new(200, b"data")
+    });
+    let source = LineSource {
+        files: (0..6)
+            .map(|i| SourceFile::new(&format!("many/{}", i), &server.url(&format!("/{}", i))))
+            .collect(),
+        ..LineSource::new("many")
+    };
+    let mut registry = Registry::new();
+    registry.register(Box::new(source.clone()));
+    let dir = tempfile::tempdir().unwrap();
+    registry.load(&options(&dir, 2)).await.unwrap();
+    assert_eq!(server.requests().len(), 6);
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+    assert_eq!(*source.loaded.lock().unwrap(), vec!["many"]);
+}
+
+#[tokio::test]
+async fn sources_load_as_they_arrive() {
+    let server = Server::start(|req| {
+        if req.path == "/slow" {
+            sleep(Duration::from_millis(500));
+        }
+        Response::new(200, b"data")
+    });
+    let loaded = Arc::new(Mutex::new(Vec::new()));
+    let mut registry = Registry::new();
+    let mut add = |name, dependencies, path| {
+        let filename = format!("{}.txt", name);
+        registry.register(Box::new(LineSource {
+            dependencies,
+            files: vec![SourceFile::new(&filename, &server.url(path))],
+            loaded: loaded.clone(),
+            ..LineSource::new(name)
+        }))
+    };
+    add("slow", Vec::new(), "/slow");
+    add("dependent", vec!["slow"], "/fast");
+    add("fast", Vec::new(), "/fast");
+    let dir = tempfile::tempdir().unwrap();
+    registry.load(&options(&dir, 4)).await.unwrap();
+    // "fast" doesn't wait for the sources ahead of it, but "dependent" waits for "slow".
+    assert_eq!(*loaded.lock().unwrap(), vec!["fast", "slow", "dependent"]);
+}
@@ -0,0 +1,174 @@
+/* Tests for source coverage and staleness
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use chrono::{NaiveDate, TimeZone, Utc};
+use common::source::{LineSource, Value};
+use common::{Response, Server};
+use covid19db::dateutil::nd_to_day;
+use covid19db::dbutil::open_pool;
+use covid19db::loader::{Coverage, LoadOptions, Registry, SourceFile};
+use covid19db::progress::Progress;
+use covid19db::validate::Outcome;
+use sqlx::prelude::*;
+
+/// A source that loads `place,YYYY-MM-DD` lines from `reports.txt` into `reports`.
+fn reports() -> LineSource {
+    LineSource {
+        columns: Some("place text not null, date_julian integer not null"),
+        parse: |line| {
+            let (place, date) = line.split_once(',').ok_or("no date")?;
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|e| e.to_string())?;
+            Ok(vec![
+                Value::Text(String::from(place)),
+                Value::Int(i64::from(nd_to_day(&date))),
+            ])
+        },
+        coverage: Some(Coverage::new("reports", "place")),
+        ..LineSource::new("reports")
+    }
+}
+
+#[tokio::test]
+async fn coverage_is_recorded_and_staleness_checked() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join("reports.txt"),
+        "Harvey,2020-06-01\nHarvey,2020-06-05\nSedgwick,2020-06-02\n",
+    )
+    .unwrap();
+    let mut registry = Registry::new();
+    registry.register(Box::new(reports()));
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        progress: Progress::silent(),
+        timestamp: Some(Utc.ymd(2020, 6, 15).and_hms(12, 0, 0)),
+        ..LoadOptions::default()
+    };
+    registry.load(&opts).await.unwrap();
+
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let coverage: Vec<(Option<String>, i64, String, String, i64)> = sqlx::query_as(
+        "SELECT location, rows, first_date, last_date, days_stale FROM source_coverage
+         WHERE source = 'reports' ORDER BY location",
+    )
+    .fetch_all(&mut pool.acquire().await.unwrap())
+    .await
+    .unwrap();
+    pool.close().await;
+    let row = |location: Option<&str>, rows, first: &str, last: &str, stale| {
+        (
+            location.map(String::from),
+            rows,
+            String::from(first),
+            String::from(last),
+            stale,
+        )
+    };
+    assert_eq!(
+        coverage,
+        vec![
+            row(None, 3, "2020-06-01", "2020-06-05", 10),
+            row(Some("Harvey"), 2, "2020-06-01", "2020-06-05", 10),
+            row(Some("Sedgwick"), 1, "2020-06-02", "2020-06-02", 13),
+        ]
+    );
+
+    // More than a week stale, so a warning, though the build passed.
+    let report = registry.verify(&opts).await.unwrap();
+    let result = report.results.last().unwrap();
+    assert_eq!(result.outcome, Outcome::Warn);
+    assert_eq!(result.detail, "found 10");
+
+    let strict = LoadOptions {
+        max_staleness: Some(7),
+        ..opts.clone()
+    };
+    let report = registry.verify(&strict).await.unwrap();
+    let result = report.results.last().unwrap();
+    assert_eq!(result.outcome, Outcome::Fail);
+    assert_eq!(result.detail, "found 10");
+    assert!(registry.load(&strict).await.is_err());
+
+    let lenient = LoadOptions {
+        max_staleness: Some(10),
+        ..opts.clone()
+    };
+    assert!(registry.verify(&lenient).await.unwrap().passed());
+}
+
+/// Returns the `days_stale` recorded for the `reports` source as a whole.
+async fn days_stale(opts: &LoadOptions) -> i64 {
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let (stale,): (i64,) = sqlx::query_as(
+        "SELECT days_stale FROM source_coverage WHERE source = 'reports' AND location IS NULL",
+    )
+    .fetch_one(&mut pool.acquire().await.unwrap())
+    .await
+    .unwrap();
+    pool.close().await;
+    stale
+}
+
+#[tokio::test]
+async fn staleness_grows_while_sources_are_unchanged() {
+    let server = Server::start(|req| {
+        if req.headers.contains_key("if-none-match") {
+            Response::new(304, b"")
+        } else {
+            Response::new(200, b"Harvey,2020-06-05\n").header("ETag", "\"v1\"")
+        }
+    });
+    let mut registry = Registry::new();
+    registry.register(Box::new(LineSource {
+        files: vec![SourceFile::new("reports.txt", &server.url("/reports.txt"))],
+        ..reports()
+    }));
+    let dir = tempfile::tempdir().unwrap();
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        incremental: true,
+        progress: Progress::silent(),
+        timestamp: Some(Utc.ymd(2020, 6, 6).and_hms(12, 0, 0)),
+        max_staleness: Some(7),
+        ..LoadOptions::default()
+    };
+    registry.load(&opts).await.unwrap();
+    assert_eq!(days_stale(&opts).await, 1);
+
+    // The server answers 304, so the data is kept, but it is staler by the
+    // time of each build, until the check fails and the database is left as
+    // it was.
+    let later = LoadOptions {
+        timestamp: Some(Utc.ymd(2020, 6, 10).and_hms(12, 0, 0)),
+        ..opts.clone()
+    };
+    registry.load(&later).await.unwrap();
+    assert_eq!(server.requests().len(), 2);
+    assert_eq!(days_stale(&later).await, 5);
+
+    let much_later = LoadOptions {
+        timestamp: Some(Utc.ymd(2020, 6, 20).and_hms(12, 0, 0)),
+        ..opts.clone()
+    };
+    assert!(registry.verify(&much_later).await.unwrap().passed());
+    assert!(registry.load(&much_later).await.is_err());
+    assert_eq!(server.requests().len(), 3);
+}
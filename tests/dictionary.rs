@@ -0,0 +1,107 @@
+/* Tests for the data dictionary
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use covid19db::dbschema::{checkpoint_schema, execute_all, meta_schema};
+use covid19db::dbutil::open_pool;
+use covid19db::dictionary::{read, Dictionary, Kind};
+use covid19db::loader::Registry;
+use covid19db::progress::Progress;
+
+#[tokio::test]
+async fn every_column_is_described() {
+    let dir = tempfile::tempdir().unwrap();
+    let pool = open_pool(&dir.path().join("test.db"), 1).await.unwrap();
+    let mut conn = pool.acquire().await.unwrap();
+    let mut queries = meta_schema();
+    queries.extend(checkpoint_schema());
+    for source in Registry::builtin().iter() {
+        queries.extend(source.schema());
+    }
+    execute_all(&mut conn, &queries, &Progress::silent())
+        .await
+        .unwrap();
+    let dict = Dictionary::builtin();
+    dict.write(&mut conn).await.unwrap();
+    let columns = read(&mut conn).await.unwrap();
+    drop(conn);
+    pool.close().await;
+
+    let undescribed: Vec<String> = columns
+        .iter()
+        .filter(|x| x.doc.description.is_none())
+        .map(|x| format!("{}.{}", x.table, x.name))
+        .collect();
+    assert!(undescribed.is_empty(), "{:?}", undescribed);
+    // Nor does the dictionary describe columns that don't exist.
+    for table in &dict.tables {
+        for name in table.columns.keys() {
+            assert!(
+                columns
+                    .iter()
+                    .any(|x| x.table == table.name && &x.name == name),
+                "{}.{} is not in the schema",
+                table.name,
+                name
+            );
+        }
+    }
+
+    let find = |table: &str, name: &str| {
+        columns
+            .iter()
+            .find(|x| x.table == table && x.name == name)
+            .unwrap()
+            .clone()
+    };
+    let per_100k = find("owid", "total_cases_per_100k");
+    assert_eq!(per_100k.doc.kind, Some(Kind::Derived));
+    assert_eq!(
+        per_100k.doc.unit.as_deref(),
+        Some("cases per 100,000 people")
+    );
+    assert_eq!(
+        per_100k.doc.transformation.as_deref(),
+        Some("total_cases_per_million divided by 10")
+    );
+    let per_million = find("owid", "total_cases_per_million");
+    assert_eq!(
+        per_million.doc.source_column.as_deref(),
+        Some("total_cases_per_million")
+    );
+    assert_eq!(per_million.sql_type, "real");
+    assert_eq!(
+        find("covidtracking_raw", "incluCurrently")
+            .doc
+            .source_column
+            .as_deref(),
+        Some("inIcuCurrently")
+    );
+    assert_eq!(
+        find("cdataset", "delta_confirmed").doc.kind,
+        Some(Kind::Daily)
+    );
+    assert!(find("cdataset", "absolute_pop100k_deaths")
+        .doc
+        .transformation
+        .unwrap()
+        .contains("set_per_pop"));
+    assert_eq!(
+        find("covid19db_columns", "kind").doc,
+        dict.describe("covid19db_columns", "kind").unwrap()
+    );
+}
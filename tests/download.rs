@@ -0,0 +1,131 @@
+/* Tests for downloading with retries and resumption
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use common::{Response, Server};
+use covid19db::loader::{downloadto_if_changed, DownloadOptions, Validators};
+use covid19db::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+const DATA: &[u8] = b"0123456789";
+
+fn quick() -> DownloadOptions {
+    DownloadOptions {
+        read_timeout: Duration::from_millis(500),
+        retries: 3,
+        backoff: Duration::from_millis(10),
+        ..DownloadOptions::default()
+    }
+}
+
+/// Sends the first half of `DATA` while claiming the whole length.
+fn truncated() -> Response {
+    Response::new(200, &DATA[..5])
+        .header("Content-Length", "10")
+        .header("ETag", "\"x\"")
+}
+
+async fn download(server: &Server) -> (covid19db::Result<Option<Validators>>, Vec<u8>) {
+    let mut buf = Vec::new();
+    let result = downloadto_if_changed(
+        &server.url("/f"),
+        &Validators::default(),
+        &quick(),
+        &mut buf,
+    )
+    .await;
+    (result, buf)
+}
+
+#[tokio::test]
+async fn retries_and_resumes() {
+    let count = AtomicUsize::new(0);
+    let server = Server::start(move |req| match count.fetch_add(1, Ordering::SeqCst) {
+        0 => Response::new(500, b"oops"),
+        1 => truncated(),
+        _ => {
+            assert_eq!(req.headers.get("range"), Some(&String::from("bytes=5-")));
+            Response::new(206, &DATA[5..]).header("Content-Range", "bytes 5-9/10")
+        }
+    });
+    let (result, buf) = download(&server).await;
+    assert_eq!(result.unwrap().unwrap().etag, Some(String::from("\"x\"")));
+    assert_eq!(buf, DATA);
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests.iter().all(|x| x.path == "/f"));
+    assert_eq!(
+        requests[2].headers.get("if-range"),
+        Some(&String::from("\"x\""))
+    );
+}
+
+#[tokio::test]
+async fn resumes_when_range_is_ignored() {
+    let count = AtomicUsize::new(0);
+    let server = Server::start(move |_| match count.fetch_add(1, Ordering::SeqCst) {
+        0 => truncated(),
+        _ => Response::new(200, DATA).header("ETag", "\"x\""),
+    });
+    let (result, buf) = download(&server).await;
+    assert!(result.is_ok());
+    assert_eq!(buf, DATA);
+}
+
+#[tokio::test]
+async fn retries_after_timeout() {
+    let count = AtomicUsize::new(0);
+    let server = Server::start(move |_| {
+        if count.fetch_add(1, Ordering::SeqCst) == 0 {
+            sleep(Duration::from_secs(2));
+        }
+        Response::new(200, DATA)
+    });
+    let (result, buf) = download(&server).await;
+    assert!(result.is_ok());
+    assert_eq!(buf, DATA);
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn wrong_length_fails_cleanly() {
+    let server = Server::start(|_| truncated());
+    let (result, buf) = download(&server).await;
+    match result {
+        Err(Error::ContentLength {
+            expected, received, ..
+        }) => {
+            assert_eq!(expected, 10);
+            assert_eq!(received, 5);
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+    assert_eq!(buf, &DATA[..5]);
+    assert_eq!(server.requests().len(), 4);
+}
+
+#[tokio::test]
+async fn client_errors_are_not_retried() {
+    let server = Server::start(|_| Response::new(404, b"not found"));
+    let (result, _) = download(&server).await;
+    assert!(matches!(result, Err(Error::Download { .. })));
+    assert_eq!(server.requests().len(), 1);
+}
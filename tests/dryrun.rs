@@ -0,0 +1,80 @@
+/* Tests for dry runs
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use common::source::LineSource;
+use covid19db::loader::{LoadOptions, Registry};
+use covid19db::progress::Progress;
+
+#[tokio::test]
+async fn dry_runs_report_every_source() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut registry = Registry::new();
+    for (name, dependencies) in &[
+        ("good", vec![]),
+        ("empty", vec![]),
+        ("dependent", vec!["empty"]),
+        ("missing", vec![]),
+    ] {
+        // Each file starts with a column name to report as new.
+        registry.register(Box::new(LineSource {
+            dependencies: dependencies.clone(),
+            header_drift: true,
+            dry_run: true,
+            ..LineSource::new(name)
+        }));
+    }
+    std::fs::write(dir.path().join("good.txt"), "cases\n1\n2\n").unwrap();
+    std::fs::write(dir.path().join("empty.txt"), "").unwrap();
+    std::fs::write(dir.path().join("dependent.txt"), "deaths\n3\n").unwrap();
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        progress: Progress::silent(),
+        ..LoadOptions::default()
+    };
+
+    let report = registry.dry_run(&opts).await.unwrap();
+    assert!(!opts.output.exists());
+    assert!(!dir.path().join("test.db.partial").exists());
+    assert!(!report.passed());
+
+    let good = &report.sources[0];
+    assert_eq!(good.source, "good");
+    assert_eq!(good.files.len(), 1);
+    assert_eq!(good.files[0].0, "good.txt");
+    assert_eq!(good.files[0].1.parsed, 2);
+    assert_eq!(good.drift[0].added, vec![String::from("cases")]);
+    assert_eq!(good.error, None);
+
+    let errors: Vec<_> = report.sources[1..]
+        .iter()
+        .map(|x| (x.source.as_str(), x.error.clone().unwrap()))
+        .collect();
+    assert_eq!(
+        errors[0],
+        ("empty", String::from("Check failed: empty is empty"))
+    );
+    assert_eq!(
+        errors[1],
+        ("dependent", String::from("not loaded, since empty failed"))
+    );
+    assert_eq!(errors[2].0, "missing");
+    assert!(report.to_string().ends_with("1 sources parsed, 3 failed"));
+}
@@ -0,0 +1,85 @@
+/* Tests for source encodings
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use covid19db::dbutil::open_pool;
+use covid19db::loader::{self, LoadOptions};
+use covid19db::progress::Progress;
+use sqlx::prelude::*;
+use std::path::Path;
+
+/// County data in Windows-1252, with an accented column name as well as an
+/// accented place name.
+const COUNTIES: &[u8] = b"date,county,state,fips,cases,deaths,poblaci\xf3n
+2020-06-01,Do\xf1a Ana,New Mexico,35013,381,6,218195
+2020-06-02,Do\xf1a Ana,New Mexico,35013,398,6,218195
+";
+
+/// Builds `nytcounties` from `COUNTIES`, declared to be in `encoding` if given,
+/// and returns the places stored and the replacements recorded.
+async fn build(dir: &Path, encoding: Option<&str>) -> (Vec<String>, i64) {
+    let sources = dir.join("sources.toml");
+    let config = match encoding {
+        Some(encoding) => format!(
+            "[[source]]\nname = \"nytcounties\"\nencoding = \"{}\"\n",
+            encoding
+        ),
+        None => String::new(),
+    };
+    std::fs::write(&sources, config).unwrap();
+    let opts = LoadOptions {
+        output: dir.join("test.db"),
+        input_dir: Some(dir.to_owned()),
+        sources: Some(sources),
+        progress: Progress::silent(),
+        only: vec![String::from("nytcounties")],
+        ..LoadOptions::default()
+    };
+    loader::load_with(&opts).await.unwrap();
+
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let mut conn = pool.acquire().await.unwrap();
+    let places: Vec<(String,)> =
+        sqlx::query_as("SELECT county FROM nytcounties_raw ORDER BY date_julian")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+    let replacements: (i64,) =
+        sqlx::query_as("SELECT replacements FROM covid19db_sources WHERE source = 'nytcounties'")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+    drop(conn);
+    pool.close().await;
+    (places.into_iter().map(|x| x.0).collect(), replacements.0)
+}
+
+#[tokio::test]
+async fn declared_encodings_are_transcoded() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("us-counties.csv"), COUNTIES).unwrap();
+
+    let (places, replacements) = build(dir.path(), Some("windows-1252")).await;
+    assert_eq!(places, vec!["Doña Ana", "Doña Ana"]);
+    assert_eq!(replacements, 0);
+
+    // Read as UTF-8, the header still loads, but the damage is counted: one
+    // replacement in the header, and one in each row.
+    let (places, replacements) = build(dir.path(), None).await;
+    assert_eq!(places, vec!["Do\u{fffd}a Ana", "Do\u{fffd}a Ana"]);
+    assert_eq!(replacements, 3);
+}
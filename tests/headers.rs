@@ -0,0 +1,129 @@
+/* Tests for header checking
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use common::{Response, Server};
+use covid19db::dbutil::open_pool;
+use covid19db::loader::{self, HeaderDrift, LoadOptions};
+use covid19db::progress::{Event, Observer, Progress};
+use covid19db::Error;
+use sqlx::prelude::*;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+struct Collect(Mutex<Vec<Event>>);
+
+impl Observer for Collect {
+    fn event(&self, event: &Event) {
+        self.0.lock().unwrap().push(event.clone());
+    }
+}
+
+impl Collect {
+    /// Removes and returns the header drift reported so far.
+    fn drift(&self) -> Vec<(String, HeaderDrift)> {
+        self.0
+            .lock()
+            .unwrap()
+            .drain(..)
+            .filter_map(|x| match x {
+                Event::HeaderDrift { source, drift } => Some((source, drift)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+async fn recorded_headers(path: &Path) -> String {
+    let pool = open_pool(path, 1).await.unwrap();
+    let (value,): (String,) =
+        sqlx::query_as("SELECT value FROM covid19db_meta WHERE field = 'headers:nytcounties'")
+            .fetch_one(&mut pool.acquire().await.unwrap())
+            .await
+            .unwrap();
+    pool.close().await;
+    value
+}
+
+#[tokio::test]
+async fn header_changes_are_reported() {
+    let body = Arc::new(Mutex::new(Vec::new()));
+    let served = body.clone();
+    let server = Server::start(move |_| Response::new(200, &served.lock().unwrap()));
+    let dir = tempfile::tempdir().unwrap();
+    let sources = dir.path().join("sources.toml");
+    std::fs::write(
+        &sources,
+        format!(
+            "[[source]]\nname = \"nytcounties\"\n\n\
+             [[source.file]]\nfilename = \"us-counties.csv\"\nurl = \"{}\"\n",
+            server.url("/us-counties.csv")
+        ),
+    )
+    .unwrap();
+    let collect = Arc::new(Collect(Mutex::new(Vec::new())));
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        sources: Some(sources),
+        progress: Progress::from(collect.clone()),
+        only: vec![String::from("nytcounties")],
+        ..LoadOptions::default()
+    };
+
+    // An added column is reported, and the build goes on.
+    *body.lock().unwrap() = b"date,county,state,fips,cases,deaths,notes
+2020-06-01,Harvey,Kansas,20079,41,1,
+"
+    .to_vec();
+    loader::load_with(&opts).await.unwrap();
+    let drift = collect.drift();
+    assert_eq!(drift.len(), 1);
+    assert_eq!(drift[0].0, "nytcounties");
+    assert_eq!(drift[0].1.added, vec![String::from("notes")]);
+    assert!(drift[0].1.removed.is_empty());
+    assert_eq!(
+        recorded_headers(&opts.output).await,
+        "date,county,state,fips,cases,deaths,notes"
+    );
+
+    // A missing required column fails the build, leaving the database as it was.
+    *body.lock().unwrap() = b"date,state,fips,cases,deaths
+2020-06-01,Kansas,20079,41,1
+"
+    .to_vec();
+    match loader::load_with(&opts).await {
+        Err(Error::HeaderMismatch {
+            source,
+            missing,
+            found,
+        }) => {
+            assert_eq!(source, "nytcounties");
+            assert_eq!(missing, vec![String::from("county")]);
+            assert_eq!(found, vec!["date", "state", "fips", "cases", "deaths"]);
+        }
+        other => panic!("expected a header mismatch, got {:?}", other),
+    }
+    let drift = collect.drift();
+    assert_eq!(drift.len(), 1);
+    assert_eq!(drift[0].1.removed, vec![String::from("county")]);
+    assert_eq!(
+        recorded_headers(&opts.output).await,
+        "date,county,state,fips,cases,deaths,notes"
+    );
+}
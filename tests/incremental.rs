@@ -0,0 +1,106 @@
+/* Tests for incremental builds
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use common::source::LineSource;
+use common::{Response, Server};
+use covid19db::dbutil::{count_rows, open_pool};
+use covid19db::loader::{LoadOptions, Registry, SourceFile};
+use sqlx::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+async fn line_count(opts: &LoadOptions) -> i64 {
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let rows = count_rows(&mut pool.acquire().await.unwrap(), "lines")
+        .await
+        .unwrap();
+    pool.close().await;
+    rows
+}
+
+/// Returns the checksum, ETag, and row count recorded for `lines.txt`.
+async fn provenance(opts: &LoadOptions) -> (String, Option<String>, i64) {
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let row = sqlx::query_as(
+        "SELECT sha256, etag, inserted FROM covid19db_sources WHERE filename = 'lines.txt'",
+    )
+    .fetch_one(&mut pool.acquire().await.unwrap())
+    .await
+    .unwrap();
+    pool.close().await;
+    row
+}
+
+#[tokio::test]
+async fn unchanged_sources_are_skipped() {
+    let version = Arc::new(AtomicUsize::new(1));
+    let served = version.clone();
+    let server = Server::start(move |req| {
+        let etag = format!("\"v{}\"", served.load(Ordering::SeqCst));
+        if req.headers.get("if-none-match") == Some(&etag) {
+            Response::new(304, b"")
+        } else {
+            let body = "a\nb\nc\n".repeat(served.load(Ordering::SeqCst));
+            Response::new(200, body.as_bytes()).header("ETag", &etag)
+        }
+    });
+    let source = LineSource {
+        files: vec![SourceFile::new("lines.txt", &server.url("/lines.txt"))],
+        ..LineSource::new("lines")
+    };
+    let mut registry = Registry::new();
+    registry.register(Box::new(source.clone()));
+    let dir = tempfile::tempdir().unwrap();
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        incremental: true,
+        ..LoadOptions::default()
+    };
+
+    // No earlier database, so this is a full build with an ordinary GET.
+    registry.load(&opts).await.unwrap();
+    assert_eq!(source.loads("lines"), 1);
+    assert_eq!(server.requests()[0].path, "/lines.txt");
+    assert_eq!(server.requests()[0].headers.get("if-none-match"), None);
+    assert_eq!(line_count(&opts).await, 3);
+    assert_eq!(
+        provenance(&opts).await,
+        (
+            String::from("880553fca8fcea94e325ee2cfb48e5a985cc797f39a14cc6d3cedecfeb2ae4d2"),
+            Some(String::from("\"v1\"")),
+            3
+        )
+    );
+
+    // The server answers 304, so the data is kept as it was.
+    registry.load(&opts).await.unwrap();
+    assert_eq!(source.loads("lines"), 1);
+    assert_eq!(
+        server.requests()[1].headers.get("if-none-match"),
+        Some(&String::from("\"v1\""))
+    );
+    assert_eq!(line_count(&opts).await, 3);
+
+    // Once the file changes, the source is reloaded from scratch.
+    version.store(2, Ordering::SeqCst);
+    registry.load(&opts).await.unwrap();
+    assert_eq!(source.loads("lines"), 2);
+    assert_eq!(line_count(&opts).await, 6);
+}
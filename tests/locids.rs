@@ -0,0 +1,222 @@
+/* Tests for cdataset location ids
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use covid19db::dbschema::{execute_all, meta_schema};
+use covid19db::dbutil::open_pool;
+use covid19db::loader::{
+    CDataSetSource, LoadContext, LocLookupSource, Source, Subset, HASHED_LOCIDS,
+};
+use covid19db::progress::Progress;
+use sqlx::prelude::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The columns of the combined Sqlite databases that the loader reads.
+const DATASET_COLUMNS: &[&str] = &[
+    "dataset",
+    "location_key",
+    "location_type",
+    "location_label",
+    "country_code",
+    "country",
+    "province",
+    "administrative",
+    "region",
+    "location_lat",
+    "location_long",
+    "date",
+    "date_year",
+    "date_month",
+    "date_day",
+    "day_index_0",
+    "day_index_1",
+    "day_index_10",
+    "day_index_100",
+    "day_index_1k",
+    "day_index_10k",
+    "day_index_peak",
+    "day_index_peak_confirmed",
+    "day_index_peak_deaths",
+    "absolute_confirmed",
+    "absolute_deaths",
+    "absolute_recovered",
+    "absolute_infected",
+    "absolute_pop100k_confirmed",
+    "absolute_pop100k_deaths",
+    "absolute_pop100k_recovered",
+    "absolute_pop100k_infected",
+    "relative_deaths",
+    "relative_recovered",
+    "relative_infected",
+    "delta_confirmed",
+    "delta_deaths",
+    "delta_recovered",
+    "delta_infected",
+    "delta_pct_confirmed",
+    "delta_pct_deaths",
+    "delta_pct_recovered",
+    "delta_pct_infected",
+    "delta_pop100k_confirmed",
+    "delta_pop100k_deaths",
+    "delta_pop100k_recovered",
+    "delta_pop100k_infected",
+    "peak_pct_confirmed",
+    "peak_pct_deaths",
+    "peak_pct_recovered",
+    "peak_pct_infected",
+    "factbook_area",
+    "factbook_population",
+    "factbook_death_rate",
+    "factbook_median_age",
+];
+
+const TSV_HEADER: &str = "key\ttype\tlabel\tcountry_code\tcountry_normalized\tprovince_normalized\tadministrative_normalized\tregion\tsubregion\tus_state_code\tus_state_name\tus_county_fips";
+
+/// Lines of `locations-diff.tsv`.  "fr" and "france" both name France, and
+/// "harvey" and "us-ks-harvey" both name Harvey County, Kansas, though only
+/// the latter gives its FIPS code.
+const LOCATIONS: &[&str] = &[
+    "fr\ttotal-country\tFrance\tFR\tFrance\t\t\tEurope\tWestern Europe\t\t\t",
+    "france\ttotal-country\tFrance\tFR\tFrance\t\t\tEurope\tWestern Europe\t\t\t",
+    "us-ks-harvey\tcounty\tHarvey\tUS\tUnited States\tKansas\tHarvey\tAmericas\tNorthern America\tKS\tKansas\t20079",
+    "harvey\tcounty\tHarvey\tUS\tUnited States\tKansas\tHarvey\tAmericas\tNorthern America\t\t\t",
+    "atlantis\ttotal-country\tAtlantis\tXX\tAtlantis\t\t\tEurope\tWestern Europe\t\t\t",
+];
+
+/// Writes a combined Sqlite database with a row for "fr", without its country
+/// code, and for "new", which isn't in `locations-diff.tsv`.
+async fn write_dataset(path: &Path) {
+    let pool = open_pool(path, 1).await.unwrap();
+    let mut conn = pool.acquire().await.unwrap();
+    conn.execute(format!("CREATE TABLE dataset ({})", DATASET_COLUMNS.join(", ")).as_str())
+        .await
+        .unwrap();
+    conn.execute(
+        "INSERT INTO dataset (dataset, location_key, country_code, date, date_year, date_month, date_day)
+         VALUES ('jhu/series', 'fr', NULL, '2020-06-01', 2020, 6, 1),
+                ('jhu/series', 'new', 'XY', '2020-06-01', 2020, 6, 1)",
+    )
+    .await
+    .unwrap();
+    drop(conn);
+    pool.close().await;
+}
+
+/// Loads `cdataset` into a new database `name` from the `locations` given,
+/// and returns the id of each location key.
+async fn load(
+    dir: &Path,
+    name: &str,
+    locations: &[&str],
+    subset: Subset,
+    locids_from: Option<PathBuf>,
+) -> HashMap<String, i64> {
+    let tsv = dir.join(format!("{}.tsv", name));
+    std::fs::write(&tsv, format!("{}\n{}\n", TSV_HEADER, locations.join("\n"))).unwrap();
+    let dataset = dir.join("values.db");
+    if !dataset.exists() {
+        write_dataset(&dataset).await;
+    }
+
+    let mut pool = open_pool(&dir.join(name), 1).await.unwrap();
+    let mut conn = pool.acquire().await.unwrap();
+    let mut queries = meta_schema();
+    queries.extend(LocLookupSource.schema());
+    queries.extend(CDataSetSource.schema());
+    execute_all(&mut conn, &queries, &Progress::silent())
+        .await
+        .unwrap();
+    conn.execute(
+        "INSERT INTO loc_lookup (uid, iso2, iso3, fips, admin2, province_state, country_region, combined_key)
+         VALUES (250, 'FR', 'FRA', NULL, NULL, NULL, 'France', 'France'),
+                (84000020, 'US', 'USA', 20, NULL, 'Kansas', 'US', 'Kansas, US'),
+                (84020079, 'US', 'USA', 20079, 'Harvey', 'Kansas', 'US', 'Harvey, Kansas, US')",
+    )
+    .await
+    .unwrap();
+    drop(conn);
+    let ctx = LoadContext {
+        progress: Progress::silent(),
+        subset,
+        locids_from,
+        ..LoadContext::default()
+    };
+    CDataSetSource
+        .load(&[tsv, dataset], &mut pool, &ctx)
+        .await
+        .unwrap();
+    let ids: Vec<(String, i64)> = sqlx::query_as("SELECT location_key, locid FROM cdataset_loc")
+        .fetch_all(&mut pool.acquire().await.unwrap())
+        .await
+        .unwrap();
+    pool.close().await;
+    ids.into_iter().collect()
+}
+
+#[tokio::test]
+async fn locations_get_the_same_ids_every_time() {
+    let dir = tempfile::tempdir().unwrap();
+    let full = load(dir.path(), "a.db", LOCATIONS, Subset::default(), None).await;
+    assert_eq!(full.len(), 6);
+    // Of keys for the same place, one with a matching FIPS code gets its UID,
+    // or else the shortest.
+    assert_eq!(full["fr"], 250);
+    assert!(full["france"] >= HASHED_LOCIDS);
+    assert_eq!(full["us-ks-harvey"], 84020079);
+    assert!(full["harvey"] >= HASHED_LOCIDS);
+    assert!(full["atlantis"] >= HASHED_LOCIDS);
+    assert!(full["new"] >= HASHED_LOCIDS);
+
+    // The order of the keys doesn't matter.
+    let mut reversed = LOCATIONS.to_vec();
+    reversed.reverse();
+    let again = load(dir.path(), "b.db", &reversed, Subset::default(), None).await;
+    assert_eq!(again, full);
+
+    // Nor does the subset built.
+    let subset = Subset {
+        fips: vec![String::from("20079")],
+        ..Subset::default()
+    };
+    let part = load(dir.path(), "c.db", LOCATIONS, subset, None).await;
+    assert!(!part.contains_key("harvey"));
+    assert_eq!(part.len(), 5);
+    for (key, locid) in &part {
+        assert_eq!(full[key], *locid, "{}", key);
+    }
+
+    // A new key that would win the UID doesn't take it from the key given it
+    // by the previous build.
+    let mut more = LOCATIONS.to_vec();
+    more.push("f\ttotal-country\tFrance\tFR\tFrance\t\t\tEurope\tWestern Europe\t\t\t");
+    let kept = load(
+        dir.path(),
+        "d.db",
+        &more,
+        Subset::default(),
+        Some(dir.path().join("a.db")),
+    )
+    .await;
+    assert_eq!(kept["fr"], 250);
+    assert!(kept["f"] >= HASHED_LOCIDS);
+    for (key, locid) in &full {
+        assert_eq!(kept[key], *locid, "{}", key);
+    }
+    let fresh = load(dir.path(), "e.db", &more, Subset::default(), None).await;
+    assert_eq!(fresh["f"], 250);
+}
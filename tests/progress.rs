@@ -0,0 +1,95 @@
+/* Tests for progress reporting
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use common::source::LineSource;
+use covid19db::loader::{LoadOptions, Registry};
+use covid19db::progress::{Event, Observer, Progress};
+use covid19db::validate::Outcome;
+use std::sync::{Arc, Mutex};
+
+struct Collect(Mutex<Vec<Event>>);
+
+impl Observer for Collect {
+    fn event(&self, event: &Event) {
+        self.0.lock().unwrap().push(event.clone());
+    }
+}
+
+#[tokio::test]
+async fn builds_report_typed_events() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("count.txt"), "a\nb\n").unwrap();
+    let seen = Arc::new(Collect(Mutex::new(Vec::new())));
+    let mut registry = Registry::new();
+    registry.register(Box::new(LineSource {
+        columns: None,
+        ..LineSource::new("count")
+    }));
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        progress: Progress::from(seen.clone()),
+        ..LoadOptions::default()
+    };
+    registry.load(&opts).await.unwrap();
+
+    let events = seen.0.lock().unwrap();
+    let interesting: Vec<&Event> = events
+        .iter()
+        .filter(|x| !matches!(x, Event::Step { .. } | Event::Statement { .. }))
+        .collect();
+    let source = String::from("count");
+    assert_eq!(
+        interesting,
+        vec![
+            &Event::SourceStarted {
+                source: source.clone()
+            },
+            &Event::Rows {
+                source: source.clone(),
+                processed: 1,
+                total: None
+            },
+            &Event::Rows {
+                source: source.clone(),
+                processed: 2,
+                total: None
+            },
+            &Event::Committed {
+                source,
+                parsed: 2,
+                inserted: 0,
+                skipped: 0,
+                rejected: 0
+            },
+            &Event::Checked {
+                passed: 0,
+                warnings: 0,
+                failed: 0
+            },
+            &Event::Finished {
+                output: opts.output.clone()
+            },
+        ]
+    );
+    assert!(events
+        .iter()
+        .all(|x| !matches!(x, Event::Check(r) if r.outcome == Outcome::Fail)));
+}
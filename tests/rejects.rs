@@ -0,0 +1,99 @@
+/* Tests for quarantining records that fail to parse
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use common::source::{LineSource, Value};
+use covid19db::dbutil::open_pool;
+use covid19db::loader::{LoadOptions, Registry};
+use covid19db::Error;
+use sqlx::prelude::*;
+
+#[tokio::test]
+async fn bad_records_are_quarantined() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("numbers.txt"), "1\n2\nthree\n4\nfive\n").unwrap();
+    let mut registry = Registry::new();
+    // Each line should be an integer.
+    registry.register(Box::new(LineSource {
+        columns: Some("n integer not null"),
+        parse: |line| {
+            line.parse()
+                .map(|n| vec![Value::Int(n)])
+                .map_err(|e: std::num::ParseIntError| e.to_string())
+        },
+        ..LineSource::new("numbers")
+    }));
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        max_rejects: 1,
+        ..LoadOptions::default()
+    };
+
+    match registry.load(&opts).await {
+        Err(Error::TooManyRejects {
+            source,
+            rejected,
+            allowed,
+            first,
+        }) => {
+            assert_eq!(source, "numbers");
+            assert_eq!((rejected, allowed), (2, 1));
+            assert!(first.starts_with("on line 3 of numbers.txt"));
+        }
+        x => panic!("unexpected {:?}", x),
+    }
+    assert!(!opts.output.exists());
+
+    let opts = LoadOptions {
+        max_rejects_for: vec![(String::from("numbers"), 2)].into_iter().collect(),
+        ..opts
+    };
+    registry.load(&opts).await.unwrap();
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let mut conn = pool.acquire().await.unwrap();
+    let rejects: Vec<(String, String, i64, String)> =
+        sqlx::query_as("SELECT source, filename, line, record FROM load_rejects ORDER BY line")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+    assert_eq!(
+        rejects,
+        vec![
+            (
+                String::from("numbers"),
+                String::from("numbers.txt"),
+                3,
+                String::from("three")
+            ),
+            (
+                String::from("numbers"),
+                String::from("numbers.txt"),
+                5,
+                String::from("five")
+            ),
+        ]
+    );
+    let counts: (i64, i64) =
+        sqlx::query_as("SELECT inserted, rejected FROM covid19db_sources WHERE source = 'numbers'")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+    assert_eq!(counts, (3, 2));
+}
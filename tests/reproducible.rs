@@ -0,0 +1,109 @@
+/* Tests for reproducible builds
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use chrono::{TimeZone, Utc};
+use common::source::LineSource;
+use covid19db::loader::{LoadOptions, Manifest, Registry};
+use covid19db::progress::Progress;
+
+#[tokio::test]
+async fn builds_from_the_same_files_are_identical() {
+    let mut registry = Registry::new();
+    for name in &["first", "second", "third"] {
+        registry.register(Box::new(LineSource::new(name)));
+    }
+    let mut built = Vec::new();
+    for _ in 0..2 {
+        // Each build reads its own copies of the files, written at different times.
+        let dir = tempfile::tempdir().unwrap();
+        for (name, text) in &[("first", "a\nb\n"), ("second", "c\n"), ("third", "d\ne\n")] {
+            std::fs::write(dir.path().join(format!("{}.txt", name)), text).unwrap();
+        }
+        let opts = LoadOptions {
+            output: dir.path().join("test.db"),
+            input_dir: Some(dir.path().to_owned()),
+            progress: Progress::silent(),
+            timestamp: Some(Utc.ymd(2021, 3, 1).and_hms(0, 0, 0)),
+            ..LoadOptions::default()
+        };
+        registry.load(&opts).await.unwrap();
+        let manifest = Manifest::from_file(&Manifest::path(&opts.output)).unwrap();
+        assert_eq!(manifest, Manifest::of(&opts.output).await.unwrap());
+        built.push((std::fs::read(&opts.output).unwrap(), manifest));
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+    }
+    assert!(built[0].0 == built[1].0, "the databases differ");
+    let (first, second) = (&built[0].1, &built[1].1);
+    assert!(first.differences(second).is_empty());
+    let inputs: Vec<&str> = first.inputs.iter().map(|x| x.filename.as_str()).collect();
+    assert_eq!(inputs, vec!["first.txt", "second.txt", "third.txt"]);
+
+    let mut changed = second.clone();
+    changed.inputs[1].sha256 = String::from("0");
+    assert_eq!(
+        first.differences(&changed),
+        vec![format!(
+            "second second.txt has SHA-256 0, not {}",
+            first.inputs[1].sha256
+        )]
+    );
+}
+
+#[tokio::test]
+async fn kept_location_ids_are_recorded_in_the_manifest() {
+    let mut registry = Registry::new();
+    registry.register(Box::new(LineSource::new("first")));
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("first.txt"), "a\nb\n").unwrap();
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        progress: Progress::silent(),
+        timestamp: Some(Utc.ymd(2021, 3, 1).and_hms(0, 0, 0)),
+        ..LoadOptions::default()
+    };
+    registry.load(&opts).await.unwrap();
+    let fresh = Manifest::of(&opts.output).await.unwrap();
+    assert_eq!(fresh.locids_from, None);
+
+    // Building over the database gives the same bytes, unless asked to keep
+    // its ids, which the manifest then says.
+    registry.load(&opts).await.unwrap();
+    assert!(fresh
+        .differences(&Manifest::of(&opts.output).await.unwrap())
+        .is_empty());
+    let keeping = LoadOptions {
+        locids_from: Some(opts.output.clone()),
+        ..opts.clone()
+    };
+    registry.load(&keeping).await.unwrap();
+    let kept = Manifest::from_file(&Manifest::path(&opts.output)).unwrap();
+    assert_eq!(kept.locids_from, Some(fresh.sha256.clone()));
+    assert!(fresh.differences(&kept).contains(&format!(
+        "the database kept those of the database with SHA-256 {} as location ids, not none",
+        fresh.sha256
+    )));
+
+    let missing = LoadOptions {
+        locids_from: Some(dir.path().join("missing.db")),
+        ..opts.clone()
+    };
+    assert!(registry.load(&missing).await.is_err());
+}
@@ -0,0 +1,88 @@
+/* Tests for resuming unfinished builds
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use common::source::LineSource;
+use covid19db::dbutil::{count_rows, open_pool};
+use covid19db::loader::{LoadOptions, Registry};
+use covid19db::progress::Progress;
+use sqlx::prelude::*;
+
+#[tokio::test]
+async fn resumed_builds_skip_completed_sources() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = LineSource::new("first");
+    let second = LineSource {
+        loaded: first.loaded.clone(),
+        ..LineSource::new("second")
+    };
+    let mut registry = Registry::new();
+    registry.register(Box::new(first.clone()));
+    registry.register(Box::new(second));
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        progress: Progress::silent(),
+        // Load one source at a time, in order.
+        download: covid19db::loader::DownloadOptions {
+            concurrency: 1,
+            ..Default::default()
+        },
+        ..LoadOptions::default()
+    };
+    let partial = dir.path().join("test.db.partial");
+
+    std::fs::write(dir.path().join("first.txt"), "a\nb\n").unwrap();
+    std::fs::write(dir.path().join("second.txt"), "").unwrap();
+    assert!(registry.load(&opts).await.is_err());
+    assert!(!opts.output.exists());
+    let pool = open_pool(&partial, 1).await.unwrap();
+    let completed: Vec<(String,)> = sqlx::query_as("SELECT source FROM load_checkpoints")
+        .fetch_all(&mut pool.acquire().await.unwrap())
+        .await
+        .unwrap();
+    pool.close().await;
+    assert_eq!(completed, vec![(String::from("first"),)]);
+
+    std::fs::write(dir.path().join("second.txt"), "c\n").unwrap();
+    let resume = LoadOptions {
+        resume: true,
+        ..opts.clone()
+    };
+    registry.load(&resume).await.unwrap();
+    assert_eq!(first.loads("first"), 1);
+    assert_eq!(first.loads("second"), 2);
+    assert!(!partial.exists());
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let mut conn = pool.acquire().await.unwrap();
+    assert_eq!(count_rows(&mut conn, "first").await.unwrap(), 2);
+    assert_eq!(count_rows(&mut conn, "second").await.unwrap(), 1);
+    let sources: i64 = sqlx::query_as::<_, (i64,)>("SELECT count(*) FROM covid19db_sources")
+        .fetch_one(&mut conn)
+        .await
+        .unwrap()
+        .0;
+    assert_eq!(sources, 2);
+    drop(conn);
+    pool.close().await;
+
+    // With nothing to resume, the build starts afresh.
+    registry.load(&resume).await.unwrap();
+    assert_eq!(first.loads("first"), 2);
+}
@@ -0,0 +1,204 @@
+/* Tests for the source configuration
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use covid19db::dbutil::open_pool;
+use covid19db::loader::{self, LoadOptions, Registry, SourcesConfig};
+use covid19db::progress::Progress;
+use covid19db::Error;
+use sqlx::prelude::*;
+
+fn configured(text: &str) -> Registry {
+    let mut config = SourcesConfig::builtin();
+    config.extend(SourcesConfig::from_toml(text).unwrap());
+    Registry::from_config(&config).unwrap()
+}
+
+#[test]
+fn builtin_config_matches_builtin_sources() {
+    let config = SourcesConfig::builtin();
+    let configured = Registry::from_config(&config).unwrap();
+    let builtin = Registry::builtin();
+    assert_eq!(configured.names(), builtin.names());
+    for source in builtin.iter() {
+        assert_eq!(
+            configured.get(source.name()).unwrap().files(),
+            source.files()
+        );
+    }
+    for file in builtin.get("cdataset").unwrap().files() {
+        assert!(file
+            .url
+            .contains("/5444d3e19eb2556a93e4d9ac4974762d9489fc1b/"));
+    }
+}
+
+#[test]
+fn disabled_sources_are_not_selected() {
+    let registry = configured(
+        r#"
+        [[source]]
+        name = "loc_lookup"
+        enabled = false
+        "#,
+    );
+    let opts = LoadOptions {
+        only: vec![String::from("owid")],
+        ..LoadOptions::default()
+    };
+    assert_eq!(registry.select(&opts).unwrap().len(), 1);
+    for only in &["loc_lookup", "cdataset"] {
+        let opts = LoadOptions {
+            only: vec![String::from(*only)],
+            ..LoadOptions::default()
+        };
+        assert!(matches!(registry.select(&opts), Err(Error::Config(_))));
+    }
+}
+
+#[test]
+fn one_parser_per_enabled_source() {
+    let mirror = r#"
+        [[source]]
+        name = "rtlive_mirror"
+        parser = "rtlive"
+
+        [[source.file]]
+        filename = "rt-mirror.csv"
+        url = "https://example.com/rt.csv"
+    "#;
+    let mut config = SourcesConfig::builtin();
+    config.extend(SourcesConfig::from_toml(mirror).unwrap());
+    assert!(matches!(
+        Registry::from_config(&config),
+        Err(Error::Config(_))
+    ));
+
+    let registry = configured(&format!(
+        "{}\n[[source]]\nname = \"rtlive\"\nenabled = false\n",
+        mirror
+    ));
+    let source = registry.get("rtlive_mirror").unwrap();
+    assert_eq!(source.schema(), registry.get("rtlive").unwrap().schema());
+    assert_eq!(source.files()[0].filename, "rt-mirror.csv");
+
+    let mut config = SourcesConfig::builtin();
+    config.extend(SourcesConfig::from_toml("[[source]]\nname = \"x\"\nparser = \"y\"").unwrap());
+    assert!(Registry::from_config(&config).is_err());
+}
+
+#[tokio::test]
+async fn pinned_hashes_are_checked() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("harveycodata.csv"), "date\n").unwrap();
+    let sources = dir.path().join("sources.toml");
+    std::fs::write(
+        &sources,
+        r#"
+        [[source]]
+        name = "harveycodata"
+
+        [[source.file]]
+        filename = "harveycodata.csv"
+        url = "https://example.com/harveycodata.csv"
+        sha256 = "0000000000000000000000000000000000000000000000000000000000000000"
+        "#,
+    )
+    .unwrap();
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        only: vec![String::from("harveycodata")],
+        sources: Some(sources),
+        progress: Progress::silent(),
+        ..LoadOptions::default()
+    };
+    match loader::load_with(&opts).await {
+        Err(Error::Checksum { url, found, .. }) => {
+            assert_eq!(url, "https://example.com/harveycodata.csv");
+            assert_eq!(found.len(), 64);
+        }
+        x => panic!("unexpected {:?}", x),
+    }
+}
+
+#[tokio::test]
+async fn renamed_sources_keep_their_names() {
+    // cdataset depends on whatever source loads the JHU lookup table.
+    let registry = configured(
+        r#"
+        [[source]]
+        name = "loc_lookup"
+        enabled = false
+
+        [[source]]
+        name = "jhu_lookup"
+        parser = "loc_lookup"
+
+        [[source.file]]
+        filename = "UID_ISO_FIPS_LookUp_Table.csv"
+        url = "https://example.com/UID_ISO_FIPS_LookUp_Table.csv"
+        "#,
+    );
+    assert_eq!(
+        registry.get("cdataset").unwrap().dependencies(),
+        vec!["jhu_lookup"]
+    );
+
+    // The loader records the headers it read under the configured name.
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join("us-counties.csv"),
+        "date,county,state,fips,cases,deaths\n2020-06-01,Harvey,Kansas,20079,41,1\n",
+    )
+    .unwrap();
+    let sources = dir.path().join("sources.toml");
+    std::fs::write(
+        &sources,
+        r#"
+        [[source]]
+        name = "nytcounties"
+        enabled = false
+
+        [[source]]
+        name = "counties"
+        parser = "nytcounties"
+
+        [[source.file]]
+        filename = "us-counties.csv"
+        url = "https://example.com/us-counties.csv"
+        "#,
+    )
+    .unwrap();
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        only: vec![String::from("counties")],
+        sources: Some(sources),
+        progress: Progress::silent(),
+        ..LoadOptions::default()
+    };
+    loader::load_with(&opts).await.unwrap();
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let fields: Vec<(String,)> =
+        sqlx::query_as("SELECT field FROM covid19db_meta WHERE field LIKE 'headers:%'")
+            .fetch_all(&mut pool.acquire().await.unwrap())
+            .await
+            .unwrap();
+    pool.close().await;
+    assert_eq!(fields, vec![(String::from("headers:counties"),)]);
+}
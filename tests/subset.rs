@@ -0,0 +1,139 @@
+/* Tests for subset builds
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use chrono::NaiveDate;
+use covid19db::dbutil::{count_rows, open_pool};
+use covid19db::loader::{LoadOptions, LocLookupSource, NYTCountiesSource, Registry, Subset};
+use covid19db::progress::Progress;
+use sqlx::prelude::*;
+
+const COUNTIES: &str = "date,county,state,fips,cases,deaths
+2020-05-31,Harvey,Kansas,20079,40,1
+2020-06-01,Harvey,Kansas,20079,41,1
+2020-06-01,Sedgwick,Kansas,20173,900,10
+2020-06-01,Jackson,Missouri,29095,800,20
+2020-06-01,Unknown,Kansas,,3,0
+";
+
+#[tokio::test]
+async fn subsets_are_loaded_and_recorded() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("us-counties.csv"), COUNTIES).unwrap();
+    // The nytcounties view refers to loc_lookup, which is created but not loaded.
+    let mut registry = Registry::new();
+    registry.register(Box::new(LocLookupSource));
+    registry.register(Box::new(NYTCountiesSource));
+    let opts = LoadOptions {
+        output: dir.path().join("test.db"),
+        input_dir: Some(dir.path().to_owned()),
+        progress: Progress::silent(),
+        only: vec![String::from("nytcounties")],
+        subset: Subset {
+            from: Some(NaiveDate::from_ymd(2020, 6, 1)),
+            states: vec![String::from("KS")],
+            ..Subset::default()
+        },
+        ..LoadOptions::default()
+    };
+    registry.load(&opts).await.unwrap();
+
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let mut conn = pool.acquire().await.unwrap();
+    let counties: Vec<(String,)> =
+        sqlx::query_as("SELECT county FROM nytcounties_raw ORDER BY county")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+    let counties: Vec<&str> = counties.iter().map(|(x,)| x.as_str()).collect();
+    assert_eq!(counties, vec!["Harvey", "Sedgwick", "Unknown"]);
+    let (parsed, skipped): (i64, i64) = sqlx::query_as(
+        "SELECT parsed, skipped FROM covid19db_sources WHERE source = 'nytcounties'",
+    )
+    .fetch_one(&mut conn)
+    .await
+    .unwrap();
+    assert_eq!((parsed, skipped), (3, 2));
+    let meta: Vec<(String, String)> = sqlx::query_as(
+        "SELECT field, value FROM covid19db_meta WHERE field LIKE 'subset:%' ORDER BY field",
+    )
+    .fetch_all(&mut conn)
+    .await
+    .unwrap();
+    assert_eq!(
+        meta,
+        vec![
+            (String::from("subset:from"), String::from("2020-06-01")),
+            (String::from("subset:states"), String::from("KS")),
+        ]
+    );
+    drop(conn);
+    pool.close().await;
+
+    // A county, by FIPS prefix, instead of the whole state.
+    let county = LoadOptions {
+        subset: Subset {
+            fips: vec![String::from("20079")],
+            ..Subset::default()
+        },
+        ..opts.clone()
+    };
+    registry.load(&county).await.unwrap();
+    let pool = open_pool(&opts.output, 1).await.unwrap();
+    let mut conn = pool.acquire().await.unwrap();
+    assert_eq!(count_rows(&mut conn, "nytcounties_raw").await.unwrap(), 2);
+    drop(conn);
+    pool.close().await;
+
+    // An incremental build can't change the subset.
+    let incremental = LoadOptions {
+        incremental: true,
+        ..opts.clone()
+    };
+    let err = registry.load(&incremental).await.unwrap_err().to_string();
+    assert!(err.contains("different subset"), "{}", err);
+
+    let bad = LoadOptions {
+        subset: Subset {
+            countries: vec![String::from("XX")],
+            ..Subset::default()
+        },
+        ..opts.clone()
+    };
+    assert!(registry.select(&bad).is_err());
+}
+
+#[test]
+fn unknown_datasets_are_rejected() {
+    let registry = Registry::builtin();
+    let opts = |dataset: &str| LoadOptions {
+        subset: Subset {
+            datasets: vec![String::from(dataset)],
+            ..Subset::default()
+        },
+        ..LoadOptions::default()
+    };
+    assert!(registry.select(&opts("jhu/daily")).is_ok());
+    assert!(registry.select(&opts("nytimes/us-counties")).is_ok());
+    let err = registry
+        .select(&opts("jhu/dialy"))
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("Unknown dataset \"jhu/dialy\""), "{}", err);
+    assert!(err.contains("ecdc/worldwide"), "{}", err);
+}
@@ -0,0 +1,163 @@
+/* Tests for declarative checks
+
+Copyright (c) 2021 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use covid19db::dbutil::open_pool;
+use covid19db::validate::{Checks, Outcome};
+use sqlx::prelude::*;
+
+const CHECKS: &str = r#"
+[[check]]
+table = "cases"
+kind = "min_rows"
+rows = 4
+
+[[check]]
+table = "cases"
+kind = "min_rows"
+rows = 5
+severity = "warn"
+
+[[check]]
+table = "cases"
+where = "county = 'Harvey' AND date_julian = 2459000"
+kind = "value"
+column = "cases"
+expect = 10
+
+[[check]]
+table = "cases"
+where = "county = 'Harvey' AND date_julian = 2459001"
+kind = "value"
+column = "cases"
+
+[[check]]
+table = "cases"
+where = "county = 'Nowhere'"
+kind = "value"
+column = "cases"
+expect = 1
+
+[[check]]
+table = "cases"
+kind = "not_null"
+column = "cases"
+min_ratio = 0.75
+
+[[check]]
+table = "cases"
+kind = "not_null"
+column = "cases"
+min_ratio = 0.8
+
+[[check]]
+table = "cases"
+where = "county = 'Harvey'"
+kind = "date_coverage"
+from = "2020-05-30"
+
+[[check]]
+table = "cases"
+kind = "date_coverage"
+from = "2020-05-30"
+to = "2020-06-02"
+
+[[check]]
+table = "cases"
+kind = "unique"
+columns = ["county", "date_julian"]
+
+[[check]]
+table = "cases"
+kind = "unique"
+columns = ["date_julian"]
+
+[[check]]
+table = "cases"
+kind = "at_most"
+column = "cases"
+max = 25
+
+[[check]]
+table = "cases"
+kind = "at_most"
+column = "cases"
+max = 20
+
+[[check]]
+table = "cases"
+kind = "min_rows"
+rows = 1
+where = "bogus = 1"
+
+[[check]]
+source = "unselected"
+table = "cases"
+kind = "min_rows"
+rows = 100
+"#;
+
+#[tokio::test]
+async fn every_check_is_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let pool = open_pool(&dir.path().join("test.db"), 1).await.unwrap();
+    let mut conn = pool.acquire().await.unwrap();
+    conn.execute(
+        "CREATE TABLE cases (county text, date_julian integer, cases integer);
+         INSERT INTO cases VALUES ('Harvey', 2459000, 10), ('Harvey', 2459001, NULL),
+                                  ('Sedgwick', 2459000, 20), ('Sedgwick', 2459002, 25);",
+    )
+    .await
+    .unwrap();
+
+    let checks = Checks::from_toml(CHECKS).unwrap();
+    let report = checks.run(&mut conn, &["cases"]).await.unwrap();
+    let outcomes: Vec<Outcome> = report.results.iter().map(|x| x.outcome).collect();
+    assert_eq!(
+        outcomes,
+        vec![
+            Outcome::Pass,
+            Outcome::Warn,
+            Outcome::Pass,
+            Outcome::Pass,
+            Outcome::Fail,
+            Outcome::Pass,
+            Outcome::Fail,
+            Outcome::Pass,
+            Outcome::Fail,
+            Outcome::Pass,
+            Outcome::Fail,
+            Outcome::Pass,
+            Outcome::Fail,
+            Outcome::Fail,
+        ]
+    );
+    assert!(!report.passed());
+    assert_eq!(report.results[4].detail, "no rows match");
+    assert_eq!(report.results[8].detail, "missing 1 of 4 days");
+    assert_eq!(report.results[10].detail, "1 repeated values");
+    assert_eq!(report.results[12].detail, "found 25");
+    assert!(report.results[13].detail.contains("bogus"));
+    assert!(report
+        .to_string()
+        .ends_with("7 passed, 1 warnings, 6 failed"));
+}
+
+#[test]
+fn builtin_checks_parse() {
+    assert!(!Checks::builtin().checks.is_empty());
+}